@@ -8,7 +8,7 @@ pub struct CertKeyPair {
 }
 
 pub fn generate_self_signed_cert() -> Result<CertKeyPair> {
-    println!("Generating self-signed certificate...");
+    crate::info!("Generating self-signed certificate...");
 
     let mut params = CertificateParams::new(vec!["localhost".to_string()])
         .context("Failed to create certificate params")?;
@@ -25,15 +25,16 @@ pub fn generate_self_signed_cert() -> Result<CertKeyPair> {
     let cert_pem = cert.pem();
     let key_pem = key_pair.serialize_pem();
 
-    println!("Self-signed certificate generated successfully");
+    crate::info!("Self-signed certificate generated successfully");
 
     Ok(CertKeyPair { cert_pem, key_pem })
 }
 
 pub fn load_cert_from_file(cert_path: &Path, key_path: &Path) -> Result<CertKeyPair> {
-    println!(
+    crate::info!(
         "Loading certificate from {:?} and key from {:?}",
-        cert_path, key_path
+        cert_path,
+        key_path
     );
 
     let cert_pem = std::fs::read_to_string(cert_path)
@@ -42,11 +43,20 @@ pub fn load_cert_from_file(cert_path: &Path, key_path: &Path) -> Result<CertKeyP
     let key_pem = std::fs::read_to_string(key_path)
         .context(format!("Failed to read key from {:?}", key_path))?;
 
-    println!("Certificate and key loaded successfully");
+    crate::info!("Certificate and key loaded successfully");
 
     Ok(CertKeyPair { cert_pem, key_pem })
 }
 
+/// Load the certificate given on the command line, or generate a
+/// self-signed one when none was given.
+pub fn load_or_generate(cert_path: Option<&Path>, key_path: Option<&Path>) -> Result<CertKeyPair> {
+    match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => load_cert_from_file(cert_path, key_path),
+        _ => generate_self_signed_cert(),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
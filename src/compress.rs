@@ -0,0 +1,403 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// Compressed content is cut into blocks of up to `BLOCK` bytes, each sent
+/// as a frame: `ZSTD` (u32 length and a zstd frame), `RAW` (u32 length and
+/// the bytes as they are) for blocks that do not shrink, and `END`. The
+/// body and its SHA256 go through unchanged otherwise, so the hash still
+/// covers the uncompressed content.
+const FRAME_END: u8 = 0;
+const FRAME_RAW: u8 = 1;
+const FRAME_ZSTD: u8 = 2;
+const BLOCK: usize = 128 * 1024;
+const LEVEL: i32 = 3;
+/// Blocks `auto` compresses before deciding whether it is worth it
+const SAMPLE_BLOCKS: u64 = 8;
+
+/// ALPN protocol of peers that accept compressed transfers. Offered by
+/// both sides of a connection, so it is only agreed on when both have it.
+pub const ALPN: &[u8] = b"rxx-zstd";
+
+/// Whether to compress what is sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Compress {
+    /// Send as is
+    None,
+    /// Compress everything
+    Zstd,
+    /// Compress unless the first blocks show the content does not shrink
+    Auto,
+}
+
+/// Whether the peer on `connection` agreed to compressed transfers.
+pub fn negotiated(connection: &quinn::Connection) -> bool {
+    connection
+        .handshake_data()
+        .and_then(|data| data.downcast::<quinn::crypto::rustls::HandshakeData>().ok())
+        .and_then(|data| data.protocol)
+        .is_some_and(|protocol| protocol == ALPN)
+}
+
+/// Writes everything through to `inner`, as compressed blocks unless the
+/// mode is `Compress::None`. `finish` ends the blocks.
+pub struct Compressor<W> {
+    inner: W,
+    /// Whether the content goes out in frames at all
+    framed: bool,
+    mode: Compress,
+    context: Option<zstd::bulk::Compressor<'static>>,
+    /// Content of the block being collected
+    input: Vec<u8>,
+    /// Frame waiting to be written to `inner`, and how much of it has been
+    output: Vec<u8>,
+    written: usize,
+    blocks: u64,
+    raw_bytes: u64,
+    sent_bytes: u64,
+}
+
+impl<W: AsyncWrite + Unpin> Compressor<W> {
+    pub fn new(inner: W, mode: Compress) -> Self {
+        Compressor {
+            inner,
+            framed: mode != Compress::None,
+            mode,
+            context: None,
+            input: Vec::new(),
+            output: Vec::new(),
+            written: 0,
+            blocks: 0,
+            raw_bytes: 0,
+            sent_bytes: 0,
+        }
+    }
+
+    /// Turn the collected input into a frame.
+    fn seal(&mut self) -> io::Result<()> {
+        let compressed = if self.mode == Compress::None {
+            None
+        } else {
+            let context = match &mut self.context {
+                Some(context) => context,
+                None => self.context.insert(zstd::bulk::Compressor::new(LEVEL)?),
+            };
+            Some(context.compress(&self.input)?).filter(|data| data.len() < self.input.len())
+        };
+
+        self.blocks += 1;
+        self.raw_bytes += self.input.len() as u64;
+        let (frame, data) = match &compressed {
+            Some(data) => (FRAME_ZSTD, data.as_slice()),
+            None => (FRAME_RAW, self.input.as_slice()),
+        };
+        self.output.push(frame);
+        self.output
+            .extend_from_slice(&(data.len() as u32).to_be_bytes());
+        self.output.extend_from_slice(data);
+        self.sent_bytes += data.len() as u64;
+        self.input.clear();
+
+        // Already compressed content only costs time
+        if self.mode == Compress::Auto
+            && self.blocks == SAMPLE_BLOCKS
+            && self.sent_bytes * 10 > self.raw_bytes * 9
+        {
+            crate::info!("Content does not compress, sending the rest as is");
+            self.mode = Compress::None;
+        }
+        Ok(())
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.output.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.output[self.written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.written += n;
+        }
+        self.output.clear();
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    /// Write what is left and the end frame. Does nothing when not
+    /// compressing.
+    pub async fn finish(&mut self) -> Result<()> {
+        if !self.framed {
+            return Ok(());
+        }
+        self.flush().await?;
+        self.inner.write_u8(FRAME_END).await?;
+        crate::debug!(
+            "DEBUG [SEND]: Compressed {} bytes into {} in {} blocks",
+            self.raw_bytes,
+            self.sent_bytes,
+            self.blocks
+        );
+        if self.raw_bytes > 0 {
+            crate::info!(
+                "Compressed {} bytes to {} ({}%)",
+                self.raw_bytes,
+                self.sent_bytes,
+                self.sent_bytes * 100 / self.raw_bytes
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Compressor<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if !this.framed {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        loop {
+            ready!(this.poll_drain(cx))?;
+            if this.input.len() < BLOCK {
+                break;
+            }
+            this.seal()?;
+        }
+        let n = buf.len().min(BLOCK - this.input.len());
+        this.input.extend_from_slice(&buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(this.poll_drain(cx))?;
+        if !this.input.is_empty() {
+            this.seal()?;
+            ready!(this.poll_drain(cx))?;
+        }
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Where `Decompressor` is in the current frame
+enum State {
+    Frame,
+    Length(u8),
+    Data(u8),
+    End,
+}
+
+/// Reads what `Compressor` wrote, or passes `inner` through if the content
+/// is not compressed.
+pub struct Decompressor<R> {
+    inner: R,
+    compressed: bool,
+    context: Option<zstd::bulk::Decompressor<'static>>,
+    state: State,
+    /// Bytes of the current frame field, until `want` of them are there
+    field: Vec<u8>,
+    want: usize,
+    block: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: AsyncRead + Unpin> Decompressor<R> {
+    pub fn new(inner: R, compressed: bool) -> Self {
+        Decompressor {
+            inner,
+            compressed,
+            context: None,
+            state: State::Frame,
+            field: Vec::new(),
+            want: 1,
+            block: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn poll_field(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.field.len() < self.want {
+            let start = self.field.len();
+            self.field.resize(self.want, 0);
+            let mut buf = ReadBuf::new(&mut self.field[start..]);
+            let result = Pin::new(&mut self.inner).poll_read(cx, &mut buf);
+            let n = buf.filled().len();
+            self.field.truncate(start + n);
+            ready!(result)?;
+            if n == 0 {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Compressed content ended early",
+                )));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Decode the frame in `field` into `block`.
+    fn unpack(&mut self, frame: u8) -> io::Result<()> {
+        self.block = if frame == FRAME_RAW {
+            std::mem::take(&mut self.field)
+        } else {
+            let context = match &mut self.context {
+                Some(context) => context,
+                None => self.context.insert(zstd::bulk::Decompressor::new()?),
+            };
+            // Fails for blocks larger than the sender may make them
+            context.decompress(&self.field, BLOCK)?
+        };
+        self.pos = 0;
+        Ok(())
+    }
+
+    /// Check that nothing follows what was read but the end frame.
+    pub async fn finish(&mut self) -> Result<()> {
+        if !self.compressed {
+            return Ok(());
+        }
+        if self.read(&mut [0u8; 1]).await? != 0 {
+            bail!("Unexpected data after the compressed content");
+        }
+        Ok(())
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Decompressor<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.compressed {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        loop {
+            if this.pos < this.block.len() {
+                let n = buf.remaining().min(this.block.len() - this.pos);
+                buf.put_slice(&this.block[this.pos..this.pos + n]);
+                this.pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            match this.state {
+                State::End => return Poll::Ready(Ok(())),
+                State::Frame => {
+                    ready!(this.poll_field(cx))?;
+                    let frame = this.field[0];
+                    this.state = match frame {
+                        FRAME_END => State::End,
+                        FRAME_RAW | FRAME_ZSTD => State::Length(frame),
+                        _ => return Poll::Ready(Err(invalid(format!("Unknown frame {}", frame)))),
+                    };
+                    this.field.clear();
+                    this.want = 4;
+                }
+                State::Length(frame) => {
+                    ready!(this.poll_field(cx))?;
+                    let len = u32::from_be_bytes(this.field[..4].try_into().unwrap()) as usize;
+                    let max = if frame == FRAME_RAW {
+                        BLOCK
+                    } else {
+                        zstd::zstd_safe::compress_bound(BLOCK)
+                    };
+                    if len > max {
+                        return Poll::Ready(Err(invalid(format!(
+                            "Compressed block too large ({} bytes)",
+                            len
+                        ))));
+                    }
+                    this.field.clear();
+                    this.want = len;
+                    this.state = State::Data(frame);
+                }
+                State::Data(frame) => {
+                    ready!(this.poll_field(cx))?;
+                    this.unpack(frame)?;
+                    this.field.clear();
+                    this.want = 1;
+                    this.state = State::Frame;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn roundtrip(data: &[u8], mode: Compress) -> Vec<u8> {
+        let mut wire = Vec::new();
+        let mut compressor = Compressor::new(&mut wire, mode);
+        // Odd sizes to cross block boundaries
+        for chunk in data.chunks(50_000) {
+            compressor.write_all(chunk).await.unwrap();
+        }
+        compressor.finish().await.unwrap();
+
+        let mut out = Vec::new();
+        let mut decompressor = Decompressor::new(wire.as_slice(), mode != Compress::None);
+        let mut buffer = vec![0u8; data.len()];
+        decompressor.read_exact(&mut buffer).await.unwrap();
+        out.extend_from_slice(&buffer);
+        decompressor.finish().await.unwrap();
+        assert_eq!(out, data);
+        wire
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let text: Vec<u8> = b"2026-10-19 INFO request served in 12ms\n"
+            .iter()
+            .cycle()
+            .take(1_000_000)
+            .copied()
+            .collect();
+        let noise: Vec<u8> = (0..1_000_000).map(|_| rand::random()).collect();
+
+        assert_eq!(roundtrip(&text, Compress::None).await, text);
+        assert!(roundtrip(&text, Compress::Zstd).await.len() < text.len() / 10);
+        assert!(roundtrip(&text, Compress::Auto).await.len() < text.len() / 10);
+
+        // Blocks that do not shrink go out raw, with a few bytes of framing
+        let wire = roundtrip(&noise, Compress::Zstd).await;
+        assert!(wire.len() < noise.len() + 100);
+        let wire = roundtrip(&noise, Compress::Auto).await;
+        assert!(wire.len() < noise.len() + 100);
+        assert!(roundtrip(&[], Compress::Auto).await.len() == 1);
+    }
+
+    #[tokio::test]
+    async fn test_trailing_data() {
+        let mut wire = Vec::new();
+        let mut compressor = Compressor::new(&mut wire, Compress::Zstd);
+        compressor.write_all(b"hello").await.unwrap();
+        compressor.finish().await.unwrap();
+
+        let mut decompressor = Decompressor::new(wire.as_slice(), true);
+        let mut hello = [0u8; 4];
+        decompressor.read_exact(&mut hello).await.unwrap();
+        assert!(decompressor.finish().await.is_err());
+
+        wire.truncate(wire.len() - 1);
+        let mut decompressor = Decompressor::new(wire.as_slice(), true);
+        let mut hello = [0u8; 5];
+        decompressor.read_exact(&mut hello).await.unwrap();
+        assert!(decompressor.finish().await.is_err());
+    }
+}
@@ -1,12 +1,13 @@
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
 use std::time::Duration;
 use tokio::process::Command;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct Config {
     pub user_id: String,
     pub server_url: String,
@@ -14,6 +15,45 @@ pub struct Config {
     pub nonce: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hooks: Option<Hooks>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub punch: Option<PunchConfig>,
+    /// Look for peers on the local network alongside the server lookup (default: true)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lan_discovery: Option<bool>,
+    /// Allow ULA and link-local addresses on networks without global IPv6
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allow_local: Option<bool>,
+    /// Only use addresses on this interface
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interface: Option<String>,
+    /// Interfaces, addresses or prefixes to prefer as the local address, in order
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preferred_addresses: Option<Vec<String>>,
+    /// Number of transfers a receiver handles at the same time (default: 4)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<usize>,
+    /// Give received files the sender's permissions and times
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preserve: Option<bool>,
+    /// Also set extended attributes and ACLs of received files (implies `preserve`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preserve_xattrs: Option<bool>,
+    /// Compression of what is sent when `rxx send --compress` is not given
+    /// (default: none)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub compress: Option<crate::compress::Compress>,
+    /// Cap on the send rate, such as "20M" (bytes per second); re-read on SIGHUP
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<String>,
+    /// Send with a congestion controller that yields to other traffic
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub background: Option<bool>,
+    /// Peers listed by `rxx who`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub contacts: Option<Vec<String>>,
+    /// Secret for decrypting mailbox items (hex X25519), generated on first receive
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mailbox_key: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,6 +62,26 @@ pub struct Hooks {
     pub file_received: Option<String>,
 }
 
+/// Hole punching tuning; anything left unset uses the built-in default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PunchConfig {
+    /// Seconds to wait for the peer in a single attempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_secs: Option<NonZeroU64>,
+    /// Number of attempts before giving up (0 waits forever)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_retries: Option<u32>,
+    /// Milliseconds between probe packets
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub probe_interval_ms: Option<u64>,
+    /// Initial delay between attempts, doubled after every failure
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backoff_initial_secs: Option<u64>,
+    /// Upper bound for the delay between attempts
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backoff_max_secs: Option<u64>,
+}
+
 impl Config {
     pub fn load() -> Result<Self> {
         let path = Self::config_path()?;
@@ -34,17 +94,25 @@ impl Config {
     pub fn save(&self) -> Result<()> {
         let path = Self::config_path()?;
         let content = toml::to_string_pretty(self).context("Failed to serialize config")?;
-        fs::write(&path, content)
+        write_private(&path, &content)
             .with_context(|| format!("Failed to write config file: {:?}", path))?;
-        println!("Config saved to {:?}", path);
+        crate::info!("Config saved to {:?}", path);
         Ok(())
     }
 
-    fn config_path() -> Result<PathBuf> {
+    pub fn config_path() -> Result<PathBuf> {
         let home = std::env::var("HOME").context("HOME environment variable not set")?;
         Ok(PathBuf::from(home).join(".rxx.conf"))
     }
 
+    /// Directory for local state such as the outbox, created on first use.
+    pub fn data_dir() -> Result<PathBuf> {
+        let home = std::env::var("HOME").context("HOME environment variable not set")?;
+        let dir = PathBuf::from(home).join(".rxx");
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        Ok(dir)
+    }
+
     pub fn execute_file_received_hook(&self, sender_id: &str, filename: &str, file_size: u64) {
         if let Some(hooks) = &self.hooks {
             if let Some(hook_cmd) = &hooks.file_received {
@@ -100,3 +168,25 @@ impl Config {
         }
     }
 }
+
+/// Write a file only we can read, as the config holds our nonce and
+/// mailbox key.
+fn write_private(path: &Path, content: &str) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::io::Write;
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(path)?;
+        // The mode only applies to new files
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(content.as_bytes())
+    }
+    #[cfg(not(unix))]
+    fs::write(path, content)
+}
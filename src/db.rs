@@ -4,10 +4,52 @@ use rusqlite::{params, Connection};
 use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
 
+/// Probe sessions are rotated once a peer pair has been idle this long
+const SESSION_TTL_SECS: i64 = 60 * 60;
+
 pub struct Database {
     conn: Connection,
 }
 
+/// What the registry knows about whether a peer is around
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Presence {
+    /// Unix time of the last heartbeat, if the peer ever sent one
+    pub last_seen: Option<i64>,
+    /// Whether the peer was running `rxx receive` at its last heartbeat
+    pub listening: bool,
+}
+
+/// An encrypted file waiting in the mailbox for its recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MailboxItem {
+    pub item_id: String,
+    pub sender: String,
+    pub size: u64,
+    pub created_at: i64,
+}
+
+/// Add `column` to `table` unless it is there already, bringing databases
+/// created before the column existed up to date.
+pub fn add_missing_column(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    decl: &str,
+) -> rusqlite::Result<()> {
+    let columns = conn
+        .prepare(&format!("PRAGMA table_info({})", table))?
+        .query_map([], |row| row.get::<_, String>(1))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    if !columns.iter().any(|c| c == column) {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
 impl Database {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
         let conn = Connection::open(path)?;
@@ -20,6 +62,43 @@ impl Database {
             )",
             [],
         )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                pair TEXT PRIMARY KEY,
+                session_id TEXT NOT NULL,
+                session_key TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS mailbox (
+                item_id TEXT PRIMARY KEY,
+                recipient TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS contacts (
+                owner TEXT NOT NULL,
+                contact TEXT NOT NULL,
+                PRIMARY KEY (owner, contact)
+            )",
+            [],
+        )?;
+
+        add_missing_column(&conn, "registrations", "last_seen", "INTEGER")?;
+        add_missing_column(
+            &conn,
+            "registrations",
+            "listening",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+        add_missing_column(&conn, "registrations", "public_key", "TEXT")?;
+
         Ok(Database { conn })
     }
 
@@ -63,7 +142,7 @@ impl Database {
                     return Ok(false);
                 }
                 if current_ipv6 == ipv6 {
-                    println!("IP unchanged for {}, skipping DB write", id_lower);
+                    crate::debug!("IP unchanged for {}, skipping DB write", id_lower);
                     return Ok(true);
                 }
 
@@ -78,6 +157,177 @@ impl Database {
         }
     }
 
+    /// Check a client's nonce without touching its registration.
+    pub fn verify(&self, id: &str, nonce: &str) -> Result<bool> {
+        let result = self.conn.query_row(
+            "SELECT nonce FROM registrations WHERE id = ?1",
+            params![id.to_lowercase()],
+            |row| row.get::<_, String>(0),
+        );
+        match result {
+            Ok(stored_nonce) => Ok(stored_nonce == nonce),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Record that `id` is online right now.
+    pub fn heartbeat(&self, id: &str, listening: bool) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        self.conn.execute(
+            "UPDATE registrations SET last_seen = ?1, listening = ?2 WHERE id = ?3",
+            params![now, listening, id.to_lowercase()],
+        )?;
+        Ok(())
+    }
+
+    /// Presence of a registered peer, `None` if the ID is unknown.
+    pub fn presence(&self, id: &str) -> Result<Option<Presence>> {
+        let result = self.conn.query_row(
+            "SELECT last_seen, listening FROM registrations WHERE id = ?1",
+            params![id.to_lowercase()],
+            |row| {
+                Ok(Presence {
+                    last_seen: row.get(0)?,
+                    listening: row.get(1)?,
+                })
+            },
+        );
+        match result {
+            Ok(presence) => Ok(Some(presence)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replace the IDs `owner` shares its presence with.
+    pub fn set_contacts(&self, owner: &str, contacts: &[String]) -> Result<()> {
+        let owner = owner.to_lowercase();
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute("DELETE FROM contacts WHERE owner = ?1", params![owner])?;
+        for contact in contacts {
+            tx.execute(
+                "INSERT OR IGNORE INTO contacts (owner, contact) VALUES (?1, ?2)",
+                params![owner, contact.to_lowercase()],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Whether `owner` lists `contact` among its contacts.
+    pub fn has_contact(&self, owner: &str, contact: &str) -> Result<bool> {
+        let found = self.conn.query_row(
+            "SELECT EXISTS(SELECT 1 FROM contacts WHERE owner = ?1 AND contact = ?2)",
+            params![owner.to_lowercase(), contact.to_lowercase()],
+            |row| row.get(0),
+        )?;
+        Ok(found)
+    }
+
+    /// Store the key senders use to encrypt mailbox items for `id`.
+    pub fn set_public_key(&self, id: &str, public_key: &str) -> Result<()> {
+        self.conn.execute(
+            "UPDATE registrations SET public_key = ?1 WHERE id = ?2",
+            params![public_key, id.to_lowercase()],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_public_key(&self, id: &str) -> Result<Option<String>> {
+        let result = self.conn.query_row(
+            "SELECT public_key FROM registrations WHERE id = ?1",
+            params![id.to_lowercase()],
+            |row| row.get::<_, Option<String>>(0),
+        );
+        match result {
+            Ok(key) => Ok(key),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Add an item unless it would take `recipient` over `quota` bytes.
+    /// Returns whether it was added.
+    pub fn mailbox_add(
+        &self,
+        item_id: &str,
+        recipient: &str,
+        sender: &str,
+        size: u64,
+        quota: u64,
+    ) -> Result<bool> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        // One statement, so uploads finishing together cannot both fit
+        let added = self.conn.execute(
+            "INSERT INTO mailbox (item_id, recipient, sender, size, created_at)
+             SELECT ?1, ?2, ?3, ?4, ?5
+             WHERE (SELECT COALESCE(SUM(size), 0) FROM mailbox WHERE recipient = ?2) + ?4 <= ?6",
+            params![
+                item_id,
+                recipient.to_lowercase(),
+                sender.to_lowercase(),
+                size as i64,
+                now,
+                quota.min(i64::MAX as u64) as i64
+            ],
+        )?;
+        Ok(added == 1)
+    }
+
+    /// Bytes currently stored for `recipient`.
+    pub fn mailbox_usage(&self, recipient: &str) -> Result<u64> {
+        let usage: i64 = self.conn.query_row(
+            "SELECT COALESCE(SUM(size), 0) FROM mailbox WHERE recipient = ?1",
+            params![recipient.to_lowercase()],
+            |row| row.get(0),
+        )?;
+        Ok(usage as u64)
+    }
+
+    pub fn mailbox_list(&self, recipient: &str) -> Result<Vec<MailboxItem>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT item_id, sender, size, created_at FROM mailbox WHERE recipient = ?1 ORDER BY created_at",
+        )?;
+        let items = stmt
+            .query_map(params![recipient.to_lowercase()], |row| {
+                Ok(MailboxItem {
+                    item_id: row.get(0)?,
+                    sender: row.get(1)?,
+                    size: row.get::<_, i64>(2)? as u64,
+                    created_at: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(items)
+    }
+
+    /// Remove an item addressed to `recipient`. Returns whether it existed.
+    pub fn mailbox_remove(&self, recipient: &str, item_id: &str) -> Result<bool> {
+        let removed = self.conn.execute(
+            "DELETE FROM mailbox WHERE recipient = ?1 AND item_id = ?2",
+            params![recipient.to_lowercase(), item_id],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Drop items older than `max_age_secs` and return their IDs so their
+    /// files can be deleted.
+    pub fn mailbox_expire(&self, max_age_secs: i64) -> Result<Vec<String>> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let cutoff = now - max_age_secs;
+        let expired = self
+            .conn
+            .prepare("SELECT item_id FROM mailbox WHERE created_at <= ?1")?
+            .query_map(params![cutoff], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+        self.conn.execute(
+            "DELETE FROM mailbox WHERE created_at <= ?1",
+            params![cutoff],
+        )?;
+        Ok(expired)
+    }
+
     pub fn get_ipv6(&self, id: &str) -> Result<Option<String>> {
         let id_lower = id.to_lowercase();
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
@@ -95,4 +345,139 @@ impl Database {
             Err(e) => Err(e.into()),
         }
     }
+
+    /// Get the probe session shared by two peers, creating a fresh one if the
+    /// pair has none or it has been idle for longer than `SESSION_TTL_SECS`.
+    ///
+    /// Both peers resolve each other independently, so the pair key is
+    /// order-independent and each lookup keeps the session alive.
+    pub fn get_or_create_session(&self, id: &str, peer_id: &str) -> Result<(String, String)> {
+        let mut ids = [id.to_lowercase(), peer_id.to_lowercase()];
+        ids.sort();
+        let pair = ids.join("|");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+        let mut stmt = self.conn.prepare(
+            "SELECT session_id, session_key FROM sessions WHERE pair = ?1 AND updated_at > ?2",
+        )?;
+        let result = stmt.query_row(params![pair, now - SESSION_TTL_SECS], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        });
+
+        let (session_id, session_key) = match result {
+            Ok(session) => session,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                let mut rng = rand::thread_rng();
+                (
+                    hex::encode(rng.gen::<[u8; 16]>()),
+                    hex::encode(rng.gen::<[u8; 32]>()),
+                )
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        self.conn.execute(
+            "INSERT OR REPLACE INTO sessions (pair, session_id, session_key, updated_at) VALUES (?1, ?2, ?3, ?4)",
+            params![pair, session_id, session_key, now],
+        )?;
+
+        Ok((session_id, session_key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presence() {
+        let db = Database::open(":memory:").unwrap();
+        let (_, nonce) = db.register("Bob", "2001:db8::1").unwrap();
+
+        assert!(db.verify("bob", &nonce).unwrap());
+        assert!(!db.verify("bob", "wrong").unwrap());
+        assert!(!db.verify("carol", &nonce).unwrap());
+
+        assert_eq!(
+            db.presence("bob").unwrap(),
+            Some(Presence {
+                last_seen: None,
+                listening: false
+            })
+        );
+        assert_eq!(db.presence("carol").unwrap(), None);
+
+        db.heartbeat("BOB", true).unwrap();
+        let presence = db.presence("bob").unwrap().unwrap();
+        assert!(presence.last_seen.is_some());
+        assert!(presence.listening);
+    }
+
+    #[test]
+    fn test_contacts() {
+        let db = Database::open(":memory:").unwrap();
+        db.set_contacts("Bob", &["Alice".to_string(), "carol".to_string()])
+            .unwrap();
+        assert!(db.has_contact("bob", "alice").unwrap());
+        assert!(!db.has_contact("alice", "bob").unwrap());
+
+        db.set_contacts("bob", &["carol".to_string()]).unwrap();
+        assert!(!db.has_contact("bob", "alice").unwrap());
+        assert!(db.has_contact("BOB", "Carol").unwrap());
+    }
+
+    #[test]
+    fn test_mailbox() {
+        let db = Database::open(":memory:").unwrap();
+        assert!(db.mailbox_add("a1", "Bob", "alice", 100, 200).unwrap());
+        assert!(db.mailbox_add("a2", "bob", "carol", 50, 200).unwrap());
+        assert!(db.mailbox_add("c1", "carol", "alice", 10, 200).unwrap());
+        // Over the quota together with what is already stored
+        assert!(!db.mailbox_add("a3", "bob", "carol", 51, 200).unwrap());
+
+        assert_eq!(db.mailbox_usage("bob").unwrap(), 150);
+        let items = db.mailbox_list("BOB").unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].sender, "alice");
+        assert_eq!(items[0].size, 100);
+
+        // Only the recipient can remove an item
+        assert!(!db.mailbox_remove("carol", "a1").unwrap());
+        assert!(db.mailbox_remove("bob", "a1").unwrap());
+        assert_eq!(db.mailbox_usage("bob").unwrap(), 50);
+
+        assert_eq!(db.mailbox_expire(3600).unwrap(), Vec::<String>::new());
+        let mut expired = db.mailbox_expire(-1).unwrap();
+        expired.sort();
+        assert_eq!(expired, vec!["a2", "c1"]);
+        assert!(db.mailbox_list("bob").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_migrates_old_schema() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("registry.db");
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute(
+                "CREATE TABLE registrations (
+                    id TEXT PRIMARY KEY,
+                    ipv6 TEXT NOT NULL,
+                    nonce TEXT NOT NULL,
+                    updated_at INTEGER NOT NULL
+                )",
+                [],
+            )
+            .unwrap();
+            conn.execute(
+                "INSERT INTO registrations VALUES ('bob', '2001:db8::1', 'n', 0)",
+                [],
+            )
+            .unwrap();
+        }
+
+        let db = Database::open(&path).unwrap();
+        db.heartbeat("bob", false).unwrap();
+        assert!(db.presence("bob").unwrap().unwrap().last_seen.is_some());
+    }
 }
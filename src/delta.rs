@@ -0,0 +1,493 @@
+use anyhow::{bail, Context, Result};
+use indicatif::ProgressBar;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+/// rsync-style delta transfer against a copy the receiver already has.
+///
+/// The receiver cuts its copy into blocks and sends a weak rolling checksum
+/// and a strong hash of each. The sender slides a window over its file and
+/// wherever the window matches a block sends a reference instead of the
+/// data. The content is then a series of frames: `LITERAL` (u32 length and
+/// the bytes), `COPY` (u32 first block, u32 block count) and `END`.
+const FRAME_END: u8 = 0;
+const FRAME_LITERAL: u8 = 1;
+const FRAME_COPY: u8 = 2;
+const MIN_BLOCK: u32 = 2048;
+const MAX_BLOCK: u32 = 128 * 1024;
+/// Blocks a receiver may announce; covers 512 GB at the largest block size
+const MAX_BLOCKS: u32 = 1 << 22;
+/// Literal data goes out in frames of at most this size
+const MAX_LITERAL: usize = 64 * 1024;
+const STRONG_LEN: usize = 16;
+
+struct BlockSignature {
+    weak: u32,
+    strong: [u8; STRONG_LEN],
+}
+
+/// Checksums of the full blocks of the receiver's copy; a shorter tail is
+/// always sent as literal data.
+pub struct Signatures {
+    pub block_size: u32,
+    blocks: Vec<BlockSignature>,
+}
+
+impl Signatures {
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+}
+
+/// Roughly the square root of the file size, as rsync does.
+fn block_size_for(len: u64) -> u32 {
+    ((len.isqrt() as u32) & !1023).clamp(MIN_BLOCK, MAX_BLOCK)
+}
+
+fn strong(data: &[u8]) -> [u8; STRONG_LEN] {
+    let mut hash = [0u8; STRONG_LEN];
+    hash.copy_from_slice(&Sha256::digest(data)[..STRONG_LEN]);
+    hash
+}
+
+/// The rsync rolling checksum of a window, updatable one byte at a time.
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let mut rolling = Rolling {
+            a: 0,
+            b: 0,
+            len: window.len() as u32,
+        };
+        for (i, &byte) in window.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(byte as u32);
+            rolling.b = rolling
+                .b
+                .wrapping_add((window.len() - i) as u32 * byte as u32);
+        }
+        rolling
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+
+    /// Move the window one byte: drop `out` at the front, append `input`.
+    fn roll(&mut self, out: u8, input: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(input as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+}
+
+/// Read until `buf` is full or the input ends.
+async fn read_full<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// Compute the signatures of our copy of a file that is `len` bytes long.
+pub async fn signatures<R: AsyncRead + Unpin>(basis: &mut R, len: u64) -> Result<Signatures> {
+    let block_size = block_size_for(len);
+    let mut buf = vec![0u8; block_size as usize];
+    let mut blocks = Vec::new();
+    while blocks.len() < MAX_BLOCKS as usize {
+        if read_full(basis, &mut buf).await? < buf.len() {
+            break;
+        }
+        blocks.push(BlockSignature {
+            weak: Rolling::new(&buf).digest(),
+            strong: strong(&buf),
+        });
+    }
+    Ok(Signatures { block_size, blocks })
+}
+
+/// Answer a delta request: 0 if we have no copy to work from, otherwise 1
+/// and the signatures.
+pub async fn write_signatures<W>(writer: &mut W, signatures: Option<&Signatures>) -> Result<()>
+where
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let Some(signatures) = signatures else {
+        writer.write_u8(0).await?;
+        writer.flush().await?;
+        return Ok(());
+    };
+
+    let mut data = Vec::with_capacity(9 + signatures.blocks.len() * (4 + STRONG_LEN));
+    data.push(1);
+    data.extend_from_slice(&signatures.block_size.to_be_bytes());
+    data.extend_from_slice(&(signatures.blocks.len() as u32).to_be_bytes());
+    for block in &signatures.blocks {
+        data.extend_from_slice(&block.weak.to_be_bytes());
+        data.extend_from_slice(&block.strong);
+    }
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Read the answer to a delta request; `None` means send everything.
+pub async fn read_signatures<R>(reader: &mut R) -> Result<Option<Signatures>>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    if reader.read_u8().await.context("Peer did not answer")? == 0 {
+        return Ok(None);
+    }
+
+    let block_size = reader.read_u32().await?;
+    let count = reader.read_u32().await?;
+    if !(MIN_BLOCK..=MAX_BLOCK).contains(&block_size) || count > MAX_BLOCKS {
+        bail!(
+            "Invalid block signatures ({} x {} bytes)",
+            count,
+            block_size
+        );
+    }
+
+    let mut blocks = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let weak = reader.read_u32().await?;
+        let mut strong = [0u8; STRONG_LEN];
+        reader.read_exact(&mut strong).await?;
+        blocks.push(BlockSignature { weak, strong });
+    }
+    Ok(Some(Signatures { block_size, blocks }))
+}
+
+async fn write_literal<W: AsyncWrite + Unpin>(send: &mut W, data: &[u8]) -> Result<()> {
+    for piece in data.chunks(MAX_LITERAL) {
+        send.write_u8(FRAME_LITERAL).await?;
+        send.write_u32(piece.len() as u32).await?;
+        send.write_all(piece).await?;
+    }
+    Ok(())
+}
+
+async fn write_copy<W: AsyncWrite + Unpin>(send: &mut W, (first, count): (u32, u32)) -> Result<()> {
+    send.write_u8(FRAME_COPY).await?;
+    send.write_u32(first).await?;
+    send.write_u32(count).await?;
+    Ok(())
+}
+
+/// What `send_delta` did
+pub struct DeltaStats {
+    pub total: u64,
+    pub hash: [u8; 32],
+    /// Bytes sent as literal data rather than block references
+    pub literal: u64,
+}
+
+/// Send `source` as delta frames against the receiver's `signatures`.
+pub async fn send_delta<R, W>(
+    source: &mut R,
+    send: &mut W,
+    signatures: &Signatures,
+    pb: &ProgressBar,
+) -> Result<DeltaStats>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let block = signatures.block_size as usize;
+    let mut by_weak: HashMap<u32, Vec<u32>> = HashMap::new();
+    for (i, signature) in signatures.blocks.iter().enumerate() {
+        by_weak.entry(signature.weak).or_default().push(i as u32);
+    }
+
+    let mut hasher = Sha256::new();
+    let mut chunk = vec![0u8; 256 * 1024];
+    // Data not sent yet; the window starts at `pos`, unmatched bytes before
+    // it are pending literal data
+    let mut buf: Vec<u8> = Vec::new();
+    let mut pos = 0;
+    let mut rolling: Option<Rolling> = None;
+    let mut pending_copy: Option<(u32, u32)> = None;
+    let mut eof = false;
+    let (mut total, mut literal) = (0u64, 0u64);
+
+    loop {
+        // Keep a full window plus the byte after it buffered
+        if !eof && buf.len() - pos <= block {
+            let n = source
+                .read(&mut chunk)
+                .await
+                .context("Failed to read from file")?;
+            if n == 0 {
+                eof = true;
+            } else {
+                hasher.update(&chunk[..n]);
+                buf.extend_from_slice(&chunk[..n]);
+                total += n as u64;
+                pb.set_position(total);
+            }
+            continue;
+        }
+        if buf.len() - pos < block {
+            break;
+        }
+
+        let window = &buf[pos..pos + block];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        let matched = by_weak.get(&weak).and_then(|candidates| {
+            let hash = strong(window);
+            candidates
+                .iter()
+                .copied()
+                .find(|&i| signatures.blocks[i as usize].strong == hash)
+        });
+
+        match matched {
+            Some(index) => {
+                if pos > 0 {
+                    if let Some(copy) = pending_copy.take() {
+                        write_copy(send, copy).await?;
+                    }
+                    write_literal(send, &buf[..pos]).await?;
+                    literal += pos as u64;
+                }
+                pending_copy = match pending_copy {
+                    Some((first, count)) if first + count == index => Some((first, count + 1)),
+                    Some(copy) => {
+                        write_copy(send, copy).await?;
+                        Some((index, 1))
+                    }
+                    None => Some((index, 1)),
+                };
+                buf.drain(..pos + block);
+                pos = 0;
+                rolling = None;
+            }
+            None if pos + block < buf.len() => {
+                if let Some(rolling) = rolling.as_mut() {
+                    rolling.roll(buf[pos], buf[pos + block]);
+                }
+                pos += 1;
+                if pos >= MAX_LITERAL {
+                    if let Some(copy) = pending_copy.take() {
+                        write_copy(send, copy).await?;
+                    }
+                    write_literal(send, &buf[..pos]).await?;
+                    literal += pos as u64;
+                    buf.drain(..pos);
+                    pos = 0;
+                }
+            }
+            // The last window of the file did not match
+            None => break,
+        }
+    }
+
+    if let Some(copy) = pending_copy {
+        write_copy(send, copy).await?;
+    }
+    write_literal(send, &buf).await?;
+    literal += buf.len() as u64;
+    send.write_u8(FRAME_END).await?;
+
+    Ok(DeltaStats {
+        total,
+        hash: hasher.finalize().into(),
+        literal,
+    })
+}
+
+/// Rebuild the sender's file from delta frames and our copy (`basis`),
+/// which `signatures` were computed from. Returns the length and SHA256 of
+/// what was written.
+pub async fn apply_delta<R, B, W>(
+    recv: &mut R,
+    basis: &mut B,
+    out: &mut W,
+    signatures: &Signatures,
+    pb: &ProgressBar,
+) -> Result<(u64, [u8; 32])>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    B: AsyncRead + AsyncSeek + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let block = signatures.block_size as usize;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; MAX_LITERAL.max(block)];
+    let mut total = 0u64;
+
+    loop {
+        match recv.read_u8().await.context("Failed to read delta frame")? {
+            FRAME_END => break,
+            FRAME_LITERAL => {
+                let len = recv.read_u32().await? as usize;
+                if len > MAX_LITERAL {
+                    bail!("Literal frame too large ({} bytes)", len);
+                }
+                recv.read_exact(&mut buf[..len]).await?;
+                hasher.update(&buf[..len]);
+                out.write_all(&buf[..len])
+                    .await
+                    .context("Failed to write to file")?;
+                total += len as u64;
+            }
+            FRAME_COPY => {
+                let first = recv.read_u32().await? as usize;
+                let count = recv.read_u32().await? as usize;
+                if count == 0 || first + count > signatures.len() {
+                    bail!("Invalid block reference {}+{}", first, count);
+                }
+                basis.seek(SeekFrom::Start((first * block) as u64)).await?;
+                for _ in 0..count {
+                    basis
+                        .read_exact(&mut buf[..block])
+                        .await
+                        .context("Our copy changed during the transfer")?;
+                    hasher.update(&buf[..block]);
+                    out.write_all(&buf[..block])
+                        .await
+                        .context("Failed to write to file")?;
+                }
+                total += (count * block) as u64;
+            }
+            other => bail!("Unknown delta frame {}", other),
+        }
+        pb.set_position(total);
+    }
+
+    Ok((total, hasher.finalize().into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::RngCore;
+    use std::io::Cursor;
+
+    fn random(len: usize) -> Vec<u8> {
+        let mut data = vec![0u8; len];
+        rand::thread_rng().fill_bytes(&mut data);
+        data
+    }
+
+    #[test]
+    fn test_rolling_matches_fresh_checksum() {
+        let data = random(5000);
+        let window = 1000;
+        let mut rolling = Rolling::new(&data[..window]);
+        for start in 1..data.len() - window {
+            rolling.roll(data[start - 1], data[start + window - 1]);
+            assert_eq!(
+                rolling.digest(),
+                Rolling::new(&data[start..start + window]).digest()
+            );
+        }
+    }
+
+    /// Send `new` against `old` and check the result; returns the number of
+    /// literal bytes.
+    async fn roundtrip(old: &[u8], new: &[u8]) -> u64 {
+        let pb = ProgressBar::hidden();
+        let signatures = signatures(&mut Cursor::new(old), old.len() as u64)
+            .await
+            .unwrap();
+
+        // Through the wire format and back
+        let mut reply = Vec::new();
+        write_signatures(&mut reply, Some(&signatures))
+            .await
+            .unwrap();
+        let signatures = read_signatures(&mut reply.as_slice())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut frames = Vec::new();
+        let stats = send_delta(&mut Cursor::new(new), &mut frames, &signatures, &pb)
+            .await
+            .unwrap();
+        assert_eq!(stats.total, new.len() as u64);
+
+        let mut rebuilt = Vec::new();
+        let (total, hash) = apply_delta(
+            &mut frames.as_slice(),
+            &mut Cursor::new(old),
+            &mut rebuilt,
+            &signatures,
+            &pb,
+        )
+        .await
+        .unwrap();
+        assert_eq!(rebuilt, new);
+        assert_eq!(total, new.len() as u64);
+        assert_eq!(hash, stats.hash);
+        assert_eq!(hash, <[u8; 32]>::from(Sha256::digest(new)));
+        stats.literal
+    }
+
+    #[tokio::test]
+    async fn test_delta_roundtrip() {
+        let old = random(300_000);
+        let block = block_size_for(old.len() as u64) as u64;
+
+        // Unchanged: everything but the tail after the last full block is
+        // referenced
+        assert!(roundtrip(&old, &old).await < block);
+
+        // Insert, modify and delete in the middle, append at the end
+        let mut new = old.clone();
+        new.splice(1000..1000, random(500));
+        new[100_000] ^= 0xff;
+        new.drain(200_000..210_000);
+        new.extend(random(3000));
+        // Each edit costs at most the blocks it touches (two for the
+        // deletion), plus the old tail
+        let literal = roundtrip(&old, &new).await;
+        assert!(literal <= 500 + 4 * block + 3000 + block, "{}", literal);
+
+        // Nothing in common, and a basis smaller than a block
+        assert_eq!(roundtrip(&old, &random(70_000)).await, 70_000);
+        assert_eq!(roundtrip(b"short", &new).await, new.len() as u64);
+        assert_eq!(roundtrip(&old, b"").await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_apply_rejects_bad_references() {
+        let old = random(10_000);
+        let signatures = signatures(&mut Cursor::new(&old), old.len() as u64)
+            .await
+            .unwrap();
+        let frames = [FRAME_COPY, 0, 0, 0, 9, 0, 0, 0, 1, FRAME_END];
+        let result = apply_delta(
+            &mut frames.as_slice(),
+            &mut Cursor::new(&old),
+            &mut Vec::new(),
+            &signatures,
+            &ProgressBar::hidden(),
+        )
+        .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_block_size() {
+        assert_eq!(block_size_for(0), MIN_BLOCK);
+        assert_eq!(block_size_for(100 * 1024 * 1024), 10 * 1024);
+        assert_eq!(block_size_for(100 * 1024 * 1024 * 1024), MAX_BLOCK);
+    }
+}
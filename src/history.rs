@@ -0,0 +1,498 @@
+use anyhow::{Context, Result};
+use rusqlite::{params_from_iter, Connection};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Every transfer this machine took part in, kept in `~/.rxx/history.db`.
+pub struct History {
+    conn: Connection,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl Direction {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Sent => "sent",
+            Direction::Received => "received",
+        }
+    }
+}
+
+/// A transfer as stored in the history
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Record {
+    pub id: i64,
+    /// Unix time the transfer started
+    pub time: i64,
+    pub direction: Direction,
+    /// User ID of the other side, if known
+    pub peer: Option<String>,
+    /// `peer` is only what the sender put in its header, not an ID we
+    /// connected to or checked
+    pub claimed: bool,
+    /// Address of the other side, or `mailbox` for mailbox transfers
+    pub remote: Option<String>,
+    pub file_name: Option<String>,
+    /// Where the file was read from or stored locally
+    pub path: Option<String>,
+    pub size: Option<u64>,
+    pub sha256: Option<String>,
+    pub duration_ms: u64,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+/// What to show in `rxx history`
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub peer: Option<String>,
+    pub direction: Option<Direction>,
+    /// Part of the file name
+    pub file: Option<String>,
+    pub failed_only: bool,
+    /// Unix time of the oldest transfer to show
+    pub since: Option<i64>,
+    pub limit: usize,
+}
+
+/// A transfer in progress, recorded with `finish`.
+pub struct Transfer {
+    pub direction: Direction,
+    pub peer: Option<String>,
+    claimed: bool,
+    pub remote: Option<String>,
+    pub file_name: Option<String>,
+    pub path: Option<PathBuf>,
+    pub size: Option<u64>,
+    pub sha256: Option<[u8; 32]>,
+    time: i64,
+    started: Instant,
+}
+
+impl Transfer {
+    pub fn start(direction: Direction, remote: Option<String>) -> Self {
+        Transfer {
+            direction,
+            peer: None,
+            claimed: false,
+            remote,
+            file_name: None,
+            path: None,
+            size: None,
+            sha256: None,
+            time: crate::outbox::now(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Set the name and local path of a file being sent.
+    pub fn local_file(&mut self, path: &Path) {
+        self.file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        self.path = Some(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+    }
+
+    /// Set the peer we know we are talking to, if any.
+    pub fn expected_peer(&mut self, peer: Option<&str>) {
+        if let Some(peer) = peer {
+            self.peer = Some(peer.to_string());
+            self.claimed = false;
+        }
+    }
+
+    /// Fall back to the ID a sender claims when we don't know the peer.
+    fn claimed_peer(&mut self, sender_id: &str) {
+        if self.peer.is_none() {
+            self.peer = Some(sender_id.to_string());
+            self.claimed = true;
+        }
+    }
+
+    /// Set what the sender of an incoming file announced.
+    pub fn header(&mut self, header: &crate::quic::Header) {
+        self.claimed_peer(&header.sender_id);
+        self.file_name = Some(header.file_name.clone());
+        self.size = Some(header.size);
+    }
+
+    /// Set the name of a file sent from stdin.
+    pub fn stream(&mut self, name: &str) {
+        self.file_name = Some(name.to_string());
+    }
+
+    /// Set where an incoming file was stored.
+    pub fn received(&mut self, received: &crate::quic::Received) {
+        self.claimed_peer(&received.sender_id);
+        self.file_name = received
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        self.path = Some(received.path.clone());
+        self.size = Some(received.size);
+        self.sha256 = Some(received.sha256);
+    }
+
+    /// Set the size and SHA256 of what was transferred.
+    pub fn content(&mut self, size: u64, sha256: [u8; 32]) {
+        self.size = Some(size);
+        self.sha256 = Some(sha256);
+    }
+
+    /// Record the transfer with the outcome in `result`. Failing to write
+    /// the history never fails the transfer itself.
+    pub async fn finish<T>(self, result: &Result<T>) {
+        let record = Record {
+            id: 0,
+            time: self.time,
+            direction: self.direction,
+            peer: self.peer,
+            claimed: self.claimed,
+            remote: self.remote,
+            file_name: self.file_name,
+            path: self.path.map(|path| path.to_string_lossy().into_owned()),
+            size: self.size,
+            sha256: self.sha256.map(hex::encode),
+            duration_ms: self.started.elapsed().as_millis() as u64,
+            ok: result.is_ok(),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        };
+        // Waiting for a busy database must not stall the runtime
+        let added = tokio::task::spawn_blocking(move || {
+            History::open_default().and_then(|history| history.add(&record))
+        })
+        .await;
+        match added {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => crate::debug!("DEBUG [HISTORY]: Failed to record transfer: {:#}", e),
+            Err(e) => crate::debug!("DEBUG [HISTORY]: Failed to record transfer: {}", e),
+        }
+    }
+}
+
+impl History {
+    pub fn open_default() -> Result<Self> {
+        Self::open(crate::config::Config::data_dir()?.join("history.db"))
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let conn = Connection::open(path.as_ref())
+            .with_context(|| format!("Failed to open history {:?}", path.as_ref()))?;
+        // Concurrent transfers record at the same time
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS transfers (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                time INTEGER NOT NULL,
+                direction TEXT NOT NULL,
+                peer TEXT,
+                claimed INTEGER NOT NULL DEFAULT 0,
+                remote TEXT,
+                file_name TEXT,
+                path TEXT,
+                size INTEGER,
+                sha256 TEXT,
+                duration_ms INTEGER NOT NULL,
+                ok INTEGER NOT NULL,
+                error TEXT
+            )",
+            [],
+        )?;
+
+        crate::db::add_missing_column(&conn, "transfers", "claimed", "INTEGER NOT NULL DEFAULT 0")?;
+        Ok(History { conn })
+    }
+
+    pub fn add(&self, record: &Record) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO transfers (time, direction, peer, claimed, remote, file_name, path,
+                size, sha256, duration_ms, ok, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            rusqlite::params![
+                record.time,
+                record.direction.as_str(),
+                record.peer,
+                record.claimed,
+                record.remote,
+                record.file_name,
+                record.path,
+                record.size,
+                record.sha256,
+                record.duration_ms,
+                record.ok,
+                record.error
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Transfers matching `filter`, newest first.
+    pub fn query(&self, filter: &Filter) -> Result<Vec<Record>> {
+        let mut sql = String::from("SELECT * FROM transfers WHERE 1");
+        let mut values: Vec<rusqlite::types::Value> = Vec::new();
+        if let Some(peer) = &filter.peer {
+            sql.push_str(" AND lower(peer) = lower(?)");
+            values.push(peer.clone().into());
+        }
+        if let Some(direction) = filter.direction {
+            sql.push_str(" AND direction = ?");
+            values.push(direction.as_str().to_string().into());
+        }
+        if let Some(file) = &filter.file {
+            sql.push_str(" AND instr(lower(file_name), lower(?)) > 0");
+            values.push(file.clone().into());
+        }
+        if filter.failed_only {
+            sql.push_str(" AND ok = 0");
+        }
+        if let Some(since) = filter.since {
+            sql.push_str(" AND time >= ?");
+            values.push(since.into());
+        }
+        sql.push_str(" ORDER BY time DESC, id DESC LIMIT ?");
+        values.push((filter.limit as i64).into());
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let records = stmt
+            .query_map(params_from_iter(values), |row| {
+                let direction: String = row.get("direction")?;
+                Ok(Record {
+                    id: row.get("id")?,
+                    time: row.get("time")?,
+                    direction: if direction == "sent" {
+                        Direction::Sent
+                    } else {
+                        Direction::Received
+                    },
+                    peer: row.get("peer")?,
+                    claimed: row.get("claimed")?,
+                    remote: row.get("remote")?,
+                    file_name: row.get("file_name")?,
+                    path: row.get("path")?,
+                    size: row.get("size")?,
+                    sha256: row.get("sha256")?,
+                    duration_ms: row.get("duration_ms")?,
+                    ok: row.get("ok")?,
+                    error: row.get("error")?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(records)
+    }
+}
+
+impl Record {
+    /// One line of `rxx history` output.
+    pub fn describe(&self) -> String {
+        let arrow = match self.direction {
+            Direction::Sent => "->",
+            Direction::Received => "<-",
+        };
+        // Mark IDs we only have the sender's word for
+        let peer = match (&self.peer, &self.remote) {
+            (Some(peer), _) if self.claimed => format!("{}?", peer),
+            (Some(peer), _) | (None, Some(peer)) => peer.clone(),
+            (None, None) => "?".to_string(),
+        };
+        let size = self.size.map_or("-".to_string(), |size| size.to_string());
+        let mut line = format!(
+            "{}  {} {:<12} {:>12}  {:>6.1}s  {}",
+            format_time(self.time),
+            arrow,
+            peer,
+            size,
+            self.duration_ms as f64 / 1000.0,
+            self.file_name.as_deref().unwrap_or("?")
+        );
+        if let Some(error) = &self.error {
+            line.push_str(&format!("  FAILED: {}", error));
+        }
+        line
+    }
+}
+
+/// Parse a period such as `90s`, `30m`, `12h` or `7d`.
+pub fn parse_period(period: &str) -> Result<Duration> {
+    let split = period
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(period.len());
+    let (number, unit) = period.split_at(split);
+    let number: u64 = number
+        .parse()
+        .with_context(|| format!("Invalid period {:?}", period))?;
+    let unit = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "" | "d" => 86400,
+        _ => anyhow::bail!("Invalid period {:?}, use e.g. 30m, 12h or 7d", period),
+    };
+    Ok(Duration::from_secs(number.saturating_mul(unit)))
+}
+
+/// Unix time as `YYYY-MM-DD HH:MM:SS` UTC.
+fn format_time(time: i64) -> String {
+    let (days, secs) = (time.div_euclid(86400), time.rem_euclid(86400));
+
+    // Civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(time: i64, direction: Direction, peer: &str, file: &str, ok: bool) -> Record {
+        Record {
+            id: 0,
+            time,
+            direction,
+            peer: Some(peer.to_string()),
+            claimed: false,
+            remote: Some("[2001:db8::1]:3458".to_string()),
+            file_name: Some(file.to_string()),
+            path: Some(format!("/data/{}", file)),
+            size: Some(100),
+            sha256: Some("ab".repeat(32)),
+            duration_ms: 1500,
+            ok,
+            error: (!ok).then(|| "hash mismatch".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_query() {
+        let history = History::open(":memory:").unwrap();
+        history
+            .add(&record(100, Direction::Received, "bob", "report.pdf", true))
+            .unwrap();
+        history
+            .add(&record(200, Direction::Sent, "bob", "notes.txt", true))
+            .unwrap();
+        history
+            .add(&record(
+                300,
+                Direction::Received,
+                "Carol",
+                "Report-2.pdf",
+                false,
+            ))
+            .unwrap();
+
+        let query = |filter: Filter| {
+            history
+                .query(&Filter {
+                    limit: 10,
+                    ..filter
+                })
+                .unwrap()
+                .iter()
+                .map(|r| r.time)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(query(Filter::default()), [300, 200, 100]);
+        assert_eq!(
+            query(Filter {
+                peer: Some("BOB".to_string()),
+                ..Default::default()
+            }),
+            [200, 100]
+        );
+        assert_eq!(
+            query(Filter {
+                direction: Some(Direction::Received),
+                file: Some("report".to_string()),
+                ..Default::default()
+            }),
+            [300, 100]
+        );
+        assert_eq!(
+            query(Filter {
+                failed_only: true,
+                ..Default::default()
+            }),
+            [300]
+        );
+        assert_eq!(
+            query(Filter {
+                since: Some(200),
+                ..Default::default()
+            }),
+            [300, 200]
+        );
+
+        let all = history
+            .query(&Filter {
+                limit: 1,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].error.as_deref(), Some("hash mismatch"));
+        assert_eq!(all[0].peer.as_deref(), Some("Carol"));
+        assert!(!all[0].ok);
+        assert!(!all[0].claimed);
+    }
+
+    #[test]
+    fn test_claimed_peer() {
+        let mut entry = Transfer::start(Direction::Received, None);
+        entry.claimed_peer("mallory");
+        assert_eq!(entry.peer.as_deref(), Some("mallory"));
+        assert!(entry.claimed);
+
+        let mut entry = Transfer::start(Direction::Received, None);
+        entry.expected_peer(Some("alice"));
+        entry.claimed_peer("mallory");
+        assert_eq!(entry.peer.as_deref(), Some("alice"));
+        assert!(!entry.claimed);
+
+        let mut claimed = record(100, Direction::Received, "mallory", "a.txt", true);
+        claimed.claimed = true;
+        assert!(claimed.describe().contains("<- mallory? "));
+    }
+
+    #[test]
+    fn test_parse_period() {
+        assert_eq!(parse_period("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_period("30m").unwrap(), Duration::from_secs(1800));
+        assert_eq!(parse_period("12h").unwrap(), Duration::from_secs(43200));
+        assert_eq!(parse_period("7d").unwrap(), Duration::from_secs(604800));
+        assert_eq!(parse_period("2").unwrap(), Duration::from_secs(172800));
+        assert!(parse_period("").is_err());
+        assert!(parse_period("d").is_err());
+        assert!(parse_period("3w").is_err());
+    }
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00");
+        assert_eq!(format_time(1792377398), "2026-10-19 02:36:38");
+    }
+}
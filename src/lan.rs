@@ -0,0 +1,250 @@
+use anyhow::{Context, Result};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use tokio::net::UdpSocket;
+use tokio::time::{timeout_at, Duration, Instant};
+
+/// Link-local multicast group peers announce themselves on
+pub const DISCOVERY_GROUP: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x7278);
+pub const DISCOVERY_PORT: u16 = 3459;
+const DISCOVERY_TIMEOUT_MS: u64 = 1000;
+const MAGIC: &str = "RXX_LAN1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Message {
+    /// "Is `target` on this link?", asked by `asker`
+    Query { target: String, asker: String },
+    /// `id` is here and punches from `addr`
+    Answer { id: String, addr: Ipv6Addr },
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Message::Query { target, asker } => format!("{} Q {} {}", MAGIC, target, asker),
+            Message::Answer { id, addr } => format!("{} A {} {}", MAGIC, id, addr),
+        }
+        .into_bytes()
+    }
+
+    fn decode(data: &[u8]) -> Option<Self> {
+        let text = std::str::from_utf8(data).ok()?;
+        let mut parts = text.split(' ');
+        if parts.next()? != MAGIC {
+            return None;
+        }
+        let message = match (parts.next()?, parts.next()?, parts.next()?) {
+            ("Q", target, asker) => Message::Query {
+                target: target.to_string(),
+                asker: asker.to_string(),
+            },
+            ("A", id, addr) => Message::Answer {
+                id: id.to_string(),
+                addr: addr.parse().ok()?,
+            },
+            _ => return None,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(message)
+    }
+}
+
+/// Indexes of all interfaces that have an IPv6 address, i.e. every link a
+/// peer might be on.
+fn ipv6_interfaces() -> Vec<u32> {
+    let mut indexes: Vec<u32> = if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|iface| !iface.is_loopback() && matches!(iface.addr.ip(), IpAddr::V6(_)))
+        .filter_map(|iface| iface.index)
+        .collect();
+    indexes.sort_unstable();
+    indexes.dedup();
+    indexes
+}
+
+/// Answer discovery queries for `user_id` until the process exits.
+///
+/// Every running `rxx send`/`rxx receive` starts one of these, so peers on
+/// the same link can find each other without the registration server. The
+/// socket uses `SO_REUSEPORT` so a sender and a receiver on the same host can
+/// both listen.
+pub fn spawn_responder(user_id: &str) -> Result<()> {
+    let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))
+        .context("Failed to create discovery socket")?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, DISCOVERY_PORT)).into())
+        .context("Failed to bind discovery socket")?;
+
+    let mut joined = 0;
+    for index in ipv6_interfaces() {
+        match socket.join_multicast_v6(&DISCOVERY_GROUP, index) {
+            Ok(()) => joined += 1,
+            Err(e) => crate::debug!(
+                "DEBUG [LAN]: Failed to join discovery group on interface {}: {}",
+                index,
+                e
+            ),
+        }
+    }
+    if joined == 0 {
+        anyhow::bail!("No interface available for LAN discovery");
+    }
+
+    let socket =
+        UdpSocket::from_std(socket.into()).context("Failed to register discovery socket")?;
+    let user_id = user_id.to_string();
+
+    tokio::spawn(async move {
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    crate::debug!("DEBUG [LAN]: Discovery receive error: {}", e);
+                    continue;
+                }
+            };
+
+            let Some(Message::Query { target, asker }) = Message::decode(&buf[..len]) else {
+                continue;
+            };
+            if !target.eq_ignore_ascii_case(&user_id) || asker.eq_ignore_ascii_case(&user_id) {
+                continue;
+            }
+
+            // Announce a routable address if we have one, otherwise the
+            // link-local address on the interface the query came in on
+            let link = match from {
+                SocketAddr::V6(from) => Some(from),
+                SocketAddr::V4(_) => None,
+            };
+            let addr = match crate::net::get_local_ipv6() {
+                Ok(addr) if !crate::net::is_link_local(&addr) => Ok(addr),
+                _ => crate::net::select_local(link.as_ref()).map(|local| *local.ip()),
+            };
+            let addr = match addr {
+                Ok(addr) => addr,
+                Err(e) => {
+                    crate::debug!("DEBUG [LAN]: Not answering {}: {}", asker, e);
+                    continue;
+                }
+            };
+
+            crate::debug!(
+                "DEBUG [LAN]: Answering discovery query from {} ({}) with {}",
+                asker,
+                from,
+                addr
+            );
+            let answer = Message::Answer {
+                id: user_id.clone(),
+                addr,
+            };
+            if let Err(e) = socket.send_to(&answer.encode(), from).await {
+                crate::debug!("DEBUG [LAN]: Failed to answer {}: {}", from, e);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Look for `target` on the local links.
+///
+/// Sends a query to the discovery group on every IPv6 interface and returns
+/// the address from the first matching answer. Link-local answers keep the
+/// scope ID of the interface they arrived on so they stay usable.
+pub async fn discover(target: &str, user_id: &str) -> Result<Option<SocketAddrV6>> {
+    let socket = UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0))
+        .await
+        .context("Failed to bind discovery query socket")?;
+    let socket_ref = socket2::SockRef::from(&socket);
+
+    let query = Message::Query {
+        target: target.to_string(),
+        asker: user_id.to_string(),
+    }
+    .encode();
+
+    for index in ipv6_interfaces() {
+        let sent = socket_ref.set_multicast_if_v6(index).and_then(|_| {
+            socket_ref.send_to(
+                &query,
+                &SocketAddr::from(SocketAddrV6::new(DISCOVERY_GROUP, DISCOVERY_PORT, 0, index))
+                    .into(),
+            )
+        });
+        if let Err(e) = sent {
+            crate::debug!(
+                "DEBUG [LAN]: Failed to send discovery query on interface {}: {}",
+                index,
+                e
+            );
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_millis(DISCOVERY_TIMEOUT_MS);
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, from) = match timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            Ok(received) => received.context("Failed to receive discovery answer")?,
+            Err(_) => return Ok(None),
+        };
+
+        let Some(Message::Answer { id, addr }) = Message::decode(&buf[..len]) else {
+            continue;
+        };
+        if !id.eq_ignore_ascii_case(target) {
+            continue;
+        }
+
+        let scope_id = match from {
+            SocketAddr::V6(from) if crate::net::is_link_local(&addr) => from.scope_id(),
+            _ => 0,
+        };
+        crate::debug!(
+            "DEBUG [LAN]: {} answered from {} with {} (scope {})",
+            id,
+            from,
+            addr,
+            scope_id
+        );
+        return Ok(Some(SocketAddrV6::new(addr, 0, 0, scope_id)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_roundtrip() {
+        let query = Message::Query {
+            target: "alice".to_string(),
+            asker: "bob".to_string(),
+        };
+        assert_eq!(Message::decode(&query.encode()), Some(query));
+
+        let answer = Message::Answer {
+            id: "alice".to_string(),
+            addr: "fe80::1".parse().unwrap(),
+        };
+        assert_eq!(Message::decode(&answer.encode()), Some(answer));
+    }
+
+    #[test]
+    fn test_message_rejects_garbage() {
+        assert_eq!(Message::decode(b"RXX_PROBE"), None);
+        assert_eq!(Message::decode(b"RXX_LAN1 A alice not-an-address"), None);
+        assert_eq!(Message::decode(b"RXX_LAN1 Q alice"), None);
+        assert_eq!(Message::decode(b"RXX_LAN1 Q alice bob extra"), None);
+        assert_eq!(Message::decode(&[0xff, 0xfe]), None);
+    }
+}
@@ -0,0 +1,464 @@
+use crate::compress::Compress;
+use crate::config::Config;
+use crate::history::{Direction, Transfer};
+use crate::meta::Preserve;
+use crate::quic::Received;
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// How often a running receiver checks its mailbox
+const POLL_SECS: u64 = 300;
+/// Buffer between the file encoder and the cipher
+const PIPE_SIZE: usize = 256 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct MailboxItem {
+    item_id: String,
+    sender: String,
+    size: u64,
+}
+
+fn nonce(config: &Config) -> Result<&str> {
+    config.nonce.as_deref().ok_or_else(|| {
+        anyhow::anyhow!("No nonce found. Please re-register with: rxx register <id>")
+    })
+}
+
+/// Check a mailbox response, mapping the statuses every endpoint shares.
+fn check_status(response: &reqwest::Response) -> Result<()> {
+    let status = response.status();
+    if status == reqwest::StatusCode::UNAUTHORIZED {
+        bail!("Invalid nonce. Please re-register with: rxx register <id>");
+    } else if !status.is_success() {
+        bail!("Server error: {}", status);
+    }
+    Ok(())
+}
+
+/// Our mailbox secret, generated on first use. Only the new key is added to
+/// the config file; whatever else `config` holds stays in memory.
+pub fn load_or_create_key(config: &mut Config) -> Result<StaticSecret> {
+    if let Some(key) = &config.mailbox_key {
+        return crate::seal::secret_from_hex(key);
+    }
+    let secret = crate::seal::generate_secret();
+    let key = hex::encode(secret.to_bytes());
+    let mut saved = Config::load()?;
+    saved.mailbox_key = Some(key.clone());
+    saved.save()?;
+    config.mailbox_key = Some(key);
+    crate::info!("Generated mailbox key");
+    Ok(secret)
+}
+
+/// The public half of our mailbox key, as published to the server.
+pub fn public_key(config: &Config) -> Option<String> {
+    let secret = crate::seal::secret_from_hex(config.mailbox_key.as_deref()?).ok()?;
+    Some(hex::encode(PublicKey::from(&secret).as_bytes()))
+}
+
+async fn recipient_key(config: &Config, recipient: &str) -> Result<PublicKey> {
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/mailbox/key", config.server_url))
+        .json(&serde_json::json!({
+            "id": config.user_id,
+            "nonce": nonce(config)?,
+            "peer_id": recipient
+        }))
+        .send()
+        .await
+        .context("Failed to contact server")?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        bail!(
+            "No mailbox for '{}': the server has no mailbox or {} has never run rxx receive",
+            recipient,
+            recipient
+        );
+    }
+    check_status(&response)?;
+
+    let body: serde_json::Value = response.json().await?;
+    let key = body["public_key"]
+        .as_str()
+        .ok_or_else(|| anyhow::anyhow!("Server did not return a key"))?;
+    crate::seal::public_from_hex(key)
+}
+
+/// Encrypt a file for `recipient` and leave it in their mailbox on the server.
+///
+/// The server only ever sees the sealed blob; the recipient's public key
+/// comes from the server, so the server has to be trusted to hand out the
+/// right one.
+pub async fn send(config: &Config, file_path: &Path, recipient: &str) -> Result<()> {
+    let key = recipient_key(config, recipient).await?;
+
+    // Seal into a temporary file first so the upload has a known length
+    let sealed_path = std::env::temp_dir().join(format!(
+        "rxx-mailbox-{}.sealed",
+        hex::encode(rand::random::<[u8; 8]>())
+    ));
+    let mut entry = Transfer::start(Direction::Sent, Some("mailbox".to_string()));
+    entry.peer = Some(recipient.to_string());
+    entry.local_file(file_path);
+    let result = seal_and_upload(config, file_path, recipient, &key, &sealed_path).await;
+    let _ = tokio::fs::remove_file(&sealed_path).await;
+    if let Ok((total, hash)) = result {
+        entry.content(total, hash);
+    }
+    entry.finish(&result).await;
+    result.map(|_| ())
+}
+
+async fn seal_and_upload(
+    config: &Config,
+    file_path: &Path,
+    recipient: &str,
+    key: &PublicKey,
+    sealed_path: &Path,
+) -> Result<(u64, [u8; 32])> {
+    let (total, hash, sealed_size) =
+        seal_file(&config.user_id, file_path, key, sealed_path).await?;
+
+    crate::info!(
+        "Uploading {} bytes ({} encrypted) to {}'s mailbox...",
+        total,
+        sealed_size,
+        recipient
+    );
+    let body = tokio::fs::File::open(sealed_path).await?;
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/mailbox/upload", config.server_url))
+        .header("x-rxx-id", &config.user_id)
+        .header("x-rxx-nonce", nonce(config)?)
+        .header("x-rxx-recipient", recipient)
+        .header(reqwest::header::CONTENT_LENGTH, sealed_size)
+        .body(body)
+        .send()
+        .await
+        .context("Failed to contact server")?;
+
+    if response.status() == reqwest::StatusCode::PAYLOAD_TOO_LARGE {
+        bail!("{}'s mailbox is full", recipient);
+    } else if response.status() == reqwest::StatusCode::NOT_FOUND {
+        bail!("Unknown recipient or mailbox disabled on the server");
+    }
+    check_status(&response)?;
+
+    crate::info!("SHA256: {}", hex::encode(hash));
+    crate::info!(
+        "File left in {}'s mailbox; it is delivered when they run rxx receive",
+        recipient
+    );
+    Ok((total, hash))
+}
+
+/// Encode `file_path` as for a direct transfer and encrypt it for `key` into
+/// `sealed_path`. Returns the file size, its hash and the sealed size.
+async fn seal_file(
+    user_id: &str,
+    file_path: &Path,
+    key: &PublicKey,
+    sealed_path: &Path,
+) -> Result<(u64, [u8; 32], u64)> {
+    let mut sealed = tokio::fs::File::create(sealed_path)
+        .await
+        .context("Failed to create temporary file")?;
+
+    let (mut plain_reader, mut plain_writer) = tokio::io::duplex(PIPE_SIZE);
+    let encode = async move {
+        let result =
+            crate::quic::write_file(&mut plain_writer, file_path, user_id, None, Compress::None)
+                .await;
+        plain_writer.shutdown().await?;
+        result
+    };
+    let encrypt = crate::seal::seal(&mut plain_reader, &mut sealed, key);
+    let ((total, hash), sealed_size) = tokio::try_join!(encode, encrypt)?;
+    sealed.sync_all().await?;
+    Ok((total, hash, sealed_size))
+}
+
+async fn list(config: &Config) -> Result<Vec<MailboxItem>> {
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/mailbox/list", config.server_url))
+        .json(&serde_json::json!({
+            "id": config.user_id,
+            "nonce": nonce(config)?
+        }))
+        .send()
+        .await
+        .context("Failed to contact server")?;
+
+    if response.status() == reqwest::StatusCode::NOT_FOUND {
+        // Server without a mailbox
+        return Ok(Vec::new());
+    }
+    check_status(&response)?;
+
+    #[derive(Deserialize)]
+    struct ListResponse {
+        items: Vec<MailboxItem>,
+    }
+    Ok(response.json::<ListResponse>().await?.items)
+}
+
+async fn delete(config: &Config, item_id: &str) -> Result<()> {
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/mailbox/delete", config.server_url))
+        .json(&serde_json::json!({
+            "id": config.user_id,
+            "nonce": nonce(config)?,
+            "item_id": item_id
+        }))
+        .send()
+        .await
+        .context("Failed to contact server")?;
+    check_status(&response)
+}
+
+/// Download, decrypt and store one item.
+async fn fetch(
+    config: &Config,
+    secret: &StaticSecret,
+    item: &MailboxItem,
+    output_dir: &Path,
+    user_folder: bool,
+    keep: Preserve,
+) -> Result<Received> {
+    let client = reqwest::Client::new();
+    let mut response = client
+        .post(format!("{}/mailbox/fetch", config.server_url))
+        .json(&serde_json::json!({
+            "id": config.user_id,
+            "nonce": nonce(config)?,
+            "item_id": item.item_id
+        }))
+        .send()
+        .await
+        .context("Failed to contact server")?;
+    check_status(&response)?;
+
+    // Download -> decrypt -> decode, streamed through two pipes
+    let (mut sealed_reader, mut sealed_writer) = tokio::io::duplex(PIPE_SIZE);
+    let download = async move {
+        while let Some(chunk) = response.chunk().await? {
+            sealed_writer.write_all(&chunk).await?;
+        }
+        sealed_writer.shutdown().await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    let store = open_item(
+        &mut sealed_reader,
+        secret,
+        &item.sender,
+        output_dir,
+        user_folder,
+        keep,
+        config,
+    );
+    let (_, received) = tokio::try_join!(download, store)?;
+    Ok(received)
+}
+
+/// Decrypt a sealed item from `sealed` and store the file it holds. The
+/// item is refused before anything is written if the name sealed inside
+/// is not that of `sender`, who uploaded it.
+async fn open_item<R: AsyncRead + Unpin>(
+    sealed: &mut R,
+    secret: &StaticSecret,
+    sender: &str,
+    output_dir: &Path,
+    user_folder: bool,
+    keep: Preserve,
+    config: &Config,
+) -> Result<Received> {
+    let (mut plain_reader, mut plain_writer) = tokio::io::duplex(PIPE_SIZE);
+    let decrypt = async move {
+        crate::seal::open(sealed, &mut plain_writer, secret).await?;
+        plain_writer.shutdown().await?;
+        Ok::<_, anyhow::Error>(())
+    };
+    let decode = async {
+        let header = crate::quic::read_header(&mut plain_reader).await?;
+        header.check_sender(Some(sender))?;
+        crate::quic::read_content(
+            &mut plain_reader,
+            None,
+            header,
+            output_dir,
+            user_folder,
+            keep,
+            config,
+        )
+        .await
+    };
+    let (_, received) = tokio::try_join!(decrypt, decode)?;
+    Ok(received)
+}
+
+/// Deliver everything waiting in our mailbox. Items are deleted from the
+/// server only after they were stored and verified; those that fail stay
+/// there.
+pub async fn fetch_pending(
+    config: &Config,
+    secret: &StaticSecret,
+    output_dir: &Path,
+    user_folder: bool,
+    keep: Preserve,
+) -> Result<usize> {
+    let items = list(config).await?;
+    let mut delivered = 0;
+    for item in items {
+        crate::info!(
+            "Fetching mailbox item from {} ({} bytes)...",
+            item.sender,
+            item.size
+        );
+        let mut entry = Transfer::start(Direction::Received, Some("mailbox".to_string()));
+        entry.peer = Some(item.sender.clone());
+        let result = fetch(config, secret, &item, output_dir, user_folder, keep).await;
+        if let Ok(received) = &result {
+            entry.received(received);
+        }
+        entry.finish(&result).await;
+        match result {
+            Ok(_) => {
+                delivered += 1;
+                // Stored already; the item is fetched again on the next check
+                if let Err(e) = delete(config, &item.item_id).await {
+                    eprintln!("Failed to delete mailbox item {}: {:#}", item.item_id, e);
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to fetch mailbox item {}: {:#}", item.item_id, e);
+            }
+        }
+    }
+    Ok(delivered)
+}
+
+/// Check the mailbox now and then while the receiver runs.
+pub fn spawn_poller(
+    config: &Config,
+    secret: StaticSecret,
+    output_dir: &Path,
+    user_folder: bool,
+    keep: Preserve,
+) -> tokio::task::JoinHandle<()> {
+    let config = config.clone();
+    let output_dir = output_dir.to_path_buf();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(POLL_SECS));
+        loop {
+            ticker.tick().await;
+            match fetch_pending(&config, &secret, &output_dir, user_folder, keep).await {
+                Ok(0) => {}
+                Ok(n) => crate::info!("Delivered {} file(s) from the mailbox", n),
+                Err(e) => crate::debug!("DEBUG [MAILBOX]: Check failed: {}", e),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_seal_and_open_item() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let inbox = dir.join("inbox");
+        std::fs::create_dir_all(&inbox).unwrap();
+        let file = dir.join("notes.txt");
+        std::fs::write(&file, b"left for later").unwrap();
+
+        let secret = crate::seal::generate_secret();
+        let sealed_path = dir.join("item.sealed");
+        let (total, hash, sealed_size) =
+            seal_file("alice", &file, &PublicKey::from(&secret), &sealed_path)
+                .await
+                .unwrap();
+        assert_eq!(total, 14);
+        assert_eq!(sealed_size, std::fs::metadata(&sealed_path).unwrap().len());
+        let sealed = std::fs::read(&sealed_path).unwrap();
+        assert!(!sealed
+            .windows(b"left for later".len())
+            .any(|window| window == b"left for later"));
+
+        let config = Config::default();
+        let received = open_item(
+            &mut sealed.as_slice(),
+            &secret,
+            "alice",
+            &inbox,
+            true,
+            Preserve::default(),
+            &config,
+        )
+        .await
+        .unwrap();
+        assert_eq!(received.sender_id, "alice");
+        assert_eq!(received.sha256, hash);
+        assert_eq!(received.path, inbox.join("alice/notes.txt"));
+        assert_eq!(std::fs::read(&received.path).unwrap(), b"left for later");
+
+        // Someone else's key opens nothing and stores nothing
+        let other = crate::seal::generate_secret();
+        let elsewhere = dir.join("elsewhere");
+        std::fs::create_dir_all(&elsewhere).unwrap();
+        assert!(open_item(
+            &mut sealed.as_slice(),
+            &other,
+            "alice",
+            &elsewhere,
+            false,
+            Preserve::default(),
+            &config,
+        )
+        .await
+        .is_err());
+        assert_eq!(std::fs::read_dir(&elsewhere).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_open_item_from_other_sender() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let inbox = dir.join("inbox");
+        std::fs::create_dir_all(&inbox).unwrap();
+        let file = dir.join("invoice.pdf");
+        std::fs::write(&file, b"pay here").unwrap();
+
+        // Mallory uploads an item sealed in alice's name
+        let secret = crate::seal::generate_secret();
+        let sealed_path = dir.join("item.sealed");
+        seal_file("alice", &file, &PublicKey::from(&secret), &sealed_path)
+            .await
+            .unwrap();
+        let sealed = std::fs::read(&sealed_path).unwrap();
+
+        let config = Config::default();
+        let result = open_item(
+            &mut sealed.as_slice(),
+            &secret,
+            "mallory",
+            &inbox,
+            true,
+            Preserve::default(),
+            &config,
+        )
+        .await;
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_dir(&inbox).unwrap().count(), 0);
+    }
+}
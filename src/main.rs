@@ -1,29 +1,63 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
-use std::net::Ipv6Addr;
+use std::io::Write;
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 
 mod cert;
+mod compress;
 mod config;
 mod db;
+mod delta;
+mod history;
+mod lan;
+mod mailbox;
+mod meta;
+mod mux;
 mod net;
+mod outbox;
 mod peer;
+mod probe;
+mod qos;
 mod quic;
+mod receiver;
+mod seal;
 mod server;
+mod share;
+mod sparse;
+mod sync;
+mod tree;
 mod udp;
+mod watch;
 
 static DEBUG: AtomicBool = AtomicBool::new(false);
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
 
 pub fn is_debug() -> bool {
     DEBUG.load(Ordering::Relaxed)
 }
 
+pub fn logs_to_stderr() -> bool {
+    LOG_TO_STDERR.load(Ordering::Relaxed)
+}
+
+/// Print a log line, to stderr once stdout is taken for output.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::logs_to_stderr() {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
 #[macro_export]
 macro_rules! debug {
     ($($arg:tt)*) => {
         if $crate::is_debug() {
-            println!($($arg)*);
+            $crate::info!($($arg)*);
         }
     };
 }
@@ -36,6 +70,18 @@ struct Cli {
     #[arg(long, global = true)]
     debug: bool,
 
+    /// Allow ULA and link-local addresses (e.g. fe80::1%eth0) on networks without global IPv6
+    #[arg(long, global = true)]
+    allow_local: bool,
+
+    /// Only use local addresses on this network interface
+    #[arg(long, global = true)]
+    interface: Option<String>,
+
+    /// Use this exact local IPv6 address
+    #[arg(long, global = true, conflicts_with = "interface")]
+    source_addr: Option<std::net::Ipv6Addr>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -44,10 +90,10 @@ struct Cli {
 enum Commands {
     /// Send a file to a remote peer
     Send {
-        /// File to send
+        /// File or directory to send, or `-` to send what is read from stdin
         file: PathBuf,
 
-        /// Destination (IPv6 address or user ID)
+        /// Destination (IPv6 address, or user ID resolved through the server)
         destination: String,
 
         /// Path to custom certificate file
@@ -57,11 +103,56 @@ enum Commands {
         /// Path to custom private key file
         #[arg(long)]
         key: Option<PathBuf>,
+
+        /// Seconds to wait for the peer in each hole punching attempt
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: Option<u64>,
+
+        /// Number of hole punching attempts before giving up
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        retries: Option<u32>,
+
+        /// Leave the file in the recipient's server mailbox instead of sending it directly
+        #[arg(long)]
+        mailbox: bool,
+
+        /// Only send what differs from the copy the receiver already has
+        #[arg(long, conflicts_with = "mailbox")]
+        delta: bool,
+
+        /// If sending fails, queue the file for `rxx outbox run` to retry
+        #[arg(long, conflicts_with = "mailbox")]
+        queue: bool,
+
+        /// File name for the receiver when sending stdin
+        #[arg(long)]
+        name: Option<String>,
+
+        /// What to do with symbolic links when sending a directory
+        #[arg(long, value_enum, default_value = "preserve")]
+        symlinks: tree::Symlinks,
+
+        /// Compress what is sent, if the receiver supports it (default: from config, or none)
+        #[arg(long, value_enum, conflicts_with = "mailbox")]
+        compress: Option<compress::Compress>,
+
+        /// Send at most this many bytes per second, e.g. 500K or 20M
+        #[arg(long, conflicts_with = "mailbox")]
+        limit: Option<String>,
+
+        /// Yield to other traffic on the link (low-priority congestion control)
+        #[arg(long, conflicts_with = "mailbox")]
+        background: bool,
     },
     /// Receive a file from a remote peer
     Receive {
-        /// Source (IPv6 address or user ID)
-        source: String,
+        /// Source (IPv6 address, or user ID resolved through the server)
+        #[arg(required_unless_present = "any")]
+        source: Option<String>,
+
+        /// Accept files from any sender that asks the server for us
+        #[arg(long, conflicts_with = "source")]
+        any: bool,
 
         /// Output directory for received files
         #[arg(short, long)]
@@ -78,6 +169,168 @@ enum Commands {
         /// Path to custom private key file
         #[arg(long)]
         key: Option<PathBuf>,
+
+        /// Seconds to wait for the peer in each hole punching attempt
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: Option<u64>,
+
+        /// Number of hole punching attempts before giving up
+        #[arg(long, conflicts_with = "wait", value_parser = clap::value_parser!(u32).range(1..))]
+        retries: Option<u32>,
+
+        /// Keep waiting for the sender indefinitely instead of giving up
+        #[arg(long)]
+        wait: bool,
+
+        /// Number of transfers to handle at the same time
+        #[arg(long)]
+        max_concurrent: Option<usize>,
+
+        /// Write the received file to stdout instead of storing it, then exit
+        #[arg(long, conflicts_with_all = ["output", "user_folder"])]
+        stdout: bool,
+
+        /// Give received files the sender's permissions and times
+        #[arg(long)]
+        preserve: bool,
+
+        /// Like --preserve, and also set extended attributes and ACLs
+        #[arg(long)]
+        preserve_xattrs: bool,
+    },
+    /// Export a directory read-only so peers can fetch files with `rxx get`
+    Share {
+        /// Directory to share
+        dir: PathBuf,
+
+        /// Let peers upload and delete files, e.g. for `rxx sync`
+        #[arg(long, requires = "allow")]
+        writable: bool,
+
+        /// User ID that may change a writable share (repeat for several)
+        #[arg(long, requires = "writable")]
+        allow: Vec<String>,
+
+        /// Path to custom certificate file
+        #[arg(long)]
+        cert: Option<PathBuf>,
+
+        /// Path to custom private key file
+        #[arg(long)]
+        key: Option<PathBuf>,
+
+        /// Number of requests to serve at the same time
+        #[arg(long)]
+        max_concurrent: Option<usize>,
+    },
+    /// Fetch a file from a peer running `rxx share`
+    Get {
+        /// Peer and path inside its share, e.g. alice:/docs/report.pdf
+        remote: String,
+
+        /// Output directory for the file
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Seconds to wait for the peer in each hole punching attempt
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: Option<u64>,
+
+        /// Number of hole punching attempts before giving up
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        retries: Option<u32>,
+    },
+    /// List files in a peer's shared directory
+    Ls {
+        /// Peer and directory inside its share, e.g. alice:/
+        remote: String,
+
+        /// Include subdirectories
+        #[arg(short, long)]
+        recursive: bool,
+
+        /// Print the listing as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Seconds to wait for the peer in each hole punching attempt
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: Option<u64>,
+
+        /// Number of hole punching attempts before giving up
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        retries: Option<u32>,
+    },
+    /// Synchronize a directory with a peer's writable share in both directions
+    Sync {
+        /// Local directory
+        dir: PathBuf,
+
+        /// Peer running `rxx share --writable` (user ID or IPv6 address)
+        peer: String,
+
+        /// Only show what would be transferred
+        #[arg(long)]
+        dry_run: bool,
+
+        /// Seconds to wait for the peer in each hole punching attempt
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: Option<u64>,
+
+        /// Number of hole punching attempts before giving up
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        retries: Option<u32>,
+    },
+    /// Send every file that appears in a directory to a peer
+    Watch {
+        /// Directory to watch; sent files are moved to its .sent subdirectory
+        dir: PathBuf,
+
+        /// Destination (IPv6 address, or user ID resolved through the server)
+        destination: String,
+
+        /// Seconds to wait for the peer in each hole punching attempt
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: Option<u64>,
+
+        /// Number of hole punching attempts before giving up
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        retries: Option<u32>,
+    },
+    /// Show past transfers, newest first
+    History {
+        /// Only transfers with this peer
+        #[arg(long)]
+        peer: Option<String>,
+
+        /// Only sent or only received files
+        #[arg(long, value_enum)]
+        direction: Option<history::Direction>,
+
+        /// Only files whose name contains this
+        #[arg(long)]
+        file: Option<String>,
+
+        /// Only failed transfers
+        #[arg(long)]
+        failed: bool,
+
+        /// Only transfers in this period, e.g. 30m, 12h or 7d
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Maximum number of transfers to show
+        #[arg(short = 'n', long, default_value = "50")]
+        limit: usize,
+
+        /// Print the transfers as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Manage sends queued with `rxx send --queue`
+    Outbox {
+        #[command(subcommand)]
+        command: OutboxCommand,
     },
     /// Run the registration server
     Server {
@@ -88,6 +341,18 @@ enum Commands {
         /// Port to listen on
         #[arg(long, default_value = "3457")]
         port: u16,
+
+        /// Directory for files left for offline recipients (enables the mailbox)
+        #[arg(long)]
+        mailbox_dir: Option<PathBuf>,
+
+        /// Mailbox space per recipient in megabytes
+        #[arg(long, default_value = "1024")]
+        mailbox_quota_mb: u64,
+
+        /// Days to keep mailbox items that are never fetched
+        #[arg(long, default_value = "7")]
+        mailbox_ttl_days: u64,
     },
     /// Register user ID with the server
     Register {
@@ -100,6 +365,298 @@ enum Commands {
     },
     /// Show available IPv6 addresses
     Ip,
+    /// Show whether a peer is online and receiving
+    Status {
+        /// User ID to look up
+        id: String,
+    },
+    /// Show which of your contacts are online
+    Who,
+}
+
+#[derive(Subcommand)]
+enum OutboxCommand {
+    /// Keep retrying queued sends until they are delivered
+    Run {
+        /// Try every send that is due once, then exit
+        #[arg(long)]
+        once: bool,
+
+        /// Seconds to wait for the peer in each hole punching attempt
+        #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: Option<u64>,
+
+        /// Number of hole punching attempts before giving up
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        retries: Option<u32>,
+    },
+    /// Show queued sends
+    List,
+    /// Remove a send from the queue
+    Cancel {
+        /// Job number as shown by `rxx outbox list`
+        id: i64,
+    },
+}
+
+/// Migrate the QUIC connection of a client endpoint when our local address
+/// changes, and re-register the new address with the server.
+///
+/// Returns `None` if the address cannot be monitored; the transfer then just
+/// runs without migration. Receivers follow address changes themselves.
+fn follow_address_changes(
+    config: &config::Config,
+    endpoint: quinn::Endpoint,
+    peer_addr: std::net::SocketAddr,
+) -> Option<tokio::task::JoinHandle<()>> {
+    let peer = match peer_addr {
+        std::net::SocketAddr::V6(peer) => Some(peer),
+        _ => None,
+    };
+    let mut changes = match net::spawn_address_monitor(peer) {
+        Ok(changes) => changes,
+        Err(e) => {
+            info!("Address monitoring unavailable: {}", e);
+            return None;
+        }
+    };
+    let config = config.clone();
+
+    Some(tokio::spawn(async move {
+        while changes.changed().await.is_ok() {
+            let addr = *changes.borrow_and_update();
+
+            if let Err(e) = quic::rebind(&endpoint, addr) {
+                info!("Connection migration failed: {}", e);
+                continue;
+            }
+            if let Err(e) = net::refresh_registration(&config, addr.ip()).await {
+                info!("Failed to update server with new address: {}", e);
+            }
+        }
+    }))
+}
+
+/// A QUIC connection opened by `connect_to_peer`.
+struct PeerSession {
+    endpoint: quinn::Endpoint,
+    connection: quinn::Connection,
+    /// Moves the connection along when our address changes
+    migration: Option<tokio::task::JoinHandle<()>>,
+}
+
+impl PeerSession {
+    /// Stop following address changes and close the connection gracefully.
+    async fn close(self) {
+        if let Some(migration) = self.migration {
+            migration.abort();
+        }
+
+        // Close connection gracefully and wait for acknowledgment
+        debug!("DEBUG [MAIN]: Closing connection gracefully...");
+        self.connection.close(0u32.into(), b"transfer complete");
+        // Make sure the close reaches the peer before we exit, so it does
+        // not have to wait for the idle timeout
+        self.endpoint.wait_idle().await;
+        debug!("DEBUG [MAIN]: Connection closed");
+    }
+}
+
+/// Load `~/.rxx.conf`, which `rxx register` creates.
+fn load_config() -> Result<config::Config> {
+    if !config::Config::config_path()?.exists() {
+        anyhow::bail!("Config file not found. Please run: rxx register <id>");
+    }
+    config::Config::load()
+}
+
+/// Punch options from the config, overridden by `--timeout` and `--retries`.
+fn punch_options(
+    config: &config::Config,
+    timeout: Option<u64>,
+    retries: Option<u32>,
+) -> udp::PunchOptions {
+    let mut options = udp::PunchOptions::from_config(config);
+    if let Some(secs) = timeout {
+        options.attempt_timeout = std::time::Duration::from_secs(secs);
+    }
+    if let Some(n) = retries {
+        options.max_retries = Some(n);
+    }
+    options
+}
+
+/// Punch a hole to `destination` and open a QUIC connection over it.
+async fn connect_to_peer(
+    config: &config::Config,
+    destination: &str,
+    punch_options: &udp::PunchOptions,
+) -> Result<PeerSession> {
+    let dest = destination.to_string();
+    let cfg = config.clone();
+    let resolver = move || {
+        let d = dest.clone();
+        let c = cfg.clone();
+        Box::pin(async move { net::resolve_peer(&d, &c).await })
+            as std::pin::Pin<
+                Box<dyn std::future::Future<Output = Result<net::ResolvedPeer>> + Send>,
+            >
+    };
+
+    // Perform UDP hole punching
+    let channel = udp::punch_hole(resolver, punch_options).await?;
+
+    // Create QUIC client config
+    let client_config = quic::create_client_config()?;
+
+    // Connect to QUIC server over the socket used for UDP hole punching
+    let peer_addr = channel.peer_addr;
+    let (endpoint, connection) =
+        quic::connect_client(client_config, channel.socket, peer_addr).await?;
+
+    // Follow address changes so a long transfer survives them
+    let migration = follow_address_changes(config, endpoint.clone(), peer_addr);
+
+    info!(
+        "QUIC connection established to {}",
+        connection.remote_address()
+    );
+    Ok(PeerSession {
+        endpoint,
+        connection,
+        migration,
+    })
+}
+
+/// Deliver queued sends, retrying failed ones with increasing delays.
+///
+/// Jobs for the same destination share one connection. With `once`, every
+/// job that is due is tried once; otherwise this runs until stopped and also
+/// picks up jobs queued meanwhile. Several runners can share the outbox, as
+/// each job is claimed by one of them before it is sent.
+async fn run_outbox(
+    config: &config::Config,
+    punch_options: &udp::PunchOptions,
+    once: bool,
+) -> Result<()> {
+    /// How often to look for newly queued jobs
+    const POLL: std::time::Duration = std::time::Duration::from_secs(30);
+
+    let outbox = outbox::Outbox::open_default()?;
+    loop {
+        let jobs = outbox.claim(outbox::now())?;
+        let ids = jobs.iter().map(|job| job.id).collect::<Vec<_>>();
+        let stopped = tokio::select! {
+            result = deliver_jobs(config, punch_options, &outbox, jobs) => {
+                result?;
+                false
+            }
+            _ = tokio::signal::ctrl_c() => true,
+        };
+        // Hand back whatever we did not get to
+        outbox.release(&ids)?;
+
+        if once || stopped {
+            return Ok(());
+        }
+        let next = outbox
+            .next_due()?
+            .map(|at| std::time::Duration::from_secs((at - outbox::now()).max(1) as u64));
+        tokio::time::sleep(next.map_or(POLL, |next| next.min(POLL))).await;
+    }
+}
+
+/// Try to send claimed outbox jobs once, one connection per destination.
+async fn deliver_jobs(
+    config: &config::Config,
+    punch_options: &udp::PunchOptions,
+    outbox: &outbox::Outbox,
+    jobs: Vec<outbox::Job>,
+) -> Result<()> {
+    let mut due: std::collections::BTreeMap<String, Vec<outbox::Job>> = Default::default();
+    for job in jobs {
+        due.entry(job.destination.clone()).or_default().push(job);
+    }
+
+    for (destination, jobs) in due {
+        let session = match connect_to_peer(config, &destination, punch_options).await {
+            Ok(session) => session,
+            Err(e) => {
+                let error = format!("{:#}", e);
+                for job in &jobs {
+                    let delay = outbox.failed(job.id, &error)?;
+                    info!(
+                        "#{} not sent, next attempt in {}s: {}",
+                        job.id, delay, error
+                    );
+                }
+                continue;
+            }
+        };
+
+        for job in &jobs {
+            if !job.file.is_file() {
+                info!("#{} dropped: {:?} no longer exists", job.id, job.file);
+                outbox.remove(job.id)?;
+                continue;
+            }
+            let send = quic::send_file(
+                &session.connection,
+                &job.file,
+                &config.user_id,
+                &destination,
+                job.delta,
+                config.compress.unwrap_or(compress::Compress::None),
+            );
+            let sent = tokio::select! {
+                result = send => result,
+                _ = quic::stalled(&session.connection, watch::STALL_TIMEOUT) => {
+                    Err(anyhow::anyhow!("{} stopped responding", destination))
+                }
+            };
+            match sent {
+                Ok(()) => {
+                    info!("#{} delivered to {}", job.id, destination);
+                    outbox.remove(job.id)?;
+                }
+                Err(e) => {
+                    let error = format!("{:#}", e);
+                    let delay = outbox.failed(job.id, &error)?;
+                    info!(
+                        "#{} not sent, next attempt in {}s: {}",
+                        job.id, delay, error
+                    );
+                    if session.connection.close_reason().is_some() {
+                        break;
+                    }
+                }
+            }
+        }
+        session.close().await;
+    }
+    Ok(())
+}
+
+/// Keep stdout for machine-readable output and send log lines to stderr.
+fn take_stdout() -> std::io::Stdout {
+    LOG_TO_STDERR.store(true, Ordering::Relaxed);
+    std::io::stdout()
+}
+
+/// Like `take_stdout`, for writing from async code.
+fn take_async_stdout() -> tokio::io::Stdout {
+    LOG_TO_STDERR.store(true, Ordering::Relaxed);
+    tokio::io::stdout()
+}
+
+/// Let peers on the local network find us while we send or receive.
+fn start_lan_responder(config: &config::Config) {
+    if !config.lan_discovery.unwrap_or(true) {
+        return;
+    }
+    if let Err(e) = lan::spawn_responder(&config.user_id) {
+        info!("LAN discovery unavailable: {}", e);
+    }
 }
 
 #[tokio::main]
@@ -108,182 +665,496 @@ async fn main() -> Result<()> {
 
     DEBUG.store(cli.debug, Ordering::Relaxed);
 
+    let file_config = config::Config::load().ok();
+    net::configure(net::AddrSelection {
+        allow_local: cli.allow_local
+            || file_config
+                .as_ref()
+                .and_then(|c| c.allow_local)
+                .unwrap_or(false),
+        interface: cli
+            .interface
+            .clone()
+            .or_else(|| file_config.as_ref().and_then(|c| c.interface.clone())),
+        source_addr: cli.source_addr,
+        preferred: file_config
+            .as_ref()
+            .and_then(|c| c.preferred_addresses.clone())
+            .unwrap_or_default(),
+    });
+
     match cli.command {
         Commands::Send {
             file,
             destination,
             cert,
             key,
+            timeout,
+            retries,
+            mailbox,
+            delta,
+            queue,
+            name,
+            symlinks,
+            compress,
+            limit,
+            background,
         } => {
-            println!(
+            let stdin = file.as_os_str() == "-";
+            let dir = file.is_dir();
+            if dir && (mailbox || delta || queue) {
+                anyhow::bail!("--mailbox, --delta and --queue only work with single files");
+            }
+            if stdin {
+                if name.is_none() {
+                    anyhow::bail!("Sending stdin needs --name for the receiver");
+                }
+                if mailbox || delta || queue {
+                    anyhow::bail!("--mailbox, --delta and --queue need a file, not stdin");
+                }
+            } else if name.is_some() {
+                anyhow::bail!("--name is only used when sending stdin (`rxx send - ...`)");
+            }
+
+            info!(
                 "Send mode: file={:?}, destination={}, cert={:?}, key={:?}",
                 file, destination, cert, key
             );
 
-            let config = match config::Config::load() {
-                Ok(cfg) => cfg,
-                Err(_) => {
-                    anyhow::bail!("Config file not found. Please run: rxx register <id>");
-                }
-            };
+            let config = load_config()?;
 
-            let dest = destination.clone();
-            let cfg = config.clone();
-            let resolver = move || {
-                let d = dest.clone();
-                let c = cfg.clone();
-                Box::pin(async move { net::resolve_peer(&d, &c).await })
-                    as std::pin::Pin<Box<dyn std::future::Future<Output = Result<Ipv6Addr>> + Send>>
-            };
+            let compress = compress
+                .or(config.compress)
+                .unwrap_or(compress::Compress::None);
 
-            // Perform UDP hole punching
-            let peer_addr = udp::punch_hole(resolver, false).await?;
+            if mailbox {
+                if !matches!(peer::parse_peer(&destination), peer::PeerAddress::Id(_)) {
+                    anyhow::bail!("--mailbox needs a user ID as destination");
+                }
+                return mailbox::send(&config, &file, &destination).await;
+            }
 
-            // Create QUIC client config
-            let client_config = quic::create_client_config()?;
+            start_lan_responder(&config);
+            qos::configure(
+                limit.as_deref().or(config.limit.as_deref()),
+                background || config.background.unwrap_or(false),
+            )?;
+            qos::reload_on_hangup(limit.is_some());
 
-            // Connect to QUIC server using the same port and address as UDP hole punching
-            let local_ipv6 = net::get_local_ipv6()?;
-            let bind_addr = format!("[{}]:{}", local_ipv6, udp::CLIENT_PORT).parse()?;
-            let connection = quic::connect_client(client_config, bind_addr, peer_addr).await?;
+            let punch_options = punch_options(&config, timeout, retries);
 
-            println!(
-                "QUIC connection established to {}",
-                connection.remote_address()
-            );
+            let by_id = matches!(peer::parse_peer(&destination), peer::PeerAddress::Id(_));
+            let sent = async {
+                let session = connect_to_peer(&config, &destination, &punch_options)
+                    .await
+                    .map_err(|e| {
+                        if by_id && !queue {
+                            e.context(
+                                "Peer unreachable; use --mailbox to leave the file in their mailbox",
+                            )
+                        } else {
+                            e
+                        }
+                    })?;
 
-            // Send file
-            quic::send_file(&connection, &file, &config.user_id).await?;
+                // Send file
+                let result = match &name {
+                    Some(name) => {
+                        quic::send_stdin(
+                            &session.connection,
+                            name,
+                            &config.user_id,
+                            &destination,
+                            compress,
+                        )
+                        .await
+                    }
+                    None if dir => {
+                        quic::send_dir(
+                            &session.connection,
+                            &file,
+                            &config.user_id,
+                            &destination,
+                            symlinks,
+                            compress,
+                        )
+                        .await
+                    }
+                    None => {
+                        quic::send_file(
+                            &session.connection,
+                            &file,
+                            &config.user_id,
+                            &destination,
+                            delta,
+                            compress,
+                        )
+                        .await
+                    }
+                };
 
-            // Close connection gracefully and wait for acknowledgment
-            debug!("DEBUG [MAIN]: Closing connection gracefully...");
-            connection.close(0u32.into(), b"transfer complete");
-            connection.closed().await;
-            debug!("DEBUG [MAIN]: Connection closed");
+                session.close().await;
+                result
+            }
+            .await;
 
-            println!("File transfer completed successfully");
+            match sent {
+                Ok(()) => info!("File transfer completed successfully"),
+                Err(e) if queue => {
+                    eprintln!("Send failed: {:#}", e);
+                    let outbox = outbox::Outbox::open_default()?;
+                    let id = outbox.add(&file, &destination, delta)?;
+                    let delay = outbox.failed(id, &format!("{:#}", e))?;
+                    info!(
+                        "Queued as #{}; rxx outbox run tries again in {}s and keeps trying until {} is reachable",
+                        id, delay, destination
+                    );
+                }
+                Err(e) => return Err(e),
+            }
         }
         Commands::Receive {
             source,
+            any,
             output,
             user_folder,
             cert,
             key,
+            timeout,
+            retries,
+            wait,
+            max_concurrent,
+            stdout,
+            preserve,
+            preserve_xattrs,
         } => {
-            println!(
+            // Only the file goes to stdout
+            let stdout = match stdout {
+                true => Some(receiver::StdoutSink::new(Box::new(take_async_stdout()))),
+                false => None,
+            };
+
+            info!(
                 "Receive mode: source={}, output={:?}, user_folder={}, cert={:?}, key={:?}",
-                source, output, user_folder, cert, key
+                source.as_deref().unwrap_or("any"),
+                output,
+                user_folder,
+                cert,
+                key
             );
 
-            let config = match config::Config::load() {
-                Ok(cfg) => cfg,
-                Err(_) => {
-                    anyhow::bail!("Config file not found. Please run: rxx register <id>");
-                }
-            };
+            let config = load_config()?;
+            let from_config = meta::Preserve::from_config(&config);
+            let preserve = meta::Preserve::new(
+                preserve || from_config.metadata,
+                preserve_xattrs || from_config.xattrs,
+            );
 
             let output_base = output.unwrap_or_else(|| PathBuf::from("."));
 
-            loop {
-                println!("\nWaiting for next connection...");
-
-                let src = source.clone();
-                let cfg = config.clone();
-                let resolver = move || {
-                    let s = src.clone();
-                    let c = cfg.clone();
-                    Box::pin(async move { net::resolve_peer(&s, &c).await })
-                        as std::pin::Pin<
-                            Box<dyn std::future::Future<Output = Result<Ipv6Addr>> + Send>,
-                        >
-                };
+            start_lan_responder(&config);
 
-                // Perform UDP hole punching
-                let peer_addr = match udp::punch_hole(resolver, true).await {
-                    Ok(addr) => addr,
-                    Err(e) => {
-                        eprintln!("Error during UDP hole punching: {}", e);
-                        continue;
-                    }
-                };
+            let mut punch_options = punch_options(&config, timeout, retries);
+            if wait {
+                punch_options.max_retries = None;
+            }
 
-                // Generate or load certificate
-                let cert_key = if let (Some(cert_path), Some(key_path)) = (&cert, &key) {
-                    match cert::load_cert_from_file(cert_path, key_path) {
-                        Ok(ck) => ck,
-                        Err(e) => {
-                            eprintln!("Error loading certificate: {}", e);
-                            continue;
+            let cert_key = cert::load_or_generate(cert.as_deref(), key.as_deref())?;
+            let server_config = quic::create_server_config(&cert_key)?;
+            let options = receiver::ReceiveOptions {
+                output_base,
+                user_folder,
+                punch: punch_options,
+                max_concurrent: max_concurrent
+                    .or(config.max_concurrent)
+                    .unwrap_or(receiver::DEFAULT_MAX_CONCURRENT),
+                share: None,
+                stdout,
+                preserve,
+            };
+
+            receiver::run(config, server_config, options, source.filter(|_| !any)).await?;
+        }
+        Commands::Share {
+            dir,
+            writable,
+            allow,
+            cert,
+            key,
+            max_concurrent,
+        } => {
+            let config = load_config()?;
+
+            let share = std::sync::Arc::new(share::Share::new(&dir, &allow)?);
+            let access = match writable {
+                true => format!("writable by {}", allow.join(", ")),
+                false => "read-only".to_string(),
+            };
+            info!(
+                "Sharing {:?} {} as '{}'",
+                share.root(),
+                access,
+                config.user_id
+            );
+
+            start_lan_responder(&config);
+            qos::configure(config.limit.as_deref(), config.background.unwrap_or(false))?;
+            qos::reload_on_hangup(false);
+
+            let cert_key = cert::load_or_generate(cert.as_deref(), key.as_deref())?;
+            let server_config = quic::create_server_config(&cert_key)?;
+            let options = receiver::ReceiveOptions {
+                output_base: share.root().to_path_buf(),
+                user_folder: false,
+                punch: udp::PunchOptions::from_config(&config),
+                max_concurrent: max_concurrent
+                    .or(config.max_concurrent)
+                    .unwrap_or(receiver::DEFAULT_MAX_CONCURRENT),
+                share: Some(share),
+                stdout: None,
+                preserve: meta::Preserve::from_config(&config),
+            };
+
+            receiver::run(config, server_config, options, None).await?;
+        }
+        Commands::Get {
+            remote,
+            output,
+            timeout,
+            retries,
+        } => {
+            let (peer, path) = share::parse_remote(&remote)?;
+
+            let config = load_config()?;
+
+            start_lan_responder(&config);
+
+            let punch_options = punch_options(&config, timeout, retries);
+
+            let session = connect_to_peer(&config, &peer, &punch_options).await?;
+
+            let output_dir = output.unwrap_or_else(|| PathBuf::from("."));
+            share::get(&session.connection, &path, &output_dir, &peer, &config).await?;
+
+            session.close().await;
+
+            info!("Fetched {} from {}", path, peer);
+        }
+        Commands::Ls {
+            remote,
+            recursive,
+            json,
+            timeout,
+            retries,
+        } => {
+            let (peer, path) = share::parse_remote(&remote)?;
+
+            // Only the listing goes to stdout
+            let mut out = take_stdout();
+
+            let config = load_config()?;
+
+            start_lan_responder(&config);
+
+            let punch_options = punch_options(&config, timeout, retries);
+
+            let session = connect_to_peer(&config, &peer, &punch_options).await?;
+            let entries = share::list(&session.connection, &path, recursive).await?;
+            session.close().await;
+
+            if json {
+                writeln!(out, "{}", serde_json::to_string_pretty(&entries)?)?;
+            } else {
+                let now = std::time::SystemTime::now();
+                for entry in &entries {
+                    writeln!(out, "{}", entry.describe(now))?;
+                }
+            }
+        }
+        Commands::Sync {
+            dir,
+            peer,
+            dry_run,
+            timeout,
+            retries,
+        } => {
+            let config = load_config()?;
+
+            start_lan_responder(&config);
+            qos::configure(config.limit.as_deref(), config.background.unwrap_or(false))?;
+            qos::reload_on_hangup(false);
+
+            let punch_options = punch_options(&config, timeout, retries);
+
+            let session = connect_to_peer(&config, &peer, &punch_options).await?;
+            let result = sync::run(&session.connection, &dir, &peer, dry_run, &config).await;
+            session.close().await;
+            result?;
+        }
+        Commands::Watch {
+            dir,
+            destination,
+            timeout,
+            retries,
+        } => {
+            let config = load_config()?;
+
+            start_lan_responder(&config);
+            qos::configure(config.limit.as_deref(), config.background.unwrap_or(false))?;
+            qos::reload_on_hangup(false);
+
+            let punch_options = punch_options(&config, timeout, retries);
+
+            let mut watcher = watch::Watcher::new(&dir)?;
+            println!("Watching {:?}, sending new files to {}", dir, destination);
+
+            // One connection for all files, opened again when it drops
+            let mut session: Option<PeerSession> = None;
+            let mut connect_failures = 0;
+            while let Some(file) = watcher.next().await {
+                if let Some(lost) = session.take_if(|s| s.connection.close_reason().is_some()) {
+                    println!("Connection to {} lost", destination);
+                    lost.close().await;
+                }
+                let connection = match &session {
+                    Some(session) => &session.connection,
+                    None => match connect_to_peer(&config, &destination, &punch_options).await {
+                        Ok(connected) => {
+                            connect_failures = 0;
+                            &session.insert(connected).connection
                         }
-                    }
-                } else {
-                    match cert::generate_self_signed_cert() {
-                        Ok(ck) => ck,
                         Err(e) => {
-                            eprintln!("Error generating certificate: {}", e);
+                            // Nothing can be sent until the peer is back
+                            let delay = watch::retry_delay(connect_failures);
+                            connect_failures += 1;
+                            eprintln!(
+                                "Cannot reach {}: {:#}; trying again in {}s",
+                                destination,
+                                e,
+                                delay.as_secs()
+                            );
+                            tokio::time::sleep(delay).await;
+                            watcher.retry_now(file);
                             continue;
                         }
-                    }
+                    },
                 };
 
-                // Create QUIC server config
-                let server_config = match quic::create_server_config(&cert_key) {
-                    Ok(sc) => sc,
-                    Err(e) => {
-                        eprintln!("Error creating server config: {}", e);
-                        continue;
+                let sent = tokio::select! {
+                    result = quic::send_file(
+                        connection,
+                        &file,
+                        &config.user_id,
+                        &destination,
+                        false,
+                        config.compress.unwrap_or(compress::Compress::None),
+                    ) => result,
+                    _ = quic::stalled(connection, watch::STALL_TIMEOUT) => {
+                        Err(anyhow::anyhow!("{} stopped responding", destination))
                     }
                 };
-
-                // Start QUIC server on the same port and address as UDP hole punching
-                let local_ipv6 = match net::get_local_ipv6() {
-                    Ok(ip) => ip,
-                    Err(e) => {
-                        eprintln!("Error getting local IPv6: {}", e);
-                        continue;
+                match sent {
+                    Ok(()) => {
+                        if let Err(e) = watcher.mark_sent(&file) {
+                            eprintln!("{:#}", e);
+                        }
                     }
-                };
-                let bind_addr = format!("[{}]:{}", local_ipv6, udp::SERVER_PORT).parse()?;
-                let endpoint = match quic::start_server(server_config, bind_addr).await {
-                    Ok(ep) => ep,
                     Err(e) => {
-                        eprintln!("Error starting QUIC server: {}", e);
-                        continue;
+                        let delay = watcher.retry(file.clone());
+                        eprintln!(
+                            "Failed to send {:?}: {:#}; trying again in {}s",
+                            file,
+                            e,
+                            delay.as_secs()
+                        );
+                        if let Some(session) = session.take() {
+                            session.close().await;
+                        }
                     }
-                };
+                }
+            }
+        }
+        Commands::History {
+            peer,
+            direction,
+            file,
+            failed,
+            since,
+            limit,
+            json,
+        } => {
+            let since = match since {
+                Some(period) => {
+                    Some(outbox::now() - history::parse_period(&period)?.as_secs() as i64)
+                }
+                None => None,
+            };
+            let records = history::History::open_default()?.query(&history::Filter {
+                peer,
+                direction,
+                file,
+                failed_only: failed,
+                since,
+                limit,
+            })?;
 
-                // Accept incoming connection
-                println!("Waiting for QUIC connection from {}...", peer_addr);
-                let incoming = match endpoint.accept().await {
-                    Some(inc) => inc,
-                    None => {
-                        eprintln!("No incoming connection");
-                        continue;
-                    }
-                };
-                let connection = match incoming.await {
-                    Ok(conn) => conn,
-                    Err(e) => {
-                        eprintln!("Error accepting connection: {}", e);
-                        continue;
-                    }
-                };
+            if json {
+                println!("{}", serde_json::to_string_pretty(&records)?);
+            } else if records.is_empty() {
+                println!("No transfers recorded");
+            } else {
+                for record in &records {
+                    println!("{}", record.describe());
+                }
+            }
+        }
+        Commands::Outbox { command } => match command {
+            OutboxCommand::Run {
+                once,
+                timeout,
+                retries,
+            } => {
+                let config = load_config()?;
 
-                println!(
-                    "QUIC connection accepted from {}",
-                    connection.remote_address()
-                );
+                start_lan_responder(&config);
+                qos::configure(config.limit.as_deref(), config.background.unwrap_or(false))?;
+                qos::reload_on_hangup(false);
+
+                let punch_options = punch_options(&config, timeout, retries);
 
-                // Receive file
-                match quic::receive_file(&connection, &output_base, user_folder, &config).await {
-                    Ok(_) => println!("File transfer completed successfully"),
-                    Err(e) => eprintln!("Error during file transfer: {}", e),
+                run_outbox(&config, &punch_options, once).await?;
+            }
+            OutboxCommand::List => {
+                let jobs = outbox::Outbox::open_default()?.list()?;
+                if jobs.is_empty() {
+                    println!("Outbox is empty");
+                }
+                let now = outbox::now();
+                for job in jobs {
+                    println!("{}", job.describe(now));
                 }
             }
-        }
-        Commands::Server { db, port } => {
+            OutboxCommand::Cancel { id } => {
+                if !outbox::Outbox::open_default()?.remove(id)? {
+                    anyhow::bail!("No queued send #{}", id);
+                }
+                println!("Cancelled #{}", id);
+            }
+        },
+        Commands::Server {
+            db,
+            port,
+            mailbox_dir,
+            mailbox_quota_mb,
+            mailbox_ttl_days,
+        } => {
             println!("Starting server: db={}, port={}", db, port);
-            server::run_server(&db, port).await?;
+            let mailbox = mailbox_dir.map(|dir| server::MailboxSettings {
+                dir,
+                quota: mailbox_quota_mb * 1024 * 1024,
+                ttl: std::time::Duration::from_secs(mailbox_ttl_days * 86400),
+            });
+            server::run_server(&db, port, mailbox).await?;
         }
         Commands::Register { id, server } => {
             // Validate ID format
@@ -330,7 +1201,7 @@ async fn main() -> Result<()> {
                     user_id: id.clone(),
                     server_url: server.clone(),
                     nonce: Some(nonce),
-                    hooks: None,
+                    ..Default::default()
                 };
                 config.save()?;
                 println!("Successfully registered ID '{}'", id);
@@ -342,10 +1213,15 @@ async fn main() -> Result<()> {
         }
         Commands::Ip => {
             let addrs = net::get_all_ipv6()?;
+            let selected = net::get_local_ipv6().ok();
 
             println!("Available IPv6 addresses:\n");
-            for (i, info) in addrs.iter().enumerate() {
-                let marker = if i == 0 { " [RECOMMENDED]" } else { "" };
+            for info in &addrs {
+                let marker = if Some(info.addr) == selected {
+                    " [RECOMMENDED]"
+                } else {
+                    ""
+                };
                 let temp_flag = if info.is_temporary {
                     " (temporary)"
                 } else {
@@ -357,7 +1233,11 @@ async fn main() -> Result<()> {
                     net::Ipv6Scope::LinkLocal => "link-local",
                 };
 
-                println!("  {}{}", info.addr, marker);
+                if info.scope == net::Ipv6Scope::LinkLocal {
+                    println!("  {}%{}{}", info.addr, info.interface, marker);
+                } else {
+                    println!("  {}{}", info.addr, marker);
+                }
                 println!("    Interface: {}", info.interface);
                 println!("    Type: {}{}", scope, temp_flag);
                 println!();
@@ -367,6 +1247,27 @@ async fn main() -> Result<()> {
                 "The recommended address will be used by default for send/receive operations."
             );
         }
+        Commands::Status { id } => {
+            let config = load_config()?;
+            for status in net::query_status(&config, &[id]).await? {
+                println!("{}: {}", status.id, status.describe());
+            }
+        }
+        Commands::Who => {
+            let config = load_config()?;
+            let contacts = config.contacts.clone().unwrap_or_default();
+            if contacts.is_empty() {
+                anyhow::bail!(
+                    "No contacts configured. Add them to ~/.rxx.conf, e.g. contacts = [\"bob\"]"
+                );
+            }
+
+            let statuses = net::query_status(&config, &contacts).await?;
+            let width = statuses.iter().map(|s| s.id.len()).max().unwrap_or(0);
+            for status in &statuses {
+                println!("  {:<width$}  {}", status.id, status.describe());
+            }
+        }
     }
 
     Ok(())
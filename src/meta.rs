@@ -0,0 +1,262 @@
+use anyhow::{bail, Context, Result};
+use filetime::FileTime;
+use std::path::Path;
+
+/// Which of the sender's metadata a receiver applies to what it stores,
+/// from the config and `rxx receive --preserve` / `--preserve-xattrs`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Preserve {
+    pub metadata: bool,
+    /// Extended attributes too; implies `metadata`
+    pub xattrs: bool,
+}
+
+impl Preserve {
+    pub fn new(metadata: bool, xattrs: bool) -> Self {
+        Preserve {
+            metadata: metadata || xattrs,
+            xattrs,
+        }
+    }
+
+    /// What the config asks for, without any command line flags
+    pub fn from_config(config: &crate::config::Config) -> Self {
+        Self::new(
+            config.preserve.unwrap_or(false),
+            config.preserve_xattrs.unwrap_or(false),
+        )
+    }
+}
+
+/// Extended attributes sent along with a file, at most this much in total;
+/// more are left out
+const MAX_XATTRS: usize = 64 * 1024;
+/// Largest encoded metadata accepted from a peer
+pub const MAX_ENCODED: u32 = 2 * MAX_XATTRS as u32;
+
+/// File metadata sent with the content, applied by receivers run with
+/// `--preserve`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// Unix permission bits, if the sender has them
+    pub mode: Option<u32>,
+    pub mtime: FileTime,
+    pub atime: FileTime,
+    /// Extended attributes, including POSIX ACLs
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Attributes that may travel between machines. Others (`security.*`,
+/// `trusted.*`) can grant privileges and are neither sent nor applied.
+fn portable(name: &str) -> bool {
+    name.starts_with("user.")
+        || name == "system.posix_acl_access"
+        || name == "system.posix_acl_default"
+}
+
+impl Metadata {
+    /// Collect what is sent along with `path`.
+    pub fn read(path: &Path) -> Result<Self> {
+        let metadata = std::fs::metadata(path)
+            .with_context(|| format!("Failed to get file metadata: {:?}", path))?;
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        Ok(Metadata {
+            mode,
+            mtime: FileTime::from_last_modification_time(&metadata),
+            atime: FileTime::from_last_access_time(&metadata),
+            xattrs: read_xattrs(path),
+        })
+    }
+
+    /// Set the metadata on the received file at `path`. Setuid, setgid and
+    /// sticky bits are dropped. Extended attributes are only set with
+    /// `xattrs`; one that cannot be set is reported and skipped.
+    pub fn apply(&self, path: &Path, xattrs: bool) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o777))
+                .with_context(|| format!("Failed to set permissions of {:?}", path))?;
+        }
+
+        #[cfg(unix)]
+        if xattrs {
+            for (name, value) in self.xattrs.iter().filter(|(name, _)| portable(name)) {
+                if let Err(e) = xattr::set(path, name, value) {
+                    crate::info!("Could not set {} on {:?}: {}", name, path, e);
+                }
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = xattrs;
+
+        // Last, setting attributes may touch the times
+        filetime::set_file_times(path, self.atime, self.mtime)
+            .with_context(|| format!("Failed to set times of {:?}", path))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        match self.mode {
+            Some(mode) => {
+                data.push(1);
+                data.extend_from_slice(&mode.to_be_bytes());
+            }
+            None => data.push(0),
+        }
+        for time in [self.mtime, self.atime] {
+            data.extend_from_slice(&time.unix_seconds().to_be_bytes());
+            data.extend_from_slice(&time.nanoseconds().to_be_bytes());
+        }
+        data.extend_from_slice(&(self.xattrs.len() as u32).to_be_bytes());
+        for (name, value) in &self.xattrs {
+            data.extend_from_slice(&(name.len() as u32).to_be_bytes());
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            data.extend_from_slice(value);
+        }
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self> {
+        let mut reader = Reader(data);
+        let mode = match reader.take(1)?[0] {
+            0 => None,
+            _ => Some(reader.u32()?),
+        };
+        let mut times = [FileTime::zero(); 2];
+        for time in &mut times {
+            let seconds = i64::from_be_bytes(reader.take(8)?.try_into()?);
+            let nanos = reader.u32()?;
+            if nanos >= 1_000_000_000 {
+                bail!("Invalid file time");
+            }
+            *time = FileTime::from_unix_time(seconds, nanos);
+        }
+        let count = reader.u32()?;
+        let mut xattrs = Vec::new();
+        for _ in 0..count {
+            let len = reader.u32()? as usize;
+            let name = String::from_utf8(reader.take(len)?.to_vec())
+                .context("Invalid UTF-8 in attribute name")?;
+            let len = reader.u32()? as usize;
+            xattrs.push((name, reader.take(len)?.to_vec()));
+        }
+        if !reader.0.is_empty() {
+            bail!("Trailing bytes after file metadata");
+        }
+        Ok(Metadata {
+            mode,
+            mtime: times[0],
+            atime: times[1],
+            xattrs,
+        })
+    }
+}
+
+#[cfg(unix)]
+fn read_xattrs(path: &Path) -> Vec<(String, Vec<u8>)> {
+    let Ok(names) = xattr::list(path) else {
+        return Vec::new();
+    };
+    let mut xattrs = Vec::new();
+    let mut total = 0;
+    for name in names {
+        let Some(name) = name.to_str().filter(|name| portable(name)) else {
+            continue;
+        };
+        let Ok(Some(value)) = xattr::get(path, name) else {
+            continue;
+        };
+        total += name.len() + value.len();
+        if total > MAX_XATTRS {
+            crate::info!(
+                "Too many extended attributes on {:?}, sending only some",
+                path
+            );
+            break;
+        }
+        xattrs.push((name.to_string(), value));
+    }
+    xattrs
+}
+
+#[cfg(not(unix))]
+fn read_xattrs(_path: &Path) -> Vec<(String, Vec<u8>)> {
+    Vec::new()
+}
+
+/// Reads the big-endian fields of encoded metadata.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if n > self.0.len() {
+            bail!("Truncated file metadata");
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let metadata = Metadata {
+            mode: Some(0o755),
+            mtime: FileTime::from_unix_time(1_700_000_000, 123),
+            atime: FileTime::from_unix_time(-5, 999_999_999),
+            xattrs: vec![("user.origin".to_string(), b"build".to_vec())],
+        };
+        let encoded = metadata.encode();
+        assert_eq!(Metadata::decode(&encoded).unwrap(), metadata);
+        assert!(Metadata::decode(&encoded[..encoded.len() - 1]).is_err());
+
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert!(Metadata::decode(&trailing).is_err());
+    }
+
+    #[test]
+    fn test_portable() {
+        assert!(portable("user.comment"));
+        assert!(portable("system.posix_acl_access"));
+        assert!(!portable("security.capability"));
+        assert!(!portable("trusted.overlay.opaque"));
+    }
+
+    #[test]
+    fn test_apply() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("script");
+        std::fs::write(&path, b"#!/bin/sh\n").unwrap();
+        let metadata = Metadata {
+            mode: Some(0o4751),
+            mtime: FileTime::from_unix_time(1_600_000_000, 0),
+            atime: FileTime::from_unix_time(1_600_000_100, 0),
+            xattrs: Vec::new(),
+        };
+        metadata.apply(&path, true).unwrap();
+
+        let read = Metadata::read(&path).unwrap();
+        assert_eq!(read.mtime, metadata.mtime);
+        assert_eq!(read.atime, metadata.atime);
+        #[cfg(unix)]
+        assert_eq!(read.mode, Some(0o751));
+    }
+}
@@ -0,0 +1,159 @@
+use quinn::udp::{RecvMeta, Transmit};
+use quinn::{AsyncUdpSocket, UdpPoller};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, IoSliceMut};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+use tokio::sync::mpsc;
+
+use crate::probe::SESSION_ID_LEN;
+
+type ProbeSender = mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>;
+pub type ProbeReceiver = mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>;
+
+/// A UDP socket shared between a long-lived QUIC endpoint and hole punching.
+///
+/// A receiver serving several peers cannot hand its socket over to quinn
+/// after punching like a one-shot transfer does, because it has to keep
+/// punching towards new peers on the same port. Probes for registered
+/// sessions are routed to their punching task; everything else goes to quinn.
+pub struct MuxSocket {
+    inner: Arc<dyn AsyncUdpSocket>,
+    sessions: Mutex<HashMap<[u8; SESSION_ID_LEN], ProbeSender>>,
+}
+
+impl fmt::Debug for MuxSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MuxSocket")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl MuxSocket {
+    pub fn new(socket: std::net::UdpSocket) -> io::Result<Arc<Self>> {
+        let runtime = quinn::default_runtime()
+            .ok_or_else(|| io::Error::other("No async runtime found for QUIC"))?;
+        Ok(Arc::new(MuxSocket {
+            inner: runtime.wrap_udp_socket(socket)?,
+            sessions: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    /// The IPv6 address the socket is bound to, which is what peers must be
+    /// told to reach us at.
+    pub fn local_ipv6(&self) -> io::Result<Ipv6Addr> {
+        match self.inner.local_addr()?.ip() {
+            IpAddr::V6(ip) => Ok(ip),
+            IpAddr::V4(ip) => Ok(ip.to_ipv6_mapped()),
+        }
+    }
+
+    /// Start receiving the probes of a session; they stop reaching quinn
+    /// until the returned receiver is dropped.
+    pub fn register(&self, session_id: [u8; SESSION_ID_LEN]) -> ProbeReceiver {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.sessions.lock().unwrap().insert(session_id, tx);
+        rx
+    }
+
+    /// Send a raw datagram next to quinn's traffic.
+    pub async fn send_to(&self, data: &[u8], destination: SocketAddr) -> io::Result<()> {
+        let transmit = Transmit {
+            destination,
+            ecn: None,
+            contents: data,
+            segment_size: None,
+            src_ip: None,
+        };
+        let mut poller = self.inner.clone().create_io_poller();
+        loop {
+            match self.inner.try_send(&transmit) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    std::future::poll_fn(|cx| poller.as_mut().poll_writable(cx)).await?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Hand a datagram to its punching task if it is a probe for a live
+    /// session. Returns whether it was consumed.
+    fn dispatch_probe(&self, data: &[u8], from: SocketAddr) -> bool {
+        let Some(session_id) = crate::probe::session_id_of(data) else {
+            return false;
+        };
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(&session_id) {
+            Some(tx) => {
+                if tx.send((data.to_vec(), from)).is_err() {
+                    sessions.remove(&session_id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl AsyncUdpSocket for MuxSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        self.inner.clone().create_io_poller()
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        self.inner.try_send(transmit)
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let count = ready!(self.inner.poll_recv(cx, bufs, meta))?;
+
+            // Drop probes from the batch, compacting the QUIC datagrams to
+            // the front. Coalesced (GRO) buffers are always QUIC.
+            let mut kept = 0;
+            for i in 0..count {
+                let entry = meta[i];
+                if entry.len == entry.stride
+                    && self.dispatch_probe(&bufs[i][..entry.len], entry.addr)
+                {
+                    continue;
+                }
+                if kept != i {
+                    let (head, tail) = bufs.split_at_mut(i);
+                    head[kept][..entry.len].copy_from_slice(&tail[0][..entry.len]);
+                    meta[kept] = entry;
+                }
+                kept += 1;
+            }
+
+            if kept > 0 {
+                return Poll::Ready(Ok(kept));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        self.inner.max_transmit_segments()
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.inner.max_receive_segments()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
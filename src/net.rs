@@ -1,14 +1,84 @@
 use anyhow::{bail, Context, Result};
-use std::net::{IpAddr, Ipv6Addr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddrV6};
+use std::sync::OnceLock;
+use tokio::sync::watch;
+use tokio::time::Duration;
+
+use crate::probe::ProbeSession;
 
 #[derive(Debug, Clone)]
 pub struct Ipv6Info {
     pub addr: Ipv6Addr,
     pub interface: String,
+    /// Interface index, used as the scope ID of link-local addresses
+    pub index: u32,
     pub is_temporary: bool,
     pub scope: Ipv6Scope,
 }
 
+/// Process-wide rules for picking local addresses, set once from the command
+/// line and config before any networking starts.
+#[derive(Debug, Clone, Default)]
+pub struct AddrSelection {
+    /// Allow ULA and link-local addresses when no global address exists
+    pub allow_local: bool,
+    /// Only consider addresses on this interface
+    pub interface: Option<String>,
+    /// Always use this exact local address
+    pub source_addr: Option<Ipv6Addr>,
+    /// Interface names, addresses or prefixes (`2001:db8::/32`), most
+    /// preferred first; addresses matching none of them come last
+    pub preferred: Vec<String>,
+}
+
+impl AddrSelection {
+    /// Position of the first preference entry matching `info`, so lower is
+    /// better and unmatched addresses sort after all matched ones.
+    fn preference_rank(&self, info: &Ipv6Info) -> usize {
+        self.preferred
+            .iter()
+            .position(|entry| matches_preference(entry, info))
+            .unwrap_or(self.preferred.len())
+    }
+}
+
+fn matches_preference(entry: &str, info: &Ipv6Info) -> bool {
+    if let Some((prefix, len)) = entry.split_once('/') {
+        let (Ok(prefix), Ok(len)) = (prefix.parse::<Ipv6Addr>(), len.parse::<u32>()) else {
+            return false;
+        };
+        let mask = u128::MAX.checked_shl(128 - len.min(128)).unwrap_or(0);
+        return u128::from(info.addr) & mask == u128::from(prefix) & mask;
+    }
+    match entry.parse::<Ipv6Addr>() {
+        Ok(addr) => addr == info.addr,
+        Err(_) => entry == info.interface,
+    }
+}
+
+/// Ask the kernel which source address it would use to reach `peer`.
+///
+/// Connecting a UDP socket sends nothing but performs the route lookup, so
+/// the socket's local address is the routing table's answer.
+pub fn route_source(peer: &SocketAddrV6) -> Option<Ipv6Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect(peer).ok()?;
+    match socket.local_addr().ok()? {
+        std::net::SocketAddr::V6(local) => Some(*local.ip()),
+        std::net::SocketAddr::V4(_) => None,
+    }
+}
+
+static SELECTION: OnceLock<AddrSelection> = OnceLock::new();
+
+pub fn configure(selection: AddrSelection) {
+    let _ = SELECTION.set(selection);
+}
+
+fn selection() -> AddrSelection {
+    SELECTION.get().cloned().unwrap_or_default()
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Ipv6Scope {
     LinkLocal,
@@ -44,7 +114,7 @@ fn classify_ipv6(addr: &Ipv6Addr) -> Ipv6Scope {
     let segments = addr.segments();
 
     // Link-local: fe80::/10
-    if segments[0] & 0xffc0 == 0xfe80 {
+    if is_link_local(addr) {
         return Ipv6Scope::LinkLocal;
     }
 
@@ -96,6 +166,7 @@ pub fn get_all_ipv6() -> Result<Vec<Ipv6Info>> {
             addrs.push(Ipv6Info {
                 addr,
                 interface: iface.name.clone(),
+                index: iface.index.unwrap_or(0),
                 is_temporary,
                 scope,
             });
@@ -113,18 +184,75 @@ pub fn get_all_ipv6() -> Result<Vec<Ipv6Info>> {
 }
 
 pub fn get_local_ipv6() -> Result<Ipv6Addr> {
-    let addrs = get_all_ipv6()?;
+    Ok(*select_local(None)?.ip())
+}
+
+/// Pick the local address to use, optionally for talking to a given peer.
+///
+/// A link-local peer can only be reached from a link-local address on the
+/// interface named by its scope ID. Otherwise the best scored address is
+/// used, which must be global unless `--allow-local` was given. The result
+/// carries the scope ID needed to bind link-local addresses; the port is 0.
+pub fn select_local(peer: Option<&SocketAddrV6>) -> Result<SocketAddrV6> {
+    let mut addrs = get_all_ipv6()?;
+    let selection = selection();
+
+    if let Some(source) = selection.source_addr {
+        return addrs
+            .iter()
+            .find(|info| info.addr == source)
+            .map(scoped)
+            .with_context(|| format!("Source address {} is not assigned to this host", source));
+    }
+
+    if let Some(interface) = &selection.interface {
+        addrs.retain(|info| &info.interface == interface);
+        if addrs.is_empty() {
+            bail!("No IPv6 address found on interface '{}'", interface);
+        }
+    }
+
+    // Preferences override the default scoring; the sort is stable so
+    // equally preferred addresses keep their score order
+    addrs.sort_by_key(|info| selection.preference_rank(info));
+
+    if let Some(peer) = peer.filter(|peer| is_link_local(peer.ip())) {
+        if !selection.allow_local {
+            bail!(
+                "Peer {} is a link-local address. Use --allow-local to enable link-local transfers.",
+                peer.ip()
+            );
+        }
+        if peer.scope_id() == 0 {
+            bail!(
+                "Link-local address {} needs a zone, e.g. {}%eth0",
+                peer.ip(),
+                peer.ip()
+            );
+        }
+        return addrs
+            .iter()
+            .find(|info| info.scope == Ipv6Scope::LinkLocal && info.index == peer.scope_id())
+            .map(|info| SocketAddrV6::new(info.addr, 0, 0, info.index))
+            .with_context(|| {
+                format!(
+                    "No link-local address on interface {} to reach {}",
+                    peer.scope_id(),
+                    peer.ip()
+                )
+            });
+    }
 
     // Check if we have a global/routable IPv6 address
     let has_global = addrs
         .iter()
         .any(|info| matches!(info.scope, Ipv6Scope::Global));
 
-    if !has_global {
+    if !has_global && !selection.allow_local {
         bail!(
             "No global/routable IPv6 address found. This software requires a public IPv6 address to work.\n\
              Found only: {}\n\
-             Please ensure your network has IPv6 connectivity.",
+             Please ensure your network has IPv6 connectivity, or use --allow-local on isolated networks.",
             addrs.iter()
                 .map(|info| format!("{} ({})", info.addr, match info.scope {
                     Ipv6Scope::LinkLocal => "link-local",
@@ -136,46 +264,575 @@ pub fn get_local_ipv6() -> Result<Ipv6Addr> {
         );
     }
 
-    Ok(addrs[0].addr)
+    // Use whatever address the kernel would route through towards the peer,
+    // so a better scored address on a VPN that cannot reach it is skipped.
+    // Preferences still come first: the routed address is only taken if it
+    // is among the most preferred ones.
+    if let Some(peer) = peer {
+        if let Some(routed) = route_source(peer) {
+            let best = selection.preference_rank(&addrs[0]);
+            let usable = addrs.iter().find(|info| {
+                info.addr == routed
+                    && (selection.allow_local || info.scope == Ipv6Scope::Global)
+                    && selection.preference_rank(info) == best
+            });
+            if let Some(info) = usable {
+                crate::debug!(
+                    "DEBUG [NET]: Kernel routes {} via {} ({})",
+                    peer.ip(),
+                    info.addr,
+                    info.interface
+                );
+                return Ok(scoped(info));
+            }
+        }
+    }
+
+    Ok(scoped(&addrs[0]))
 }
 
-pub async fn resolve_peer(peer: &str, config: &crate::config::Config) -> Result<Ipv6Addr> {
-    let peer_addr = match crate::peer::parse_peer(peer) {
-        crate::peer::PeerAddress::Ipv6(addr) => addr,
-        crate::peer::PeerAddress::Id(peer_id) => {
-            let local_ipv6 = get_local_ipv6()?;
-            let nonce = config.nonce.as_ref().ok_or_else(|| {
-                anyhow::anyhow!("No nonce found. Please re-register with: rxx register <id>")
-            })?;
+fn scoped(info: &Ipv6Info) -> SocketAddrV6 {
+    let scope_id = if info.scope == Ipv6Scope::LinkLocal {
+        info.index
+    } else {
+        0
+    };
+    SocketAddrV6::new(info.addr, 0, 0, scope_id)
+}
 
-            let client = reqwest::Client::new();
-            let response = client
-                .post(format!("{}/update", config.server_url))
-                .json(&serde_json::json!({
-                    "id": config.user_id,
-                    "ipv6": local_ipv6.to_string(),
-                    "nonce": nonce,
-                    "peer_id": peer_id
-                }))
-                .send()
-                .await
-                .context("Failed to contact server. Use direct IPv6 address instead.")?;
-
-            if response.status().is_success() {
-                let body: serde_json::Value = response.json().await?;
-                let peer_ipv6_str = body["peer_ipv6"]
-                    .as_str()
-                    .context("Invalid response from server")?;
-                peer_ipv6_str.parse()?
-            } else if response.status() == reqwest::StatusCode::NOT_FOUND {
-                bail!("Peer ID '{}' not found on server", peer_id);
-            } else if response.status() == reqwest::StatusCode::UNAUTHORIZED {
-                bail!("Invalid nonce. Please re-register with: rxx register <id>");
-            } else {
-                bail!("Server error: {}", response.status());
+/// How often the address monitor checks for changes
+const ADDRESS_POLL_SECS: u64 = 5;
+
+/// Watch the local address used towards `peer` (or in general) for changes.
+///
+/// Polls the interface list, which catches SLAAC rotations and network
+/// switches alike without needing netlink. The receiver always holds the
+/// address currently selected by `select_local`.
+pub fn spawn_address_monitor(peer: Option<SocketAddrV6>) -> Result<watch::Receiver<SocketAddrV6>> {
+    let initial = select_local(peer.as_ref())?;
+    let (tx, rx) = watch::channel(initial);
+
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(ADDRESS_POLL_SECS));
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let current = match select_local(peer.as_ref()) {
+                Ok(addr) => addr,
+                Err(e) => {
+                    crate::debug!("DEBUG [NET]: No usable local address right now: {}", e);
+                    continue;
+                }
+            };
+            let changed = tx.send_if_modified(|addr| {
+                if *addr == current {
+                    return false;
+                }
+                *addr = current;
+                true
+            });
+            if changed {
+                crate::info!("Local address changed to {}", current.ip());
+            }
+            if tx.is_closed() {
+                break;
             }
         }
+    });
+
+    Ok(rx)
+}
+
+/// Tell the server about our current address without resolving a peer.
+pub async fn refresh_registration(config: &crate::config::Config, addr: &Ipv6Addr) -> Result<()> {
+    let Some(nonce) = config.nonce.as_ref() else {
+        // Not registered, e.g. direct IPv6 mode only
+        return Ok(());
     };
 
-    Ok(peer_addr)
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/update", config.server_url))
+        .json(&serde_json::json!({
+            "id": config.user_id,
+            "ipv6": addr.to_string(),
+            "nonce": nonce
+        }))
+        .send()
+        .await
+        .context("Failed to contact server")?;
+
+    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        bail!("Invalid nonce. Please re-register with: rxx register <id>");
+    } else if !response.status().is_success() {
+        bail!("Server error: {}", response.status());
+    }
+    Ok(())
+}
+
+/// How often a running receiver tells the server it is still online
+const HEARTBEAT_SECS: u64 = 30;
+
+/// Tell the server we are online at `local_ipv6`, and whether we are
+/// receiving.
+pub async fn send_heartbeat(
+    config: &crate::config::Config,
+    local_ipv6: &Ipv6Addr,
+    listening: bool,
+) -> Result<()> {
+    let nonce = config.nonce.as_ref().ok_or_else(|| {
+        anyhow::anyhow!("No nonce found. Please re-register with: rxx register <id>")
+    })?;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/heartbeat", config.server_url))
+        .json(&serde_json::json!({
+            "id": config.user_id,
+            "ipv6": local_ipv6.to_string(),
+            "nonce": nonce,
+            "listening": listening,
+            "public_key": crate::mailbox::public_key(config),
+            "contacts": config.contacts.clone().unwrap_or_default()
+        }))
+        .send()
+        .await
+        .context("Failed to contact server")?;
+
+    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        bail!("Invalid nonce. Please re-register with: rxx register <id>");
+    } else if !response.status().is_success() {
+        bail!("Server error: {}", response.status());
+    }
+    Ok(())
+}
+
+/// Keep our presence on the server fresh while the receiver runs on the
+/// socket in `bound`.
+pub fn spawn_heartbeat(
+    config: &crate::config::Config,
+    bound: watch::Receiver<std::sync::Arc<crate::mux::MuxSocket>>,
+) -> tokio::task::JoinHandle<()> {
+    let config = config.clone();
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(HEARTBEAT_SECS));
+        loop {
+            ticker.tick().await;
+            let local_ipv6 = bound.borrow().local_ipv6();
+            let sent = match local_ipv6 {
+                Ok(local_ipv6) => send_heartbeat(&config, &local_ipv6, true).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = sent {
+                crate::debug!("DEBUG [PRESENCE]: Heartbeat failed: {}", e);
+            }
+        }
+    })
+}
+
+/// Presence of a peer as reported by the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStatus {
+    pub id: String,
+    pub registered: bool,
+    pub online: bool,
+    /// Running `rxx receive`, i.e. files can be sent right now
+    pub listening: bool,
+    /// The peer does not list us as a contact
+    pub hidden: bool,
+    pub last_seen: Option<Duration>,
+}
+
+impl PeerStatus {
+    pub fn describe(&self) -> String {
+        let seen = self.last_seen.map(format_age);
+        match (self.registered, self.online, seen) {
+            (false, _, _) => "not registered".to_string(),
+            _ if self.hidden => "hidden (does not list you as a contact)".to_string(),
+            (true, true, Some(seen)) if self.listening => {
+                format!("receiving (seen {} ago)", seen)
+            }
+            (true, true, Some(seen)) => format!("online (seen {} ago)", seen),
+            (true, _, Some(seen)) => format!("offline (last seen {} ago)", seen),
+            (true, _, None) => "offline (never seen)".to_string(),
+        }
+    }
+}
+
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Ask the server which of `peers` are online.
+pub async fn query_status(
+    config: &crate::config::Config,
+    peers: &[String],
+) -> Result<Vec<PeerStatus>> {
+    let nonce = config.nonce.as_ref().ok_or_else(|| {
+        anyhow::anyhow!("No nonce found. Please re-register with: rxx register <id>")
+    })?;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/status", config.server_url))
+        .json(&serde_json::json!({
+            "id": config.user_id,
+            "nonce": nonce,
+            "peers": peers
+        }))
+        .send()
+        .await
+        .context("Failed to contact server")?;
+
+    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        bail!("Invalid nonce. Please re-register with: rxx register <id>");
+    } else if response.status() == reqwest::StatusCode::NOT_FOUND {
+        bail!("Server does not support presence");
+    } else if !response.status().is_success() {
+        bail!("Server error: {}", response.status());
+    }
+
+    let body: serde_json::Value = response.json().await?;
+    let statuses = body["peers"]
+        .as_array()
+        .context("Invalid response from server")?
+        .iter()
+        .map(|peer| PeerStatus {
+            id: peer["id"].as_str().unwrap_or_default().to_string(),
+            registered: peer["registered"].as_bool().unwrap_or(false),
+            online: peer["online"].as_bool().unwrap_or(false),
+            listening: peer["listening"].as_bool().unwrap_or(false),
+            hidden: peer["hidden"].as_bool().unwrap_or(false),
+            last_seen: peer["last_seen_secs"].as_u64().map(Duration::from_secs),
+        })
+        .collect();
+    Ok(statuses)
+}
+
+/// A sender the server told us about while listening
+#[derive(Debug, Clone)]
+pub struct Rendezvous {
+    pub peer_id: String,
+    pub peer: ResolvedPeer,
+}
+
+/// Wait for senders that want to reach us, as announced by the server.
+///
+/// Long-polls `/listen`, which also keeps `local_ipv6` and our listening state
+/// fresh on the server. Returns an empty list when the poll times out.
+pub async fn listen_for_senders(
+    config: &crate::config::Config,
+    local_ipv6: &Ipv6Addr,
+) -> Result<Vec<Rendezvous>> {
+    let nonce = config.nonce.as_ref().ok_or_else(|| {
+        anyhow::anyhow!("No nonce found. Please re-register with: rxx register <id>")
+    })?;
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(60))
+        .build()?;
+    let response = client
+        .post(format!("{}/listen", config.server_url))
+        .json(&serde_json::json!({
+            "id": config.user_id,
+            "ipv6": local_ipv6.to_string(),
+            "nonce": nonce
+        }))
+        .send()
+        .await
+        .context("Failed to contact server")?;
+
+    if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        bail!("Invalid nonce. Please re-register with: rxx register <id>");
+    } else if response.status() == reqwest::StatusCode::NOT_FOUND {
+        bail!("Server does not support listening for any sender");
+    } else if !response.status().is_success() {
+        bail!("Server error: {}", response.status());
+    }
+
+    let body: serde_json::Value = response.json().await?;
+    let mut senders = Vec::new();
+    for request in body["requests"].as_array().into_iter().flatten() {
+        let (Some(peer_id), Some(peer_ipv6), Some(session_id), Some(key)) = (
+            request["peer_id"].as_str(),
+            request["peer_ipv6"].as_str(),
+            request["session_id"].as_str(),
+            request["session_key"].as_str(),
+        ) else {
+            continue;
+        };
+        let session = ProbeSession::from_hex(session_id, key, &config.user_id, peer_id)
+            .context("Invalid probe session from server")?;
+        senders.push(Rendezvous {
+            peer_id: peer_id.to_string(),
+            peer: ResolvedPeer {
+                addr: peer_ipv6.parse()?,
+                scope_id: 0,
+                session,
+                start_in: request["start_in_ms"].as_u64().map(Duration::from_millis),
+            },
+        });
+    }
+
+    Ok(senders)
+}
+
+/// Resolve an interface name or number from an address zone (`fe80::1%eth0`).
+pub fn zone_to_scope_id(zone: &str) -> Result<u32> {
+    if let Ok(index) = zone.parse::<u32>() {
+        return Ok(index);
+    }
+    if_addrs::get_if_addrs()?
+        .into_iter()
+        .find(|iface| iface.name == zone)
+        .and_then(|iface| iface.index)
+        .with_context(|| format!("Unknown network interface '{}'", zone))
+}
+
+/// A peer address together with the session used to authenticate probes.
+#[derive(Debug, Clone)]
+pub struct ResolvedPeer {
+    pub addr: Ipv6Addr,
+    /// Interface index for link-local addresses, 0 otherwise
+    pub scope_id: u32,
+    pub session: ProbeSession,
+    /// Set by the server when the peer is listening: both sides wait this
+    /// long and then start punching together
+    pub start_in: Option<Duration>,
+}
+
+impl ResolvedPeer {
+    pub fn socket_addr(&self, port: u16) -> SocketAddrV6 {
+        SocketAddrV6::new(self.addr, port, 0, self.scope_id)
+    }
+}
+
+pub fn is_link_local(addr: &Ipv6Addr) -> bool {
+    addr.segments()[0] & 0xffc0 == 0xfe80
+}
+
+pub async fn resolve_peer(peer: &str, config: &crate::config::Config) -> Result<ResolvedPeer> {
+    let peer_id = match crate::peer::parse_peer(peer) {
+        crate::peer::PeerAddress::Ipv6(addr) => {
+            return Ok(ResolvedPeer {
+                addr,
+                scope_id: 0,
+                session: ProbeSession::unauthenticated(&config.user_id),
+                start_in: None,
+            });
+        }
+        crate::peer::PeerAddress::ScopedIpv6(addr, zone) => {
+            return Ok(ResolvedPeer {
+                addr,
+                scope_id: zone_to_scope_id(&zone)?,
+                session: ProbeSession::unauthenticated(&config.user_id),
+                start_in: None,
+            });
+        }
+        crate::peer::PeerAddress::Id(peer_id) => peer_id,
+    };
+
+    // Peers on the same link answer directly, the registry is only needed
+    // for the probe session then. Both are asked at once so discovery does
+    // not delay every lookup.
+    let discovery = async {
+        if !config.lan_discovery.unwrap_or(true) {
+            return None;
+        }
+        match crate::lan::discover(&peer_id, &config.user_id).await {
+            Ok(found) => found,
+            Err(e) => {
+                crate::debug!("DEBUG [LAN]: Discovery failed: {}", e);
+                None
+            }
+        }
+    };
+    let (lan_addr, registry) = tokio::join!(discovery, lookup_registry(&peer_id, config));
+
+    match (lan_addr, registry) {
+        (Some(lan_addr), Ok(resolved)) => {
+            crate::info!(
+                "Found {} on the local network at {}",
+                peer_id,
+                lan_addr.ip()
+            );
+            Ok(ResolvedPeer {
+                addr: *lan_addr.ip(),
+                scope_id: lan_addr.scope_id(),
+                session: resolved.session,
+                start_in: resolved.start_in,
+            })
+        }
+        // Anyone on the link can answer discovery, so without the server's
+        // session key the answer cannot be trusted
+        (Some(lan_addr), Err(e)) => Err(e.context(format!(
+            "Found {} on the local network at {}, but cannot authenticate it without the server",
+            peer_id,
+            lan_addr.ip()
+        ))),
+        (None, registry) => registry,
+    }
+}
+
+async fn lookup_registry(peer_id: &str, config: &crate::config::Config) -> Result<ResolvedPeer> {
+    // The receiver punches towards the address we register, so it has to be
+    // the one our socket binds for this peer, which depends on where the
+    // peer is. Ask first without announcing ourselves.
+    let guess = get_local_ipv6()?;
+    let found = update_with_peer(peer_id, guess, true, config).await?;
+    let peer_addr: Ipv6Addr = found["peer_ipv6"]
+        .as_str()
+        .context("Invalid response from server")?
+        .parse()?;
+    let local = *select_local(Some(&SocketAddrV6::new(
+        peer_addr,
+        crate::udp::SERVER_PORT,
+        0,
+        0,
+    )))?
+    .ip();
+    if local != guess {
+        crate::debug!("DEBUG [NET]: Registering {} to reach {}", local, peer_addr);
+    }
+
+    let body = update_with_peer(peer_id, local, false, config).await?;
+    let peer_ipv6_str = body["peer_ipv6"]
+        .as_str()
+        .context("Invalid response from server")?;
+
+    let session = match (body["session_id"].as_str(), body["session_key"].as_str()) {
+        (Some(session_id), Some(key)) => {
+            ProbeSession::from_hex(session_id, key, &config.user_id, peer_id)
+                .context("Invalid probe session from server")?
+        }
+        _ => {
+            crate::info!(
+                "WARNING: server did not provide a probe session, probes will not be authenticated"
+            );
+            ProbeSession::unauthenticated(&config.user_id)
+        }
+    };
+
+    Ok(ResolvedPeer {
+        addr: peer_ipv6_str.parse()?,
+        scope_id: 0,
+        session,
+        start_in: body["start_in_ms"].as_u64().map(Duration::from_millis),
+    })
+}
+
+/// Report `local_ipv6` as our address and resolve `peer_id`. Unless this is
+/// a `lookup`, a listening peer is told we want to connect.
+async fn update_with_peer(
+    peer_id: &str,
+    local_ipv6: Ipv6Addr,
+    lookup: bool,
+    config: &crate::config::Config,
+) -> Result<serde_json::Value> {
+    let nonce = config.nonce.as_ref().ok_or_else(|| {
+        anyhow::anyhow!("No nonce found. Please re-register with: rxx register <id>")
+    })?;
+
+    let client = reqwest::Client::new();
+    let response = client
+        .post(format!("{}/update", config.server_url))
+        .json(&serde_json::json!({
+            "id": config.user_id,
+            "ipv6": local_ipv6.to_string(),
+            "nonce": nonce,
+            "peer_id": peer_id,
+            "lookup": lookup
+        }))
+        .send()
+        .await
+        .context("Failed to contact server. Use direct IPv6 address instead.")?;
+
+    if response.status().is_success() {
+        Ok(response.json().await?)
+    } else if response.status() == reqwest::StatusCode::NOT_FOUND {
+        bail!("Peer ID '{}' not found on server", peer_id);
+    } else if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+        bail!("Invalid nonce. Please re-register with: rxx register <id>");
+    } else {
+        bail!("Server error: {}", response.status());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(addr: &str, interface: &str) -> Ipv6Info {
+        let addr: Ipv6Addr = addr.parse().unwrap();
+        Ipv6Info {
+            addr,
+            interface: interface.to_string(),
+            index: 1,
+            is_temporary: false,
+            scope: classify_ipv6(&addr),
+        }
+    }
+
+    #[test]
+    fn test_matches_preference() {
+        let global = info("2001:db8:1::5", "eth0");
+
+        assert!(matches_preference("eth0", &global));
+        assert!(!matches_preference("wg0", &global));
+        assert!(matches_preference("2001:db8:1::5", &global));
+        assert!(!matches_preference("2001:db8:1::6", &global));
+        assert!(matches_preference("2001:db8::/32", &global));
+        assert!(matches_preference("2001:db8:1::/48", &global));
+        assert!(!matches_preference("2001:db8:2::/48", &global));
+        assert!(matches_preference("::/0", &global));
+        assert!(!matches_preference("2001:db8::/bogus", &global));
+    }
+
+    #[test]
+    fn test_describe_status() {
+        let mut status = PeerStatus {
+            id: "bob".to_string(),
+            registered: true,
+            online: true,
+            listening: true,
+            hidden: false,
+            last_seen: Some(Duration::from_secs(12)),
+        };
+        assert_eq!(status.describe(), "receiving (seen 12s ago)");
+
+        status.listening = false;
+        status.last_seen = Some(Duration::from_secs(65));
+        assert_eq!(status.describe(), "online (seen 1m ago)");
+
+        status.online = false;
+        status.last_seen = Some(Duration::from_secs(3 * 86400));
+        assert_eq!(status.describe(), "offline (last seen 3d ago)");
+
+        status.last_seen = None;
+        assert_eq!(status.describe(), "offline (never seen)");
+
+        status.hidden = true;
+        assert_eq!(status.describe(), "hidden (does not list you as a contact)");
+
+        status.registered = false;
+        assert_eq!(status.describe(), "not registered");
+    }
+
+    #[test]
+    fn test_preference_rank() {
+        let selection = AddrSelection {
+            preferred: vec!["eth1".to_string(), "2001:db8::/32".to_string()],
+            ..Default::default()
+        };
+
+        assert_eq!(selection.preference_rank(&info("fd00::1", "eth1")), 0);
+        assert_eq!(selection.preference_rank(&info("2001:db8::1", "eth0")), 1);
+        assert_eq!(selection.preference_rank(&info("2a00::1", "wg0")), 2);
+    }
 }
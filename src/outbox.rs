@@ -0,0 +1,262 @@
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Delay before the first retry, doubled after every failed attempt
+const RETRY_DELAY_SECS: i64 = 30;
+const MAX_RETRY_DELAY_SECS: i64 = 60 * 60;
+/// A claimed job is left to its runner for this long, after which another
+/// runner may take it over in case the first one died
+const CLAIM_SECS: i64 = 6 * 60 * 60;
+
+/// Sends waiting to be retried by `rxx outbox run`, kept in
+/// `~/.rxx/outbox.db` so they survive restarts.
+pub struct Outbox {
+    conn: Connection,
+}
+
+/// A queued send
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub id: i64,
+    pub file: PathBuf,
+    pub destination: String,
+    /// Send as a delta (`rxx send --delta`)
+    pub delta: bool,
+    pub created_at: i64,
+    pub attempts: u32,
+    /// Unix time of the next attempt
+    pub next_attempt: i64,
+    pub last_error: Option<String>,
+    /// Unix time until which a runner is sending this job
+    pub claimed_until: Option<i64>,
+}
+
+pub fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+/// Seconds to wait after the `attempts`-th failed attempt.
+fn retry_delay(attempts: u32) -> i64 {
+    let doublings = attempts.saturating_sub(1).min(16);
+    (RETRY_DELAY_SECS << doublings).min(MAX_RETRY_DELAY_SECS)
+}
+
+impl Outbox {
+    pub fn open_default() -> Result<Self> {
+        Self::open(crate::config::Config::data_dir()?.join("outbox.db"))
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let conn = Connection::open(path.as_ref())
+            .with_context(|| format!("Failed to open outbox {:?}", path.as_ref()))?;
+        // `rxx send --queue` and `rxx outbox run` may use it at the same time
+        conn.busy_timeout(Duration::from_secs(5))?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS jobs (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                file TEXT NOT NULL,
+                destination TEXT NOT NULL,
+                delta INTEGER NOT NULL DEFAULT 0,
+                created_at INTEGER NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt INTEGER NOT NULL,
+                last_error TEXT,
+                claimed_until INTEGER
+            )",
+            [],
+        )?;
+
+        crate::db::add_missing_column(&conn, "jobs", "claimed_until", "INTEGER")?;
+        Ok(Outbox { conn })
+    }
+
+    /// Queue `file` for `destination`, to be tried right away. Returns the
+    /// job ID.
+    pub fn add(&self, file: &Path, destination: &str, delta: bool) -> Result<i64> {
+        let file = file
+            .canonicalize()
+            .with_context(|| format!("Failed to open file: {:?}", file))?;
+        let file = file.to_str().context("File path is not valid UTF-8")?;
+        let now = now();
+        self.conn.execute(
+            "INSERT INTO jobs (file, destination, delta, created_at, next_attempt)
+             VALUES (?1, ?2, ?3, ?4, ?4)",
+            params![file, destination, delta, now],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Record a failed attempt and schedule the next one. Returns the delay
+    /// in seconds.
+    pub fn failed(&self, id: i64, error: &str) -> Result<i64> {
+        let attempts: u32 = self.conn.query_row(
+            "SELECT attempts FROM jobs WHERE id = ?1",
+            params![id],
+            |row| row.get(0),
+        )?;
+        let delay = retry_delay(attempts + 1);
+        self.conn.execute(
+            "UPDATE jobs SET attempts = attempts + 1, next_attempt = ?1, last_error = ?2,
+                 claimed_until = NULL
+             WHERE id = ?3",
+            params![now() + delay, error, id],
+        )?;
+        Ok(delay)
+    }
+
+    /// Give up the claim on jobs this runner did not get to, so they are
+    /// tried again right away.
+    pub fn release(&self, ids: &[i64]) -> Result<()> {
+        for id in ids {
+            self.conn.execute(
+                "UPDATE jobs SET claimed_until = NULL WHERE id = ?1",
+                params![id],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Remove a job, once delivered or cancelled. Returns whether it existed.
+    pub fn remove(&self, id: i64) -> Result<bool> {
+        Ok(self
+            .conn
+            .execute("DELETE FROM jobs WHERE id = ?1", params![id])?
+            > 0)
+    }
+
+    /// All jobs, in the order they were queued.
+    pub fn list(&self) -> Result<Vec<Job>> {
+        self.query("SELECT * FROM jobs ORDER BY id", params![])
+    }
+
+    /// Take the jobs whose next attempt is due at `now` and that no other
+    /// runner is sending. Claiming happens in one statement, so two runners
+    /// never get the same job.
+    pub fn claim(&self, now: i64) -> Result<Vec<Job>> {
+        let mut jobs = self.query(
+            "UPDATE jobs SET claimed_until = ?2
+             WHERE next_attempt <= ?1 AND (claimed_until IS NULL OR claimed_until <= ?1)
+             RETURNING *",
+            params![now, now + CLAIM_SECS],
+        )?;
+        jobs.sort_by_key(|job| job.id);
+        Ok(jobs)
+    }
+
+    /// Unix time at which the next job becomes due, if any are queued.
+    pub fn next_due(&self) -> Result<Option<i64>> {
+        Ok(self.conn.query_row(
+            "SELECT MIN(MAX(next_attempt, COALESCE(claimed_until, 0))) FROM jobs",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    fn query(&self, sql: &str, params: &[&dyn rusqlite::ToSql]) -> Result<Vec<Job>> {
+        let mut stmt = self.conn.prepare(sql)?;
+        let jobs = stmt
+            .query_map(params, |row| {
+                Ok(Job {
+                    id: row.get("id")?,
+                    file: PathBuf::from(row.get::<_, String>("file")?),
+                    destination: row.get("destination")?,
+                    delta: row.get("delta")?,
+                    created_at: row.get("created_at")?,
+                    attempts: row.get("attempts")?,
+                    next_attempt: row.get("next_attempt")?,
+                    last_error: row.get("last_error")?,
+                    claimed_until: row.get("claimed_until")?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(jobs)
+    }
+}
+
+impl Job {
+    /// One line of `rxx outbox list` output.
+    pub fn describe(&self, now: i64) -> String {
+        let next = if self.claimed_until.is_some_and(|until| until > now) {
+            "sending".to_string()
+        } else if self.next_attempt <= now {
+            "now".to_string()
+        } else {
+            format!(
+                "in {}",
+                crate::net::format_age(Duration::from_secs((self.next_attempt - now) as u64))
+            )
+        };
+        let mut line = format!(
+            "#{:<4} {:<12} {:>3} attempt(s), next {:<8} {}",
+            self.id,
+            self.destination,
+            self.attempts,
+            next,
+            self.file.display()
+        );
+        if let Some(error) = &self.last_error {
+            line.push_str(&format!("\n      last error: {}", error));
+        }
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(1), 30);
+        assert_eq!(retry_delay(2), 60);
+        assert_eq!(retry_delay(3), 120);
+        assert_eq!(retry_delay(8), MAX_RETRY_DELAY_SECS);
+        assert_eq!(retry_delay(1000), MAX_RETRY_DELAY_SECS);
+    }
+
+    #[test]
+    fn test_outbox() {
+        let outbox = Outbox::open(":memory:").unwrap();
+        let file = std::env::current_exe().unwrap();
+        let first = outbox.add(&file, "bob", false).unwrap();
+        let second = outbox.add(&file, "carol", true).unwrap();
+        assert!(outbox
+            .add(Path::new("/no/such/file"), "bob", false)
+            .is_err());
+
+        let jobs = outbox.list().unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].destination, "bob");
+        assert_eq!(jobs[0].file, file.canonicalize().unwrap());
+        assert!(jobs[1].delta);
+        let claimed = outbox.claim(now()).unwrap();
+        assert_eq!(
+            claimed.iter().map(|job| job.id).collect::<Vec<_>>(),
+            [first, second]
+        );
+        // Another runner gets nothing until the claim runs out
+        assert!(outbox.claim(now()).unwrap().is_empty());
+        assert_eq!(outbox.claim(now() + CLAIM_SECS).unwrap().len(), 2);
+
+        // A failure releases the job and pushes it back
+        assert_eq!(outbox.failed(first, "peer unreachable").unwrap(), 30);
+        let job = &outbox.list().unwrap()[0];
+        assert_eq!(job.attempts, 1);
+        assert_eq!(job.claimed_until, None);
+        assert_eq!(job.last_error.as_deref(), Some("peer unreachable"));
+        assert!(outbox.claim(now()).unwrap().is_empty());
+        let claimed = outbox.claim(now() + 30).unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].id, first);
+        outbox.release(&[first]).unwrap();
+        assert_eq!(outbox.next_due().unwrap(), Some(now() + 30));
+
+        assert!(outbox.remove(first).unwrap());
+        assert!(!outbox.remove(first).unwrap());
+        assert_eq!(outbox.list().unwrap().len(), 1);
+    }
+}
@@ -3,11 +3,19 @@ use std::net::Ipv6Addr;
 pub enum PeerAddress {
     Id(String),
     Ipv6(Ipv6Addr),
+    /// Address with a zone identifier, e.g. `fe80::1%eth0`
+    ScopedIpv6(Ipv6Addr, String),
 }
 
 pub fn parse_peer(input: &str) -> PeerAddress {
     if input.contains(':') {
-        if let Ok(addr) = input.parse::<Ipv6Addr>() {
+        if let Some((addr, zone)) = input.split_once('%') {
+            if let Ok(addr) = addr.parse::<Ipv6Addr>() {
+                if !zone.is_empty() {
+                    return PeerAddress::ScopedIpv6(addr, zone.to_string());
+                }
+            }
+        } else if let Ok(addr) = input.parse::<Ipv6Addr>() {
             return PeerAddress::Ipv6(addr);
         }
     }
@@ -31,6 +39,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_scoped_ipv6() {
+        match parse_peer("fe80::1%eth0") {
+            PeerAddress::ScopedIpv6(addr, zone) => {
+                assert_eq!(addr.to_string(), "fe80::1");
+                assert_eq!(zone, "eth0");
+            }
+            _ => panic!("Expected scoped IPv6"),
+        }
+
+        match parse_peer("fe80::1%2") {
+            PeerAddress::ScopedIpv6(_, zone) => assert_eq!(zone, "2"),
+            _ => panic!("Expected scoped IPv6"),
+        }
+
+        assert!(matches!(parse_peer("fe80::1%"), PeerAddress::Id(_)));
+    }
+
     #[test]
     fn test_parse_id() {
         match parse_peer("alice") {
@@ -0,0 +1,289 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const MAGIC: &[u8; 4] = b"RXXP";
+const VERSION: u8 = 2;
+const MAC_LEN: usize = 32;
+pub const SESSION_ID_LEN: usize = 16;
+pub const NONCE_LEN: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeKind {
+    Probe = 1,
+    Ack = 2,
+}
+
+/// Shared state both peers use to authenticate hole punching probes.
+///
+/// The session ID and key are handed out by the registration server to both
+/// ends of a peer pair. In direct IPv6 mode there is no server, so probes are
+/// sent with an all-zero session and an empty key and are not authenticated.
+#[derive(Debug, Clone)]
+pub struct ProbeSession {
+    pub session_id: [u8; SESSION_ID_LEN],
+    key: Vec<u8>,
+    pub local_id: String,
+    pub peer_id: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Probe {
+    pub kind: ProbeKind,
+    pub sender_id: String,
+    /// Fresh for each punching attempt in a probe, copied from the probe
+    /// it answers in an ACK, so an old ACK cannot be replayed
+    pub nonce: [u8; NONCE_LEN],
+}
+
+/// A nonce for the probes of one punching attempt.
+pub fn new_nonce() -> [u8; NONCE_LEN] {
+    rand::random()
+}
+
+/// Session ID of something that looks like a probe, without verifying it.
+pub fn session_id_of(data: &[u8]) -> Option<[u8; SESSION_ID_LEN]> {
+    let start = MAGIC.len() + 2;
+    if data.len() < start + SESSION_ID_LEN || &data[..MAGIC.len()] != MAGIC {
+        return None;
+    }
+    data[start..start + SESSION_ID_LEN].try_into().ok()
+}
+
+impl ProbeSession {
+    pub fn new(
+        session_id: [u8; SESSION_ID_LEN],
+        key: Vec<u8>,
+        local_id: &str,
+        peer_id: Option<&str>,
+    ) -> Self {
+        ProbeSession {
+            session_id,
+            key,
+            local_id: local_id.to_string(),
+            peer_id: peer_id.map(|p| p.to_string()),
+        }
+    }
+
+    pub fn unauthenticated(local_id: &str) -> Self {
+        Self::new([0u8; SESSION_ID_LEN], Vec::new(), local_id, None)
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        !self.key.is_empty()
+    }
+
+    /// Parse the hex encoded session ID and key returned by the server.
+    pub fn from_hex(
+        session_id: &str,
+        key: &str,
+        local_id: &str,
+        peer_id: &str,
+    ) -> anyhow::Result<Self> {
+        let id_bytes = hex::decode(session_id)?;
+        let session_id: [u8; SESSION_ID_LEN] = id_bytes
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid session ID length"))?;
+        let key = hex::decode(key)?;
+        if key.is_empty() {
+            anyhow::bail!("Empty session key");
+        }
+        Ok(Self::new(session_id, key, local_id, Some(peer_id)))
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.key).expect("HMAC accepts keys of any length")
+    }
+
+    pub fn encode(&self, kind: ProbeKind, nonce: &[u8; NONCE_LEN]) -> Vec<u8> {
+        let id = self.local_id.as_bytes();
+        let id_len = id.len().min(u8::MAX as usize);
+
+        let mut packet =
+            Vec::with_capacity(MAGIC.len() + 3 + SESSION_ID_LEN + id_len + NONCE_LEN + MAC_LEN);
+        packet.extend_from_slice(MAGIC);
+        packet.push(VERSION);
+        packet.push(kind as u8);
+        packet.extend_from_slice(&self.session_id);
+        packet.push(id_len as u8);
+        packet.extend_from_slice(&id[..id_len]);
+        packet.extend_from_slice(nonce);
+
+        let mut mac = self.mac();
+        mac.update(&packet);
+        packet.extend_from_slice(&mac.finalize().into_bytes());
+        packet
+    }
+
+    /// Decode and verify a probe belonging to this session.
+    ///
+    /// Returns `None` for anything that is not a valid probe from our peer:
+    /// foreign sessions, bad MACs, reflected copies of our own probes, or a
+    /// sender ID other than the one the server resolved.
+    pub fn decode(&self, data: &[u8]) -> Option<Probe> {
+        let header_len = MAGIC.len() + 2 + SESSION_ID_LEN + 1;
+        if data.len() < header_len + NONCE_LEN + MAC_LEN || &data[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        if data[MAGIC.len()] != VERSION {
+            return None;
+        }
+
+        let kind = match data[MAGIC.len() + 1] {
+            1 => ProbeKind::Probe,
+            2 => ProbeKind::Ack,
+            _ => return None,
+        };
+
+        let session_start = MAGIC.len() + 2;
+        if data[session_start..session_start + SESSION_ID_LEN] != self.session_id {
+            return None;
+        }
+
+        let id_len = data[header_len - 1] as usize;
+        if data.len() != header_len + id_len + NONCE_LEN + MAC_LEN {
+            return None;
+        }
+        let (signed, tag) = data.split_at(header_len + id_len + NONCE_LEN);
+
+        let mut mac = self.mac();
+        mac.update(signed);
+        mac.verify_slice(tag).ok()?;
+
+        // Without a key anyone can forge probes, so rejecting reflections only
+        // matters for authenticated sessions. Skipping it there also lets a
+        // user with the same config on two machines send to themselves.
+        let (sender_id, nonce) = signed[header_len..].split_at(id_len);
+        let sender_id = std::str::from_utf8(sender_id).ok()?.to_string();
+        let nonce = nonce.try_into().ok()?;
+        if self.is_authenticated() && sender_id.eq_ignore_ascii_case(&self.local_id) {
+            return None;
+        }
+        if let Some(peer_id) = &self.peer_id {
+            if !sender_id.eq_ignore_ascii_case(peer_id) {
+                return None;
+            }
+        }
+
+        Some(Probe {
+            kind,
+            sender_id,
+            nonce,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> (ProbeSession, ProbeSession) {
+        let id = [7u8; SESSION_ID_LEN];
+        let key = b"shared-secret".to_vec();
+        (
+            ProbeSession::new(id, key.clone(), "alice", Some("bob")),
+            ProbeSession::new(id, key, "bob", Some("alice")),
+        )
+    }
+
+    #[test]
+    fn test_probe_roundtrip() {
+        let (alice, bob) = pair();
+
+        let nonce = new_nonce();
+        let packet = alice.encode(ProbeKind::Probe, &nonce);
+        let probe = bob.decode(&packet).expect("valid probe");
+        assert_eq!(probe.kind, ProbeKind::Probe);
+        assert_eq!(probe.sender_id, "alice");
+        assert_eq!(probe.nonce, nonce);
+
+        let ack = alice
+            .decode(&bob.encode(ProbeKind::Ack, &probe.nonce))
+            .unwrap();
+        assert_eq!(ack.kind, ProbeKind::Ack);
+        assert_eq!(ack.nonce, nonce);
+
+        // The nonce is covered by the MAC
+        let mut packet = packet;
+        let at = packet.len() - MAC_LEN - 1;
+        packet[at] ^= 0xff;
+        assert!(bob.decode(&packet).is_none());
+    }
+
+    #[test]
+    fn test_probe_rejects_foreign_packets() {
+        let (alice, bob) = pair();
+
+        // Legacy fixed probes and random garbage
+        assert!(bob.decode(b"RXX_PROBE").is_none());
+        assert!(bob.decode(&[0u8; 64]).is_none());
+
+        // Tampered MAC
+        let mut packet = alice.encode(ProbeKind::Probe, &new_nonce());
+        let last = packet.len() - 1;
+        packet[last] ^= 0xff;
+        assert!(bob.decode(&packet).is_none());
+
+        // Different session
+        let other = ProbeSession::new(
+            [9u8; SESSION_ID_LEN],
+            b"shared-secret".to_vec(),
+            "alice",
+            None,
+        );
+        assert!(bob
+            .decode(&other.encode(ProbeKind::Probe, &[0; NONCE_LEN]))
+            .is_none());
+
+        // Wrong key
+        let forged = ProbeSession::new([7u8; SESSION_ID_LEN], b"guess".to_vec(), "alice", None);
+        assert!(bob
+            .decode(&forged.encode(ProbeKind::Probe, &[0; NONCE_LEN]))
+            .is_none());
+
+        // Our own probe reflected back
+        assert!(alice
+            .decode(&alice.encode(ProbeKind::Probe, &[0; NONCE_LEN]))
+            .is_none());
+
+        // Valid MAC but unexpected sender
+        let carol = ProbeSession::new(
+            [7u8; SESSION_ID_LEN],
+            b"shared-secret".to_vec(),
+            "carol",
+            None,
+        );
+        assert!(bob
+            .decode(&carol.encode(ProbeKind::Probe, &[0; NONCE_LEN]))
+            .is_none());
+    }
+
+    #[test]
+    fn test_session_id_of() {
+        let (alice, _) = pair();
+        assert_eq!(
+            session_id_of(&alice.encode(ProbeKind::Probe, &[0; NONCE_LEN])),
+            Some([7u8; SESSION_ID_LEN])
+        );
+        assert_eq!(session_id_of(b"RXXP"), None);
+        assert_eq!(session_id_of(&[0x52u8; 64]), None);
+    }
+
+    #[test]
+    fn test_unauthenticated_session() {
+        let alice = ProbeSession::unauthenticated("alice");
+        let bob = ProbeSession::unauthenticated("bob");
+        assert!(!alice.is_authenticated());
+
+        let probe = bob
+            .decode(&alice.encode(ProbeKind::Probe, &[0; NONCE_LEN]))
+            .unwrap();
+        assert_eq!(probe.sender_id, "alice");
+
+        // The same ID on both ends is fine without a key
+        assert!(alice
+            .decode(&alice.encode(ProbeKind::Ack, &[0; NONCE_LEN]))
+            .is_some());
+    }
+}
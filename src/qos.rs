@@ -0,0 +1,352 @@
+use anyhow::{bail, Context, Result};
+use quinn::congestion::{Controller, ControllerFactory};
+use quinn_proto::RttEstimator;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+
+/// How far ahead of the rate a sender may get after being idle
+const BURST: Duration = Duration::from_millis(100);
+/// Largest write let through at once, so a limited send stays smooth
+const MAX_WRITE: usize = 16 * 1024;
+
+/// Token bucket shared by every send of the process, so `--limit` caps the
+/// total no matter how many transfers run at once.
+struct Bucket {
+    /// Bytes per second, 0 for no limit
+    rate: u64,
+    tokens: f64,
+    last: Option<Instant>,
+}
+
+static BUCKET: Mutex<Bucket> = Mutex::new(Bucket {
+    rate: 0,
+    tokens: 0.0,
+    last: None,
+});
+static BACKGROUND: AtomicBool = AtomicBool::new(false);
+
+impl Bucket {
+    fn capacity(&self) -> f64 {
+        (self.rate as f64 * BURST.as_secs_f64()).max(MAX_WRITE as f64)
+    }
+
+    /// Take `n` bytes' worth of tokens, or tell how long until there are
+    /// enough.
+    fn take(&mut self, n: usize, now: Instant) -> Result<(), Duration> {
+        if let Some(last) = self.last {
+            let refill = now.saturating_duration_since(last).as_secs_f64() * self.rate as f64;
+            self.tokens = (self.tokens + refill).min(self.capacity());
+        }
+        self.last = Some(now);
+        if self.tokens >= n as f64 {
+            self.tokens -= n as f64;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (n as f64 - self.tokens) / self.rate as f64,
+            ))
+        }
+    }
+}
+
+/// Parse a rate in bytes per second, with an optional `K`, `M` or `G`
+/// suffix (powers of 1024): `20M`, `512k`, `1.5G`. `0` means no limit.
+pub fn parse_rate(rate: &str) -> Result<u64> {
+    let rate = rate.trim();
+    let (number, unit) = match rate.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&rate[..i], c.to_ascii_uppercase()),
+        _ => (rate, ' '),
+    };
+    let factor = match unit {
+        ' ' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        _ => bail!("Unknown unit in rate {:?} (use K, M or G)", rate),
+    };
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("Invalid rate {:?}", rate))?;
+    if !number.is_finite() || number < 0.0 {
+        bail!("Invalid rate {:?}", rate);
+    }
+    Ok((number * factor as f64) as u64)
+}
+
+/// Set the send rate limit in bytes per second, 0 for none.
+fn set_rate(rate: u64) {
+    let mut bucket = BUCKET.lock().unwrap();
+    bucket.rate = rate;
+    bucket.tokens = bucket.tokens.min(bucket.capacity());
+}
+
+/// Apply the rate `limit` (see `parse_rate`) and whether connections we open
+/// use the background congestion controller.
+pub fn configure(limit: Option<&str>, background: bool) -> Result<()> {
+    let rate = limit.map(parse_rate).transpose()?.unwrap_or(0);
+    if rate > 0 {
+        crate::info!("Limiting sends to {}/s", indicatif::HumanBytes(rate));
+    }
+    set_rate(rate);
+    BACKGROUND.store(background, Ordering::Relaxed);
+    Ok(())
+}
+
+pub fn background() -> bool {
+    BACKGROUND.load(Ordering::Relaxed)
+}
+
+/// Take the rate limit from the config file again whenever we get SIGHUP,
+/// so it can be changed while a long transfer runs. A `fixed` limit, given
+/// on the command line, overrides the config file and stays.
+pub fn reload_on_hangup(fixed: bool) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                crate::info!("Cannot change the rate limit at runtime: {}", e);
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                if fixed {
+                    crate::info!("Keeping the rate limit given with --limit");
+                    continue;
+                }
+                let limit = crate::config::Config::load().map(|config| config.limit);
+                match limit.and_then(|limit| limit.as_deref().map(parse_rate).transpose()) {
+                    Ok(rate) => {
+                        let rate = rate.unwrap_or(0);
+                        set_rate(rate);
+                        if rate > 0 {
+                            crate::info!("Rate limit now {}/s", indicatif::HumanBytes(rate));
+                        } else {
+                            crate::info!("Rate limit removed");
+                        }
+                    }
+                    Err(e) => crate::info!("Keeping the rate limit: {:#}", e),
+                }
+            }
+        });
+    }
+}
+
+/// Passes writes on to `inner` no faster than the rate limit allows.
+pub struct Throttle<W> {
+    inner: W,
+    /// Bytes taken from the bucket but not written yet
+    granted: usize,
+    sleep: Option<Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl<W: AsyncWrite + Unpin> Throttle<W> {
+    pub fn new(inner: W) -> Self {
+        Throttle {
+            inner,
+            granted: 0,
+            sleep: None,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Throttle<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        while this.granted == 0 {
+            if let Some(sleep) = &mut this.sleep {
+                ready!(sleep.as_mut().poll(cx));
+                this.sleep = None;
+            }
+            let want = buf.len().min(MAX_WRITE);
+            let mut bucket = BUCKET.lock().unwrap();
+            if bucket.rate == 0 || want == 0 {
+                drop(bucket);
+                return Pin::new(&mut this.inner).poll_write(cx, buf);
+            }
+            match bucket.take(want, Instant::now()) {
+                Ok(()) => this.granted = want,
+                Err(wait) => this.sleep = Some(Box::pin(tokio::time::sleep(wait))),
+            }
+        }
+        let n = buf.len().min(this.granted);
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..n]))?;
+        this.granted -= n;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Queuing delay the background controller lets a transfer add
+const TARGET: Duration = Duration::from_millis(25);
+
+/// Congestion controller for `--background`, after LEDBAT (RFC 6817). The
+/// window grows while the round trip stays close to the smallest seen and
+/// shrinks once our own queue delays it by more than `TARGET`, so other
+/// traffic on the link goes first. Loss halves the window like NewReno.
+#[derive(Debug, Clone)]
+struct Ledbat {
+    mtu: u64,
+    window: f64,
+    initial: u64,
+    slow_start: bool,
+    recovery_start: Option<Instant>,
+}
+
+impl Ledbat {
+    fn new(mtu: u16) -> Self {
+        let initial = 10 * mtu as u64;
+        Ledbat {
+            mtu: mtu as u64,
+            window: initial as f64,
+            initial,
+            slow_start: true,
+            recovery_start: None,
+        }
+    }
+
+    fn min_window(&self) -> f64 {
+        2.0 * self.mtu as f64
+    }
+}
+
+impl Controller for Ledbat {
+    fn on_ack(
+        &mut self,
+        _now: Instant,
+        sent: Instant,
+        bytes: u64,
+        app_limited: bool,
+        rtt: &RttEstimator,
+    ) {
+        if app_limited || self.recovery_start.is_some_and(|start| sent <= start) {
+            return;
+        }
+        let queuing = rtt.get().saturating_sub(rtt.min());
+        if self.slow_start {
+            if queuing < TARGET * 3 / 4 {
+                self.window += bytes as f64;
+                return;
+            }
+            self.slow_start = false;
+        }
+        let off_target = 1.0 - queuing.as_secs_f64() / TARGET.as_secs_f64();
+        self.window += off_target * bytes as f64 * self.mtu as f64 / self.window;
+        self.window = self.window.max(self.min_window());
+    }
+
+    fn on_congestion_event(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        is_persistent_congestion: bool,
+        _lost_bytes: u64,
+    ) {
+        if self.recovery_start.is_some_and(|start| sent <= start) {
+            return;
+        }
+        self.recovery_start = Some(now);
+        self.slow_start = false;
+        self.window = if is_persistent_congestion {
+            self.min_window()
+        } else {
+            (self.window / 2.0).max(self.min_window())
+        };
+    }
+
+    fn on_mtu_update(&mut self, new_mtu: u16) {
+        self.mtu = new_mtu as u64;
+        self.window = self.window.max(self.min_window());
+    }
+
+    fn window(&self) -> u64 {
+        self.window as u64
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(self.clone())
+    }
+
+    fn initial_window(&self) -> u64 {
+        self.initial
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn std::any::Any> {
+        self
+    }
+}
+
+/// Builds the background controller for each connection
+pub struct LedbatFactory;
+
+impl ControllerFactory for LedbatFactory {
+    fn build(self: Arc<Self>, _now: Instant, current_mtu: u16) -> Box<dyn Controller> {
+        Box::new(Ledbat::new(current_mtu))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("20M").unwrap(), 20 * 1024 * 1024);
+        assert_eq!(parse_rate("512k").unwrap(), 512 * 1024);
+        assert_eq!(parse_rate("1.5G").unwrap(), 3 * 512 * 1024 * 1024);
+        assert_eq!(parse_rate("1000").unwrap(), 1000);
+        assert_eq!(parse_rate("0").unwrap(), 0);
+        assert!(parse_rate("20X").is_err());
+        assert!(parse_rate("M").is_err());
+        assert!(parse_rate("-1M").is_err());
+        assert!(parse_rate("").is_err());
+    }
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket {
+            rate: 1024 * 1024,
+            tokens: 0.0,
+            last: None,
+        };
+        // Empty at first: 16K at 1M/s takes about 16ms
+        let wait = bucket.take(MAX_WRITE, start).unwrap_err();
+        assert!(wait > Duration::from_millis(15) && wait <= Duration::from_millis(16));
+
+        // After a second only the burst is available
+        let later = start + Duration::from_secs(1);
+        for _ in 0..6 {
+            bucket.take(MAX_WRITE, later).unwrap();
+        }
+        assert!(bucket.take(MAX_WRITE, later).is_err());
+        assert!(bucket
+            .take(MAX_WRITE, later + Duration::from_millis(16))
+            .is_ok());
+    }
+}
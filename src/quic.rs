@@ -1,18 +1,47 @@
 use anyhow::{Context, Result};
 use indicatif::{ProgressBar, ProgressStyle};
-use quinn::{ClientConfig, Connection, Endpoint, ServerConfig};
+use quinn::{AsyncUdpSocket, ClientConfig, Connection, Endpoint, EndpointConfig, ServerConfig};
 use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 use sha2::{Digest, Sha256};
-use std::net::SocketAddr;
-use std::path::Path;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use tokio::fs::File;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 use crate::cert::CertKeyPair;
+use crate::compress::{Compress, Compressor, Decompressor};
+use crate::history::{Direction, Transfer};
+use crate::meta::{Metadata, Preserve};
+use crate::mux::MuxSocket;
+use crate::sparse::Sink;
+use crate::tree::Symlinks;
+
+/// Longest user ID or file name accepted from a peer
+const MAX_NAME_LEN: u32 = 4096;
+/// Header flag: the sender waits for block signatures of the receiver's
+/// copy and sends the content as delta frames (see `delta`)
+const FLAG_DELTA: u8 = 1;
+/// Header flag: the size is unknown (`rxx send -`), the content comes in
+/// chunks of a u32 length and that many bytes, ended by an empty chunk
+const FLAG_STREAM: u8 = 2;
+/// Header flag: the file has holes and the content is sent as data and
+/// hole frames (see `sparse`)
+pub const FLAG_SPARSE: u8 = 4;
+/// Header flag: file metadata follows the flags, a u32 length and the
+/// encoded `meta::Metadata`
+const FLAG_META: u8 = 8;
+/// Header flag: a directory, sent as a manifest and the content of its
+/// files (see `tree`); the size is the total of the files
+const FLAG_DIR: u8 = 16;
+/// Header flag: everything after the header comes in compressed blocks
+/// (see `compress`); combines with the others
+const FLAG_ZSTD: u8 = 32;
+/// Largest chunk accepted in a stream
+const MAX_CHUNK: u32 = 1024 * 1024;
 
 pub fn create_server_config(cert_key: &CertKeyPair) -> Result<ServerConfig> {
-    println!("Creating QUIC server configuration...");
+    crate::info!("Creating QUIC server configuration...");
 
     // Parse certificate
     let cert_der = rustls_pemfile::certs(&mut cert_key.cert_pem.as_bytes())
@@ -27,8 +56,17 @@ pub fn create_server_config(cert_key: &CertKeyPair) -> Result<ServerConfig> {
     let cert_chain: Vec<CertificateDer> = cert_der;
     let private_key: PrivateKeyDer = key_der;
 
-    let mut server_config = ServerConfig::with_single_cert(cert_chain, private_key)
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, private_key)
         .context("Failed to create server config")?;
+    crypto.alpn_protocols = vec![crate::compress::ALPN.to_vec()];
+
+    let mut server_config = ServerConfig::with_crypto(Arc::new(
+        quinn::crypto::rustls::QuicServerConfig::try_from(crypto)
+            .context("Failed to create QUIC server config")?,
+    ));
 
     let mut transport = quinn::TransportConfig::default();
     transport.max_idle_timeout(Some(quinn::IdleTimeout::from(quinn::VarInt::from_u32(
@@ -38,23 +76,27 @@ pub fn create_server_config(cert_key: &CertKeyPair) -> Result<ServerConfig> {
     transport.stream_receive_window(quinn::VarInt::from_u32(1024 * 1024)); // 1MB per stream
     transport.receive_window(quinn::VarInt::from_u64(10 * 1024 * 1024).unwrap()); // 10MB connection
     transport.send_window(10 * 1024 * 1024); // 10MB
+    if crate::qos::background() {
+        transport.congestion_controller_factory(Arc::new(crate::qos::LedbatFactory));
+    }
     server_config.transport_config(Arc::new(transport));
 
-    println!("QUIC server configuration created");
+    crate::info!("QUIC server configuration created");
     Ok(server_config)
 }
 
 pub fn create_client_config() -> Result<ClientConfig> {
-    println!("Creating QUIC client configuration...");
+    crate::info!("Creating QUIC client configuration...");
 
     // Install default crypto provider
     let _ = rustls::crypto::ring::default_provider().install_default();
 
     // Create a client config that skips certificate verification
-    let crypto = rustls::ClientConfig::builder()
+    let mut crypto = rustls::ClientConfig::builder()
         .dangerous()
         .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
         .with_no_client_auth();
+    crypto.alpn_protocols = vec![crate::compress::ALPN.to_vec()];
 
     let mut client_config = ClientConfig::new(Arc::new(
         quinn::crypto::rustls::QuicClientConfig::try_from(crypto)
@@ -69,9 +111,12 @@ pub fn create_client_config() -> Result<ClientConfig> {
     transport.stream_receive_window(quinn::VarInt::from_u32(1024 * 1024)); // 1MB per stream
     transport.receive_window(quinn::VarInt::from_u64(10 * 1024 * 1024).unwrap()); // 10MB connection
     transport.send_window(10 * 1024 * 1024); // 10MB
+    if crate::qos::background() {
+        transport.congestion_controller_factory(Arc::new(crate::qos::LedbatFactory));
+    }
     client_config.transport_config(Arc::new(transport));
 
-    println!("QUIC client configuration created (skipping cert verification)");
+    crate::info!("QUIC client configuration created (skipping cert verification)");
     Ok(client_config)
 }
 
@@ -118,28 +163,45 @@ impl rustls::client::danger::ServerCertVerifier for SkipServerVerification {
     }
 }
 
-pub async fn start_server(config: ServerConfig, bind_addr: SocketAddr) -> Result<Endpoint> {
-    println!("Starting QUIC server on {}...", bind_addr);
+/// Create a QUIC endpoint on an already bound UDP socket.
+///
+/// Used with the socket left over from hole punching so the firewall pinhole
+/// stays valid; binding a fresh socket would race with the mapping closing.
+fn endpoint_from_socket(
+    server_config: Option<ServerConfig>,
+    socket: std::net::UdpSocket,
+) -> Result<Endpoint> {
+    let runtime = quinn::default_runtime().context("No async runtime found for QUIC")?;
+    Endpoint::new(EndpointConfig::default(), server_config, socket, runtime)
+        .context("Failed to create QUIC endpoint")
+}
 
+/// Start a long-lived QUIC server on a socket shared with hole punching.
+pub fn start_server(config: ServerConfig, mux: Arc<MuxSocket>) -> Result<Endpoint> {
+    crate::info!("Starting QUIC server on {}...", mux.local_addr()?);
+
+    let runtime = quinn::default_runtime().context("No async runtime found for QUIC")?;
     let endpoint =
-        Endpoint::server(config, bind_addr).context("Failed to create QUIC server endpoint")?;
+        Endpoint::new_with_abstract_socket(EndpointConfig::default(), Some(config), mux, runtime)
+            .context("Failed to create QUIC server endpoint")?;
 
-    println!("QUIC server started on {}", endpoint.local_addr()?);
+    crate::info!("QUIC server started on {}", endpoint.local_addr()?);
     Ok(endpoint)
 }
 
 pub async fn connect_client(
     config: ClientConfig,
-    bind_addr: SocketAddr,
+    socket: std::net::UdpSocket,
     server_addr: SocketAddr,
-) -> Result<quinn::Connection> {
-    println!(
+) -> Result<(Endpoint, Connection)> {
+    crate::info!(
         "Starting QUIC client from {} to {}...",
-        bind_addr, server_addr
+        socket.local_addr()?,
+        server_addr
     );
 
     let mut endpoint =
-        Endpoint::client(bind_addr).context("Failed to create QUIC client endpoint")?;
+        endpoint_from_socket(None, socket).context("Failed to create QUIC client endpoint")?;
 
     endpoint.set_default_client_config(config);
 
@@ -149,12 +211,223 @@ pub async fn connect_client(
         .await
         .context("Failed to establish connection")?;
 
-    println!("QUIC connection established to {}", server_addr);
-    Ok(connection)
+    crate::info!("QUIC connection established to {}", server_addr);
+    Ok((endpoint, connection))
+}
+
+/// Move a client endpoint to a new local address.
+///
+/// quinn migrates all of the endpoint's connections to the new socket, so
+/// transfers in flight continue instead of running into the idle timeout.
+/// The current port is kept when it is free on the new address.
+pub fn rebind(endpoint: &Endpoint, local_addr: SocketAddrV6) -> Result<()> {
+    let port = endpoint.local_addr()?.port();
+    let socket = std::net::UdpSocket::bind(SocketAddrV6::new(
+        *local_addr.ip(),
+        port,
+        0,
+        local_addr.scope_id(),
+    ))
+    .or_else(|_| std::net::UdpSocket::bind(local_addr))
+    .context("Failed to bind UDP socket for migration")?;
+    socket.set_nonblocking(true)?;
+
+    let new_addr = socket.local_addr()?;
+    endpoint
+        .rebind(socket)
+        .context("Failed to migrate QUIC endpoint")?;
+    crate::info!("Migrated QUIC connection to {}", new_addr);
+    Ok(())
+}
+
+/// Send a file to `peer` and record the transfer in the history.
+pub async fn send_file(
+    connection: &Connection,
+    file_path: &Path,
+    user_id: &str,
+    peer: &str,
+    delta: bool,
+    compress: Compress,
+) -> Result<()> {
+    let mut entry = Transfer::start(
+        Direction::Sent,
+        Some(connection.remote_address().to_string()),
+    );
+    entry.peer = Some(peer.to_string());
+    entry.local_file(file_path);
+    let result = send_stream(
+        connection,
+        Source::File(file_path, delta),
+        user_id,
+        compress,
+    )
+    .await;
+    if let Ok((total, hash)) = result {
+        entry.content(total, hash);
+    }
+    entry.finish(&result).await;
+    result.map(|_| ())
+}
+
+/// Send what is read from stdin to `peer` as a file called `name`.
+pub async fn send_stdin(
+    connection: &Connection,
+    name: &str,
+    user_id: &str,
+    peer: &str,
+    compress: Compress,
+) -> Result<()> {
+    let mut entry = Transfer::start(
+        Direction::Sent,
+        Some(connection.remote_address().to_string()),
+    );
+    entry.peer = Some(peer.to_string());
+    entry.stream(name);
+    let result = send_stream(connection, Source::Stdin(name), user_id, compress).await;
+    if let Ok((total, hash)) = result {
+        entry.content(total, hash);
+    }
+    entry.finish(&result).await;
+    result.map(|_| ())
+}
+
+/// Send the directory `dir` with everything in it to `peer`.
+pub async fn send_dir(
+    connection: &Connection,
+    dir: &Path,
+    user_id: &str,
+    peer: &str,
+    symlinks: Symlinks,
+    compress: Compress,
+) -> Result<()> {
+    let mut entry = Transfer::start(
+        Direction::Sent,
+        Some(connection.remote_address().to_string()),
+    );
+    entry.peer = Some(peer.to_string());
+    entry.local_file(dir);
+    let result = send_stream(connection, Source::Dir(dir, symlinks), user_id, compress).await;
+    if let Ok((total, hash)) = result {
+        entry.content(total, hash);
+    }
+    entry.finish(&result).await;
+    result.map(|_| ())
+}
+
+/// What `send_stream` sends
+enum Source<'a> {
+    /// A file, as a delta if the flag is set
+    File(&'a Path, bool),
+    /// A directory tree
+    Dir(&'a Path, Symlinks),
+    /// Standard input, under the given name
+    Stdin(&'a str),
+}
+
+async fn send_stream(
+    connection: &Connection,
+    source: Source<'_>,
+    user_id: &str,
+    compress: Compress,
+) -> Result<(u64, [u8; 32])> {
+    let compress = if compress != Compress::None && !crate::compress::negotiated(connection) {
+        crate::info!("Receiver does not support compression, sending uncompressed");
+        Compress::None
+    } else {
+        compress
+    };
+
+    crate::debug!("DEBUG [SEND]: Opening bidirectional stream...");
+    let (mut send, mut recv) = connection
+        .open_bi()
+        .await
+        .context("Failed to open bidirectional stream")?;
+    crate::debug!("DEBUG [SEND]: Bidirectional stream opened");
+
+    // Everything after opening the stream counts against the rate limit
+    let mut throttled = crate::qos::Throttle::new(&mut send);
+    let result = match source {
+        Source::File(file_path, delta) => {
+            let reply: Option<&mut (dyn AsyncRead + Unpin + Send)> =
+                if delta { Some(&mut recv) } else { None };
+            write_file(&mut throttled, file_path, user_id, reply, compress).await
+        }
+        Source::Dir(dir, symlinks) => {
+            write_dir(&mut throttled, dir, user_id, symlinks, compress).await
+        }
+        Source::Stdin(name) => {
+            write_stream(
+                &mut throttled,
+                &mut tokio::io::stdin(),
+                name,
+                user_id,
+                compress,
+            )
+            .await
+        }
+    };
+    let (total_sent, hash) = match result {
+        Ok(sent) => sent,
+        Err(e) => {
+            // Dropping the stream would finish it, and the receiver would
+            // only find out from the missing hash
+            let _ = send.reset(0u32.into());
+            return Err(e);
+        }
+    };
+
+    crate::debug!("DEBUG [SEND]: Calling send.finish()...");
+    send.finish().context("Failed to finish stream")?;
+    crate::debug!("DEBUG [SEND]: send.finish() completed");
+
+    // Wait for the stream to be fully acknowledged
+    crate::debug!("DEBUG [SEND]: Waiting for stream to be fully transmitted...");
+    send.stopped().await.context("Stream was stopped by peer")?;
+    crate::debug!("DEBUG [SEND]: Stream fully transmitted and acknowledged");
+
+    crate::info!(
+        "File sent successfully: {} bytes (SHA256: {})",
+        total_sent,
+        hex::encode(hash)
+    );
+
+    Ok((total_sent, hash))
 }
 
-pub async fn send_file(connection: &Connection, file_path: &Path, user_id: &str) -> Result<()> {
-    println!("Opening file {:?} for sending...", file_path);
+/// Resolves once nothing has been received from the peer for `limit`. With
+/// keep-alives every 10 seconds that means the peer is gone, long before
+/// the idle timeout notices.
+pub async fn stalled(connection: &Connection, limit: std::time::Duration) {
+    let mut received = connection.stats().udp_rx.datagrams;
+    let mut quiet_since = tokio::time::Instant::now();
+    loop {
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        let now = connection.stats().udp_rx.datagrams;
+        if now != received {
+            received = now;
+            quiet_since = tokio::time::Instant::now();
+        } else if quiet_since.elapsed() >= limit {
+            return;
+        }
+    }
+}
+
+/// Write a file in the transfer format: sender ID, file name and size,
+/// a flags byte, the content, then its SHA256.
+///
+/// Used for QUIC streams as well as for mailbox uploads, which carry the
+/// same bytes encrypted. With a `reply` stream from the receiver the
+/// content is sent as a delta against its copy, if it has one. Everything
+/// after the header is compressed as `compress` says. Returns the number of
+/// content bytes and the hash.
+pub async fn write_file<W: AsyncWrite + Unpin>(
+    send: &mut W,
+    file_path: &Path,
+    user_id: &str,
+    reply: Option<&mut (dyn AsyncRead + Unpin + Send)>,
+    compress: Compress,
+) -> Result<(u64, [u8; 32])> {
+    crate::info!("Opening file {:?} for sending...", file_path);
 
     let mut file = File::open(file_path)
         .await
@@ -166,57 +439,101 @@ pub async fn send_file(connection: &Connection, file_path: &Path, user_id: &str)
         .context("Failed to get file metadata")?;
     let file_size = metadata.len();
 
-    if file_size == 0 {
-        anyhow::bail!("Cannot send empty file");
-    }
-
     let file_name = file_path
         .file_name()
         .and_then(|n| n.to_str())
         .context("Invalid file name")?;
 
-    println!("Sending file: {} ({} bytes)", file_name, file_size);
+    crate::info!("Sending file: {} ({} bytes)", file_name, file_size);
 
-    crate::debug!("DEBUG [SEND]: Opening bidirectional stream...");
-    let (mut send, _recv) = connection
-        .open_bi()
-        .await
-        .context("Failed to open bidirectional stream")?;
-    crate::debug!("DEBUG [SEND]: Bidirectional stream opened");
+    // A delta only carries what differs anyway
+    let extents = match reply {
+        Some(_) => None,
+        None => crate::sparse::data_extents(file_path),
+    };
+    let flags = match (&reply, &extents) {
+        (Some(_), _) => FLAG_DELTA,
+        (None, Some(_)) => FLAG_SPARSE,
+        (None, None) => 0,
+    } | compress_flag(compress);
+    let metadata = Metadata::read(file_path)?;
+    write_header(send, user_id, file_name, file_size, flags, Some(&metadata)).await?;
+
+    let signatures = match reply {
+        Some(reply) => {
+            send.flush().await?;
+            crate::debug!("DEBUG [SEND]: Waiting for block signatures...");
+            crate::delta::read_signatures(reply)
+                .await
+                .context("Failed to read block signatures")?
+        }
+        None => None,
+    };
 
-    // Send metadata: user_id length (u32) + user_id + filename length (u32) + filename + file size (u64)
-    crate::debug!("DEBUG [SEND]: Sending user_id length: {}", user_id.len());
-    send.write_u32(user_id.len() as u32)
-        .await
-        .context("Failed to send user_id length")?;
-    crate::debug!("DEBUG [SEND]: Sending user_id: {}", user_id);
-    send.write_all(user_id.as_bytes())
-        .await
-        .context("Failed to send user_id")?;
-    crate::debug!("DEBUG [SEND]: Sending filename length: {}", file_name.len());
-    send.write_u32(file_name.len() as u32)
-        .await
-        .context("Failed to send filename length")?;
-    crate::debug!("DEBUG [SEND]: Sending filename: {}", file_name);
-    send.write_all(file_name.as_bytes())
-        .await
-        .context("Failed to send filename")?;
-    crate::debug!("DEBUG [SEND]: Sending file size: {}", file_size);
-    send.write_u64(file_size)
-        .await
-        .context("Failed to send file size")?;
+    let pb = progress_bar(file_size, "Sending");
+    let mut send = Compressor::new(send, compress);
 
-    println!("Metadata sent, streaming file content...");
+    if let Some(signatures) = signatures {
+        crate::info!(
+            "Receiver has a copy ({} blocks of {} bytes), sending delta...",
+            signatures.len(),
+            signatures.block_size
+        );
+        let stats = crate::delta::send_delta(&mut file, &mut send, &signatures, &pb).await?;
+        pb.finish_with_message("Sent");
+        if stats.total != file_size {
+            anyhow::bail!(
+                "File changed size while sending ({} -> {} bytes)",
+                file_size,
+                stats.total
+            );
+        }
+        crate::info!(
+            "Delta: {} of {} bytes sent as literal data",
+            stats.literal,
+            stats.total
+        );
+        send.write_all(&stats.hash)
+            .await
+            .context("Failed to send file hash")?;
+        send.finish().await?;
+        return Ok((stats.total, stats.hash));
+    }
 
-    // Create progress bar
-    let pb = ProgressBar::new(file_size);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({percent}%)")
-            .unwrap()
-            .progress_chars("#>-"),
-    );
-    pb.set_message("Sending");
+    match &extents {
+        Some(extents) => {
+            let data: u64 = extents.iter().map(|(_, len)| len).sum();
+            crate::info!(
+                "Sparse file, sending {} bytes of data and skipping the holes...",
+                data
+            );
+        }
+        None => crate::info!("Metadata sent, streaming file content..."),
+    }
+    let sent = write_content(&mut send, &mut file, file_size, extents.as_deref(), &pb).await?;
+    send.finish().await?;
+    pb.finish_with_message("Sent");
+    Ok(sent)
+}
+
+/// Write the `size` bytes of `file`, as sparse frames if `extents` are
+/// given, followed by their SHA256. Fails without writing the hash if the
+/// file changes size meanwhile. Returns the number of bytes and the hash.
+pub async fn write_content<W: AsyncWrite + Unpin>(
+    send: &mut W,
+    file: &mut File,
+    file_size: u64,
+    extents: Option<&[(u64, u64)]>,
+    pb: &ProgressBar,
+) -> Result<(u64, [u8; 32])> {
+    if let Some(extents) = extents {
+        let hash = crate::sparse::send_sparse(file, send, extents, file_size, pb).await?;
+        check_size_unchanged(file, file_size).await?;
+        send.write_all(&hash)
+            .await
+            .context("Failed to send file hash")?;
+        return Ok((file_size, hash));
+    }
 
     // Stream file content and calculate hash
     let mut hasher = Sha256::new();
@@ -225,8 +542,10 @@ pub async fn send_file(connection: &Connection, file_path: &Path, user_id: &str)
     let mut chunk_count = 0u64;
 
     crate::debug!("DEBUG [SEND]: Starting file content loop...");
+    // Exactly the announced size, anything appended meanwhile is not sent
+    let mut content = file.take(file_size);
     loop {
-        let n = file
+        let n = content
             .read(&mut buffer)
             .await
             .context("Failed to read from file")?;
@@ -253,7 +572,7 @@ pub async fn send_file(connection: &Connection, file_path: &Path, user_id: &str)
 
         total_sent += n as u64;
         chunk_count += 1;
-        pb.set_position(total_sent);
+        pb.inc(n as u64);
 
         crate::debug!(
             "DEBUG [SEND]: Chunk #{} sent, total_sent={}/{}",
@@ -267,7 +586,14 @@ pub async fn send_file(connection: &Connection, file_path: &Path, user_id: &str)
         "DEBUG [SEND]: File content loop completed, total_sent={}",
         total_sent
     );
-    pb.finish_with_message("Sent");
+    if total_sent < file_size {
+        anyhow::bail!(
+            "File shrank while sending ({} -> {} bytes)",
+            file_size,
+            total_sent
+        );
+    }
+    check_size_unchanged(file, file_size).await?;
 
     // Send hash
     let hash = hasher.finalize();
@@ -277,38 +603,325 @@ pub async fn send_file(connection: &Connection, file_path: &Path, user_id: &str)
         .context("Failed to send file hash")?;
     crate::debug!("DEBUG [SEND]: Hash sent successfully");
 
-    crate::debug!("DEBUG [SEND]: Calling send.finish()...");
-    send.finish().context("Failed to finish stream")?;
-    crate::debug!("DEBUG [SEND]: send.finish() completed");
-
-    // Wait for the stream to be fully acknowledged
-    crate::debug!("DEBUG [SEND]: Waiting for stream to be fully transmitted...");
-    send.stopped().await.context("Stream was stopped by peer")?;
-    crate::debug!("DEBUG [SEND]: Stream fully transmitted and acknowledged");
+    Ok((total_sent, hash.into()))
+}
 
-    println!(
-        "File sent successfully: {} bytes (SHA256: {:x})",
-        total_sent, hash
+/// Write the directory `dir` in the transfer format: the header of the
+/// directory itself, then its manifest and the content of its files.
+/// Returns the number of content bytes and the SHA256 of the manifest.
+pub async fn write_dir<W: AsyncWrite + Unpin>(
+    send: &mut W,
+    dir: &Path,
+    user_id: &str,
+    symlinks: Symlinks,
+    compress: Compress,
+) -> Result<(u64, [u8; 32])> {
+    let name = dir
+        .canonicalize()
+        .with_context(|| format!("Failed to open directory: {:?}", dir))?
+        .file_name()
+        .and_then(|n| n.to_str())
+        .context("Invalid directory name")?
+        .to_string();
+    let entries = crate::tree::scan(dir, symlinks)?;
+    let size = entries
+        .iter()
+        .map(|entry| match entry {
+            crate::tree::Entry::File { size, .. } => *size,
+            _ => 0,
+        })
+        .sum();
+    crate::info!(
+        "Sending directory: {} ({} entries, {} bytes)",
+        name,
+        entries.len(),
+        size
     );
 
+    let metadata = Metadata::read(dir)?;
+    let flags = FLAG_DIR | compress_flag(compress);
+    write_header(send, user_id, &name, size, flags, Some(&metadata)).await?;
+    let pb = progress_bar(size, "Sending");
+    let mut send = Compressor::new(send, compress);
+    let sent = crate::tree::send_tree(&mut send, &entries, &pb).await?;
+    send.finish().await?;
+    pb.finish_with_message("Sent");
+    Ok(sent)
+}
+
+/// The header flag announcing content compressed with `compress`
+fn compress_flag(compress: Compress) -> u8 {
+    match compress {
+        Compress::None => 0,
+        Compress::Zstd | Compress::Auto => FLAG_ZSTD,
+    }
+}
+
+/// Fail if the file no longer has the size announced to the receiver, which
+/// then gets no hash and discards what it received.
+async fn check_size_unchanged(file: &File, announced: u64) -> Result<()> {
+    let now = file
+        .metadata()
+        .await
+        .context("Failed to get file metadata")?
+        .len();
+    if now != announced {
+        anyhow::bail!(
+            "File changed size while sending ({} -> {} bytes)",
+            announced,
+            now
+        );
+    }
     Ok(())
 }
 
-pub async fn receive_file(
+/// Write what `read_header` reads.
+async fn write_header<W: AsyncWrite + Unpin>(
+    send: &mut W,
+    user_id: &str,
+    file_name: &str,
+    size: u64,
+    flags: u8,
+    metadata: Option<&Metadata>,
+) -> Result<()> {
+    // Send metadata: user_id length (u32) + user_id + filename length (u32) + filename + file size (u64)
+    crate::debug!("DEBUG [SEND]: Sending user_id length: {}", user_id.len());
+    send.write_u32(user_id.len() as u32)
+        .await
+        .context("Failed to send user_id length")?;
+    crate::debug!("DEBUG [SEND]: Sending user_id: {}", user_id);
+    send.write_all(user_id.as_bytes())
+        .await
+        .context("Failed to send user_id")?;
+    crate::debug!("DEBUG [SEND]: Sending filename length: {}", file_name.len());
+    send.write_u32(file_name.len() as u32)
+        .await
+        .context("Failed to send filename length")?;
+    crate::debug!("DEBUG [SEND]: Sending filename: {}", file_name);
+    send.write_all(file_name.as_bytes())
+        .await
+        .context("Failed to send filename")?;
+    crate::debug!("DEBUG [SEND]: Sending file size: {}", size);
+    send.write_u64(size)
+        .await
+        .context("Failed to send file size")?;
+    let Some(metadata) = metadata else {
+        send.write_u8(flags).await.context("Failed to send flags")?;
+        return Ok(());
+    };
+    send.write_u8(flags | FLAG_META)
+        .await
+        .context("Failed to send flags")?;
+    let encoded = metadata.encode();
+    send.write_u32(encoded.len() as u32)
+        .await
+        .context("Failed to send file metadata")?;
+    send.write_all(&encoded)
+        .await
+        .context("Failed to send file metadata")?;
+    Ok(())
+}
+
+/// Write `source` in the transfer format without knowing its size up
+/// front: the content goes out in chunks as it is read, followed by its
+/// SHA256. Returns the number of content bytes and the hash.
+pub async fn write_stream<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+    send: &mut W,
+    source: &mut R,
+    file_name: &str,
+    user_id: &str,
+    compress: Compress,
+) -> Result<(u64, [u8; 32])> {
+    check_file_name(file_name)?;
+    crate::info!("Sending {} from a stream...", file_name);
+    let flags = FLAG_STREAM | compress_flag(compress);
+    write_header(send, user_id, file_name, 0, flags, None).await?;
+    let mut send = Compressor::new(send, compress);
+
+    let pb = spinner("Sending");
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut total_sent = 0u64;
+    loop {
+        let n = source
+            .read(&mut buffer)
+            .await
+            .context("Failed to read input")?;
+        send.write_u32(n as u32)
+            .await
+            .context("Failed to send chunk length")?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        send.write_all(&buffer[..n])
+            .await
+            .context("Failed to send chunk")?;
+        // Pass on what there is rather than wait for a full block
+        send.flush().await.context("Failed to send chunk")?;
+        total_sent += n as u64;
+        pb.set_position(total_sent);
+    }
+    pb.finish_with_message("Sent");
+
+    let hash = hasher.finalize();
+    send.write_all(&hash)
+        .await
+        .context("Failed to send file hash")?;
+    send.finish().await?;
+    Ok((total_sent, hash.into()))
+}
+
+/// Receive files until the sender closes the connection. Most senders send
+/// one file per connection, `rxx watch` keeps sending over the same one.
+/// Returns the number of files received.
+pub async fn receive_files(
     connection: &Connection,
+    peer: Option<&str>,
     output_dir: &Path,
     user_folder: bool,
+    keep: Preserve,
     config: &crate::config::Config,
-) -> Result<()> {
-    println!("Waiting for incoming file stream...");
+) -> Result<usize> {
+    crate::info!("Waiting for incoming file stream...");
 
-    crate::debug!("DEBUG [RECV]: Calling accept_bi()...");
-    let (_send, mut recv) = connection
-        .accept_bi()
-        .await
-        .context("Failed to accept bidirectional stream")?;
-    crate::debug!("DEBUG [RECV]: Bidirectional stream accepted");
+    let mut received = 0;
+    loop {
+        crate::debug!("DEBUG [RECV]: Calling accept_bi()...");
+        let (mut send, mut recv) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(quinn::ConnectionError::ApplicationClosed(_)) if received > 0 => {
+                return Ok(received)
+            }
+            Err(e) => return Err(e).context("Failed to accept bidirectional stream"),
+        };
+        crate::debug!("DEBUG [RECV]: Bidirectional stream accepted");
+
+        let mut entry = Transfer::start(
+            Direction::Received,
+            Some(connection.remote_address().to_string()),
+        );
+        entry.expected_peer(peer);
+        let result = async {
+            let header = read_header(&mut recv).await?;
+            entry.header(&header);
+            header.check_sender(peer)?;
+            let reply: &mut (dyn AsyncWrite + Unpin + Send) = &mut send;
+            read_content(
+                &mut recv,
+                Some(reply),
+                header,
+                output_dir,
+                user_folder,
+                keep,
+                config,
+            )
+            .await
+        }
+        .await;
+        if let Ok(file) = &result {
+            entry.received(file);
+        }
+        entry.finish(&result).await;
+        result?;
+        received += 1;
+    }
+}
+
+/// Progress of a transfer of unknown size
+fn spinner(message: &'static str) -> ProgressBar {
+    let pb = ProgressBar::new_spinner();
+    pb.set_style(
+        ProgressStyle::default_spinner()
+            .template("{msg} {spinner} {bytes} ({bytes_per_sec})")
+            .unwrap(),
+    );
+    pb.set_message(message);
+    pb
+}
+
+/// Progress of receiving content announced with `size` and `flags`
+fn receive_bar(size: u64, flags: u8) -> ProgressBar {
+    if flags & FLAG_STREAM != 0 {
+        spinner("Receiving")
+    } else {
+        progress_bar(size, "Receiving")
+    }
+}
+
+pub fn progress_bar(len: u64, message: &'static str) -> ProgressBar {
+    let pb = ProgressBar::new(len);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({percent}%)")
+            .unwrap()
+            .progress_chars("#>-"),
+    );
+    pb.set_message(message);
+    pb
+}
+
+/// Reject names that would place a received file outside the output directory.
+pub fn check_file_name(name: &str) -> Result<()> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => anyhow::bail!("Refusing unsafe file name {:?}", name),
+    }
+}
+
+/// What a sender announces before the content of a file
+pub struct Header {
+    /// User ID the sender claims
+    pub sender_id: String,
+    pub file_name: String,
+    pub size: u64,
+    flags: u8,
+    metadata: Option<Metadata>,
+}
+
+impl Header {
+    /// Refuse a sender claiming to be someone other than the `peer` we
+    /// punched towards.
+    pub fn check_sender(&self, peer: Option<&str>) -> Result<()> {
+        match peer {
+            Some(peer) if !self.sender_id.eq_ignore_ascii_case(peer) => anyhow::bail!(
+                "Sender claims to be '{}' but we expected '{}'",
+                self.sender_id,
+                peer
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A file stored by `read_file`
+pub struct Received {
+    pub sender_id: String,
+    pub path: PathBuf,
+    pub size: u64,
+    pub sha256: [u8; 32],
+}
+
+/// Read a file written by `write_file` and store it in `output_dir`,
+/// verifying its SHA256.
+///
+/// `reply` is the way back to the sender, needed to answer a delta request;
+/// without one such requests are refused.
+pub async fn read_file<R: AsyncRead + Unpin>(
+    recv: &mut R,
+    reply: Option<&mut (dyn AsyncWrite + Unpin + Send)>,
+    output_dir: &Path,
+    user_folder: bool,
+    keep: Preserve,
+    config: &crate::config::Config,
+) -> Result<Received> {
+    let header = read_header(recv).await?;
+    read_content(recv, reply, header, output_dir, user_folder, keep, config).await
+}
 
+/// Read the part of a transfer that precedes the content.
+pub async fn read_header<R: AsyncRead + Unpin>(recv: &mut R) -> Result<Header> {
     // Receive metadata
     crate::debug!("DEBUG [RECV]: Reading user_id length...");
     let user_id_len = recv
@@ -316,6 +929,9 @@ pub async fn receive_file(
         .await
         .context("Failed to read user_id length")?;
     crate::debug!("DEBUG [RECV]: User_id length: {}", user_id_len);
+    if user_id_len > MAX_NAME_LEN {
+        anyhow::bail!("User ID too long ({} bytes)", user_id_len);
+    }
 
     let mut user_id_bytes = vec![0u8; user_id_len as usize];
     crate::debug!("DEBUG [RECV]: Reading user_id bytes...");
@@ -332,6 +948,9 @@ pub async fn receive_file(
         .await
         .context("Failed to read filename length")?;
     crate::debug!("DEBUG [RECV]: Filename length: {}", filename_len);
+    if filename_len > MAX_NAME_LEN {
+        anyhow::bail!("Filename too long ({} bytes)", filename_len);
+    }
 
     let mut filename_bytes = vec![0u8; filename_len as usize];
     crate::debug!("DEBUG [RECV]: Reading filename bytes...");
@@ -341,18 +960,85 @@ pub async fn receive_file(
 
     let filename = String::from_utf8(filename_bytes).context("Invalid UTF-8 in filename")?;
     crate::debug!("DEBUG [RECV]: Filename: {}", filename);
+    check_file_name(&filename)?;
 
     crate::debug!("DEBUG [RECV]: Reading file size...");
     let file_size = recv.read_u64().await.context("Failed to read file size")?;
     crate::debug!("DEBUG [RECV]: File size: {}", file_size);
+    let flags = recv.read_u8().await.context("Failed to read flags")?;
+    if flags & !(FLAG_DELTA | FLAG_STREAM | FLAG_SPARSE | FLAG_META | FLAG_DIR | FLAG_ZSTD) != 0 {
+        anyhow::bail!("Unsupported transfer flags {:#x}", flags);
+    }
+    if (flags & (FLAG_DELTA | FLAG_STREAM | FLAG_SPARSE | FLAG_DIR)).count_ones() > 1 {
+        anyhow::bail!("Conflicting transfer flags {:#x}", flags);
+    }
 
-    println!(
-        "Receiving file: {} ({} bytes) from {}",
-        filename, file_size, sender_id
-    );
+    let metadata = if flags & FLAG_META != 0 {
+        let len = recv
+            .read_u32()
+            .await
+            .context("Failed to read metadata length")?;
+        if len > crate::meta::MAX_ENCODED {
+            anyhow::bail!("File metadata too long ({} bytes)", len);
+        }
+        let mut encoded = vec![0u8; len as usize];
+        recv.read_exact(&mut encoded)
+            .await
+            .context("Failed to read file metadata")?;
+        Some(Metadata::decode(&encoded).context("Invalid file metadata")?)
+    } else {
+        None
+    };
+
+    Ok(Header {
+        sender_id,
+        file_name: filename,
+        size: file_size,
+        flags,
+        metadata,
+    })
+}
+
+/// Read the content of the file announced by `header`; see `read_file`.
+pub async fn read_content<R: AsyncRead + Unpin>(
+    recv: &mut R,
+    reply: Option<&mut (dyn AsyncWrite + Unpin + Send)>,
+    header: Header,
+    output_dir: &Path,
+    user_folder: bool,
+    keep: Preserve,
+    config: &crate::config::Config,
+) -> Result<Received> {
+    let Header {
+        sender_id,
+        file_name: filename,
+        size: file_size,
+        flags,
+        metadata,
+    } = header;
+    let mut recv = Decompressor::new(recv, flags & FLAG_ZSTD != 0);
+
+    if flags & FLAG_DIR != 0 {
+        crate::info!(
+            "Receiving directory: {} ({} bytes) from {}",
+            filename,
+            file_size,
+            sender_id
+        );
+    } else if flags & FLAG_STREAM != 0 {
+        crate::info!("Receiving file: {} (streamed) from {}", filename, sender_id);
+    } else {
+        crate::info!(
+            "Receiving file: {} ({} bytes) from {}",
+            filename,
+            file_size,
+            sender_id
+        );
+    }
 
     // Determine output directory
     let final_output_dir = if user_folder {
+        check_file_name(&sender_id)?;
         output_dir.join(&sender_id)
     } else {
         output_dir.to_path_buf()
@@ -363,170 +1049,420 @@ pub async fn receive_file(
         tokio::fs::create_dir_all(&final_output_dir)
             .await
             .context("Failed to create output directory")?;
-        println!("Created output directory: {:?}", final_output_dir);
+        crate::info!("Created output directory: {:?}", final_output_dir);
     }
 
-    // Create output file
     let output_path = final_output_dir.join(&filename);
-    let mut file = File::create(&output_path)
+
+    if flags & FLAG_DIR != 0 {
+        let (total, sha256) =
+            crate::tree::receive_tree(&mut recv, &output_path, keep.metadata, keep.xattrs).await?;
+        recv.finish().await?;
+        preserve(metadata.as_ref(), &output_path, keep);
+        config.execute_file_received_hook(&sender_id, &filename, total);
+        return Ok(Received {
+            sender_id,
+            path: output_path,
+            size: total,
+            sha256,
+        });
+    }
+
+    if flags & FLAG_DELTA != 0 {
+        let Some(reply) = reply else {
+            anyhow::bail!("Delta transfers are not supported here");
+        };
+        let basis = match File::open(&output_path).await {
+            Ok(mut basis) if output_path.is_file() => {
+                let len = basis.metadata().await?.len();
+                let signatures = crate::delta::signatures(&mut basis, len)
+                    .await
+                    .context("Failed to read existing copy")?;
+                Some((basis, signatures))
+            }
+            _ => None,
+        };
+        crate::delta::write_signatures(reply, basis.as_ref().map(|(_, s)| s))
+            .await
+            .context("Failed to send block signatures")?;
+
+        if let Some((mut basis, signatures)) = basis {
+            crate::info!("Updating existing {:?} from a delta...", output_path);
+            let (stored, sha256) = receive_delta(
+                &mut recv,
+                &mut basis,
+                &signatures,
+                &output_path,
+                &filename,
+                file_size,
+            )
+            .await?;
+            recv.finish().await?;
+            crate::info!("File received successfully: {} bytes", stored);
+            preserve(metadata.as_ref(), &output_path, keep);
+            config.execute_file_received_hook(&sender_id, &filename, file_size);
+            return Ok(Received {
+                sender_id,
+                path: output_path,
+                size: stored,
+                sha256,
+            });
+        }
+    }
+
+    // Received next to the target, which an existing copy stays in until
+    // the new one is verified
+    let temp_path = part_path(&output_path, &filename);
+    let mut file = File::create(&temp_path)
         .await
-        .context(format!("Failed to create output file: {:?}", output_path))?;
+        .context(format!("Failed to create output file: {:?}", temp_path))?;
+
+    crate::info!("Writing to {:?}...", output_path);
+
+    let result = async {
+        let pb = receive_bar(file_size, flags);
+        let received = read_body(&mut recv, &mut file, file_size, flags, &pb).await?;
+        recv.finish().await?;
+        pb.finish_with_message("Received");
+        file.flush().await.context("Failed to flush file")?;
+        drop(file);
+        tokio::fs::rename(&temp_path, &output_path)
+            .await
+            .context(format!("Failed to replace {:?}", output_path))?;
+        Ok(received)
+    }
+    .await;
+    let (total_received, sha256) = match result {
+        Ok(received) => received,
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+    };
 
-    println!("Writing to {:?}...", output_path);
+    crate::info!("File received successfully: {} bytes", total_received);
+    crate::info!("Integrity verified (SHA256: {})", hex::encode(sha256));
+    preserve(metadata.as_ref(), &output_path, keep);
 
-    // Create progress bar
-    let pb = ProgressBar::new(file_size);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{msg} [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({percent}%)")
-            .unwrap()
-            .progress_chars("#>-"),
-    );
-    pb.set_message("Receiving");
+    config.execute_file_received_hook(&sender_id, &filename, total_received);
+
+    Ok(Received {
+        sender_id,
+        path: output_path,
+        size: total_received,
+        sha256,
+    })
+}
+
+/// Apply the sender's metadata to a received file if the receiver asked
+/// for it (`rxx receive --preserve`). Failing to only leaves a warning.
+fn preserve(metadata: Option<&Metadata>, path: &Path, keep: Preserve) {
+    let (Some(metadata), true) = (metadata, keep.metadata) else {
+        return;
+    };
+    if let Err(e) = metadata.apply(path, keep.xattrs) {
+        crate::info!("Could not preserve metadata: {:#}", e);
+    }
+}
+
+/// Copy plain content of `size` bytes, chunks if `flags` has `FLAG_STREAM`
+/// or sparse frames with `FLAG_SPARSE`, from `recv` to `out` and check it
+/// against the SHA256 that follows. Returns the number of bytes and the
+/// hash.
+pub async fn read_body<R: AsyncRead + Unpin, W: Sink + ?Sized>(
+    recv: &mut R,
+    out: &mut W,
+    size: u64,
+    flags: u8,
+    pb: &ProgressBar,
+) -> Result<(u64, [u8; 32])> {
+    let stream = flags & FLAG_STREAM != 0;
+    if flags & FLAG_SPARSE != 0 {
+        let (total, computed_hash) = crate::sparse::receive_sparse(recv, out, size, pb).await?;
+        if total != size {
+            anyhow::bail!(
+                "File size mismatch: expected {} bytes, received {} bytes",
+                size,
+                total
+            );
+        }
+        return check_hash(recv, total, computed_hash).await;
+    }
 
-    // Receive file content and calculate hash
     let mut hasher = Sha256::new();
     let mut buffer = vec![0u8; 64 * 1024]; // 64KB chunks
     let mut total_received = 0u64;
-    let mut chunk_count = 0u64;
+    // Bytes left in the file or the current chunk
+    let mut remaining = if stream { 0 } else { size };
 
     crate::debug!("DEBUG [RECV]: Starting receive loop...");
     loop {
-        crate::debug!(
-            "DEBUG [RECV]: Calling recv.read() (chunk #{}, total_received={}/{})",
-            chunk_count,
-            total_received,
-            file_size
-        );
-        match recv.read(&mut buffer).await {
-            Ok(Some(n)) => {
-                chunk_count += 1;
-                crate::debug!("DEBUG [RECV]: recv.read() returned {} bytes (chunk #{}, total_received={}, file_size={})", n, chunk_count, total_received, file_size);
-
-                if total_received + n as u64 > file_size {
-                    crate::debug!(
-                        "DEBUG [RECV]: Detected hash in this chunk (total would be {} > {})",
-                        total_received + n as u64,
-                        file_size
-                    );
-                    // This is the hash, not file data
-                    let hash_start = (file_size - total_received) as usize;
-                    crate::debug!(
-                        "DEBUG [RECV]: hash_start={}, remaining_file_data={}",
-                        hash_start,
-                        hash_start
-                    );
-
-                    if hash_start > 0 {
-                        crate::debug!(
-                            "DEBUG [RECV]: Writing final {} bytes of file data",
-                            hash_start
-                        );
-                        hasher.update(&buffer[..hash_start]);
-                        file.write_all(&buffer[..hash_start])
-                            .await
-                            .context("Failed to write to file")?;
-                        total_received += hash_start as u64;
-                    }
-                    pb.set_position(total_received);
-
-                    // Read the hash
-                    let mut received_hash = buffer[hash_start..n].to_vec();
-                    crate::debug!(
-                        "DEBUG [RECV]: Extracted {} bytes of hash from current buffer",
-                        received_hash.len()
-                    );
-                    let remaining = 32 - received_hash.len();
-                    if remaining > 0 {
-                        crate::debug!(
-                            "DEBUG [RECV]: Need to read {} more bytes for complete hash",
-                            remaining
-                        );
-                        let mut hash_buf = vec![0u8; remaining];
-                        recv.read_exact(&mut hash_buf)
-                            .await
-                            .context("Failed to read complete hash")?;
-                        received_hash.extend_from_slice(&hash_buf);
-                        crate::debug!("DEBUG [RECV]: Complete hash received");
-                    }
-
-                    pb.finish_with_message("Received");
-                    file.flush().await.context("Failed to flush file")?;
-
-                    // Verify hash
-                    let computed_hash = hasher.finalize();
-                    crate::debug!("DEBUG [RECV]: Computed hash: {:x}", computed_hash);
-                    crate::debug!(
-                        "DEBUG [RECV]: Received hash: {}",
-                        hex::encode(&received_hash)
-                    );
-
-                    if computed_hash.as_slice() != received_hash.as_slice() {
-                        anyhow::bail!(
-                            "File integrity check failed: hash mismatch\nExpected: {:x}\nReceived: {}",
-                            computed_hash,
-                            hex::encode(&received_hash)
-                        );
-                    }
-
-                    println!("File received successfully: {} bytes", total_received);
-                    println!("Integrity verified (SHA256: {:x})", computed_hash);
-
-                    if total_received != file_size {
-                        anyhow::bail!(
-                            "File size mismatch: expected {} bytes, received {} bytes",
-                            file_size,
-                            total_received
-                        );
-                    }
-
-                    config.execute_file_received_hook(&sender_id, &filename, file_size);
-
-                    return Ok(());
-                }
-
-                crate::debug!("DEBUG [RECV]: Writing {} bytes to file", n);
-                hasher.update(&buffer[..n]);
-                file.write_all(&buffer[..n])
-                    .await
-                    .context("Failed to write to file")?;
-
-                total_received += n as u64;
-                pb.set_position(total_received);
-                crate::debug!(
-                    "DEBUG [RECV]: Chunk #{} written, total_received={}/{}",
-                    chunk_count,
-                    total_received,
-                    file_size
-                );
+        if remaining == 0 {
+            if !stream {
+                break;
             }
-            Ok(None) => {
-                crate::debug!("DEBUG [RECV]: recv.read() returned None (stream finished), total_received={}, file_size={}", total_received, file_size);
+            let len = recv
+                .read_u32()
+                .await
+                .context("Failed to read chunk length")?;
+            if len == 0 {
                 break;
             }
-            Err(e) => {
-                crate::debug!("DEBUG [RECV]: recv.read() returned error: {:?}", e);
-                return Err(e).context("Failed to read from stream");
+            if len > MAX_CHUNK {
+                anyhow::bail!("Chunk too large ({} bytes)", len);
+            }
+            remaining = len as u64;
+        }
+
+        let want = remaining.min(buffer.len() as u64) as usize;
+        let n = recv
+            .read(&mut buffer[..want])
+            .await
+            .context("Failed to read from stream")?;
+        if n == 0 {
+            if stream {
+                anyhow::bail!("Stream ended in the middle of a chunk");
             }
+            anyhow::bail!(
+                "File size mismatch: expected {} bytes, received {} bytes",
+                size,
+                total_received
+            );
         }
+
+        hasher.update(&buffer[..n]);
+        out.write_all(&buffer[..n])
+            .await
+            .context("Failed to write to file")?;
+        total_received += n as u64;
+        remaining -= n as u64;
+        pb.inc(n as u64);
     }
 
-    println!(
-        "DEBUG [RECV]: Exited receive loop, total_received={}, file_size={}",
-        total_received, file_size
+    check_hash(recv, total_received, hasher.finalize().into()).await
+}
+
+/// Read the SHA256 that ends a transfer and compare it with ours.
+async fn check_hash<R: AsyncRead + Unpin>(
+    recv: &mut R,
+    total_received: u64,
+    computed_hash: [u8; 32],
+) -> Result<(u64, [u8; 32])> {
+    let mut received_hash = [0u8; 32];
+    recv.read_exact(&mut received_hash)
+        .await
+        .context("Failed to read complete hash")?;
+    crate::debug!(
+        "DEBUG [RECV]: Computed hash: {}, received hash: {}",
+        hex::encode(computed_hash),
+        hex::encode(received_hash)
     );
-    pb.finish_with_message("Received");
-    file.flush().await.context("Failed to flush file")?;
+    if computed_hash != received_hash {
+        anyhow::bail!(
+            "File integrity check failed: hash mismatch\nExpected: {}\nReceived: {}",
+            hex::encode(computed_hash),
+            hex::encode(received_hash)
+        );
+    }
+    Ok((total_received, computed_hash))
+}
 
-    println!("File received successfully: {} bytes", total_received);
+/// Receive one file and write its content to `out` instead of storing it
+/// (`rxx receive --stdout`), then wait for the sender to hang up.
+///
+/// The content is passed on as it arrives, so a failed integrity check
+/// only shows once everything has been written.
+pub async fn receive_to<W: Sink + ?Sized>(
+    connection: &Connection,
+    peer: Option<&str>,
+    out: &mut W,
+) -> Result<()> {
+    let (mut send, mut recv) = connection
+        .accept_bi()
+        .await
+        .context("Failed to accept bidirectional stream")?;
 
-    if total_received != file_size {
-        anyhow::bail!(
-            "File size mismatch: expected {} bytes, received {} bytes",
-            file_size,
-            total_received
+    let mut entry = Transfer::start(
+        Direction::Received,
+        Some(connection.remote_address().to_string()),
+    );
+    entry.expected_peer(peer);
+    let result = async {
+        let header = read_header(&mut recv).await?;
+        entry.header(&header);
+        header.check_sender(peer)?;
+        if header.flags & FLAG_DIR != 0 {
+            anyhow::bail!(
+                "{} sent a directory, which cannot go to stdout",
+                header.sender_id
+            );
+        }
+        crate::info!(
+            "Receiving {} from {} to stdout",
+            header.file_name,
+            header.sender_id
         );
+        if header.flags & FLAG_DELTA != 0 {
+            // No copy to compare against, ask for everything
+            crate::delta::write_signatures(&mut send, None)
+                .await
+                .context("Failed to answer delta request")?;
+        }
+        let pb = receive_bar(header.size, header.flags);
+        let mut recv = Decompressor::new(&mut recv, header.flags & FLAG_ZSTD != 0);
+        let (total, sha256) = read_body(&mut recv, out, header.size, header.flags, &pb).await?;
+        recv.finish().await?;
+        pb.finish_with_message("Received");
+        out.flush().await.context("Failed to flush stdout")?;
+        crate::info!(
+            "File received successfully: {} bytes (SHA256: {})",
+            total,
+            hex::encode(sha256)
+        );
+        Ok((total, sha256))
+    }
+    .await;
+    if let Ok((total, sha256)) = result {
+        entry.content(total, sha256);
     }
+    entry.finish(&result).await;
+    result?;
 
+    // Let the sender see its stream acknowledged before we exit
+    let _ = tokio::time::timeout(std::time::Duration::from_secs(10), connection.closed()).await;
     Ok(())
 }
+
+/// Where a file is received before it replaces `output_path`.
+fn part_path(output_path: &Path, filename: &str) -> PathBuf {
+    output_path.with_file_name(format!(
+        "{}{}.part",
+        crate::share::INTERNAL_PREFIX,
+        filename
+    ))
+}
+
+/// Rebuild a file from delta frames next to the existing copy, replacing
+/// it only once the result is verified. Returns the number of bytes stored
+/// and their SHA256.
+async fn receive_delta<R: AsyncRead + Unpin>(
+    recv: &mut R,
+    basis: &mut File,
+    signatures: &crate::delta::Signatures,
+    output_path: &Path,
+    filename: &str,
+    file_size: u64,
+) -> Result<(u64, [u8; 32])> {
+    let temp_path = part_path(output_path, filename);
+    let result = async {
+        let mut out = File::create(&temp_path)
+            .await
+            .context(format!("Failed to create {:?}", temp_path))?;
+        let pb = progress_bar(file_size, "Receiving");
+        let (total, computed_hash) =
+            crate::delta::apply_delta(recv, basis, &mut out, signatures, &pb).await?;
+        pb.finish_with_message("Received");
+        out.flush().await.context("Failed to flush file")?;
+
+        let mut received_hash = [0u8; 32];
+        recv.read_exact(&mut received_hash)
+            .await
+            .context("Failed to read complete hash")?;
+        if computed_hash != received_hash {
+            anyhow::bail!(
+                "File integrity check failed: hash mismatch\nExpected: {}\nReceived: {}",
+                hex::encode(computed_hash),
+                hex::encode(received_hash)
+            );
+        }
+        if total != file_size {
+            anyhow::bail!(
+                "File size mismatch: expected {} bytes, received {} bytes",
+                file_size,
+                total
+            );
+        }
+        crate::info!(
+            "Integrity verified (SHA256: {})",
+            hex::encode(computed_hash)
+        );
+        tokio::fs::rename(&temp_path, output_path)
+            .await
+            .context(format!("Failed to replace {:?}", output_path))?;
+        Ok((total, computed_hash))
+    }
+    .await;
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_file_name() {
+        assert!(check_file_name("report.pdf").is_ok());
+        assert!(check_file_name("..hidden").is_ok());
+        assert!(check_file_name("").is_err());
+        assert!(check_file_name(".").is_err());
+        assert!(check_file_name("..").is_err());
+        assert!(check_file_name("../report.pdf").is_err());
+        assert!(check_file_name("/etc/passwd").is_err());
+        assert!(check_file_name("docs/report.pdf").is_err());
+    }
+
+    #[test]
+    fn test_check_sender() {
+        let header = Header {
+            sender_id: "Alice".to_string(),
+            file_name: "report.pdf".to_string(),
+            size: 0,
+            flags: 0,
+            metadata: None,
+        };
+        assert!(header.check_sender(Some("alice")).is_ok());
+        assert!(header.check_sender(None).is_ok());
+        assert!(header.check_sender(Some("bob")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_roundtrip() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let mut wire = Vec::new();
+        let (total, hash) = write_stream(
+            &mut wire,
+            &mut data.as_slice(),
+            "data.bin",
+            "alice",
+            Compress::None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(total, data.len() as u64);
+
+        let mut recv = wire.as_slice();
+        let header = read_header(&mut recv).await.unwrap();
+        assert_eq!(header.sender_id, "alice");
+        assert_eq!(header.file_name, "data.bin");
+        assert_eq!(header.flags, FLAG_STREAM);
+        let mut out = Vec::new();
+        let pb = ProgressBar::hidden();
+        let (received, sha256) = read_body(&mut recv, &mut out, header.size, header.flags, &pb)
+            .await
+            .unwrap();
+        assert_eq!((received, sha256), (total, hash));
+        assert_eq!(out, data);
+        assert!(recv.is_empty());
+
+        // A flipped byte fails the integrity check
+        let last = wire.len() - 40;
+        wire[last] ^= 1;
+        let mut recv = wire.as_slice();
+        let header = read_header(&mut recv).await.unwrap();
+        let result = read_body(&mut recv, &mut Vec::new(), header.size, header.flags, &pb).await;
+        assert!(result.is_err());
+    }
+}
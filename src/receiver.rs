@@ -0,0 +1,770 @@
+use anyhow::{Context, Result};
+use quinn::{Endpoint, ServerConfig};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWrite;
+use tokio::sync::{broadcast, mpsc, watch, Notify, OwnedMutexGuard, Semaphore, SemaphorePermit};
+
+use crate::config::Config;
+use crate::meta::Preserve;
+use crate::mux::MuxSocket;
+use crate::net::ResolvedPeer;
+use crate::share::{self, Share};
+use crate::udp::PunchOptions;
+use crate::{mailbox, net, quic, udp};
+
+/// The socket the receiver's endpoint is currently bound to
+type Bound = watch::Receiver<Arc<MuxSocket>>;
+
+/// How long after punching towards a peer its QUIC connection is accepted
+const ACCEPT_WINDOW_SECS: u64 = 300;
+pub const DEFAULT_MAX_CONCURRENT: usize = 4;
+
+#[derive(Clone)]
+pub struct ReceiveOptions {
+    pub output_base: PathBuf,
+    pub user_folder: bool,
+    pub punch: PunchOptions,
+    /// Transfers handled at the same time; more connections wait for a slot
+    pub max_concurrent: usize,
+    /// Serve this directory to peers instead of storing what they push
+    /// (`rxx share`)
+    pub share: Option<Arc<Share>>,
+    /// Pass one file on to stdout and stop (`rxx receive --stdout`)
+    pub stdout: Option<Arc<StdoutSink>>,
+    /// Sender metadata applied to stored files, `--preserve` on top of the
+    /// config
+    pub preserve: Preserve,
+}
+
+/// Takes the content of the one file `rxx receive --stdout` waits for.
+pub struct StdoutSink {
+    out: Mutex<Option<Box<dyn AsyncWrite + Send + Unpin>>>,
+    result: Mutex<Option<Result<()>>>,
+    done: Notify,
+}
+
+impl StdoutSink {
+    pub fn new(out: Box<dyn AsyncWrite + Send + Unpin>) -> Arc<Self> {
+        Arc::new(StdoutSink {
+            out: Mutex::new(Some(out)),
+            result: Mutex::new(None),
+            done: Notify::new(),
+        })
+    }
+
+    async fn receive(&self, connection: &quinn::Connection, peer: Option<&str>) -> Result<()> {
+        let Some(mut out) = self.out.lock().unwrap().take() else {
+            anyhow::bail!("Already received a file for stdout");
+        };
+        let result = quic::receive_to(connection, peer, &mut out).await;
+        *self.result.lock().unwrap() = Some(
+            result
+                .as_ref()
+                .map(|_| ())
+                .map_err(|e| anyhow::anyhow!("{:#}", e)),
+        );
+        self.done.notify_one();
+        result
+    }
+
+    /// Wait for the transfer to end, returning how it went.
+    async fn finished(&self) -> Result<()> {
+        self.done.notified().await;
+        self.result.lock().unwrap().take().unwrap_or(Ok(()))
+    }
+}
+
+/// Addresses we punched towards recently, with the ID of the peer we
+/// expect there if we know it; only they may connect.
+#[derive(Default)]
+struct Expected {
+    peers: Mutex<HashMap<IpAddr, (Option<String>, Instant)>>,
+}
+
+impl Expected {
+    fn insert(&self, addr: IpAddr, peer_id: Option<&str>) {
+        self.peers
+            .lock()
+            .unwrap()
+            .insert(addr, (peer_id.map(str::to_string), Instant::now()));
+    }
+
+    /// Stop expecting anyone at `addr`, as punching towards it failed.
+    fn remove(&self, addr: IpAddr) {
+        self.peers.lock().unwrap().remove(&addr);
+    }
+
+    /// The peer expected at `addr`: `None` if nobody is, `Some(None)` if we
+    /// only know its address.
+    fn get(&self, addr: &IpAddr) -> Option<Option<String>> {
+        let mut peers = self.peers.lock().unwrap();
+        peers.retain(|_, (_, since)| since.elapsed() < Duration::from_secs(ACCEPT_WINDOW_SECS));
+        peers.get(addr).map(|(peer_id, _)| peer_id.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConnectionEvent {
+    Started(IpAddr),
+    Finished(IpAddr),
+}
+
+/// Connections the accept loop is handling, announced to whoever waits for
+/// them. The count per address lets a waiter that missed events check what
+/// is still running.
+struct Connections {
+    events: broadcast::Sender<ConnectionEvent>,
+    active: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl Connections {
+    fn new() -> Self {
+        Connections {
+            events: broadcast::channel(64).0,
+            active: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn started(&self, addr: IpAddr) {
+        *self.active.lock().unwrap().entry(addr).or_default() += 1;
+        let _ = self.events.send(ConnectionEvent::Started(addr));
+    }
+
+    fn finished(&self, addr: IpAddr) {
+        {
+            let mut active = self.active.lock().unwrap();
+            if let Some(count) = active.get_mut(&addr) {
+                *count -= 1;
+                if *count == 0 {
+                    active.remove(&addr);
+                }
+            }
+        }
+        let _ = self.events.send(ConnectionEvent::Finished(addr));
+    }
+
+    fn is_active(&self, addr: IpAddr) -> bool {
+        self.active.lock().unwrap().contains_key(&addr)
+    }
+}
+
+/// Caps the number of transfers running at once and makes transfers from
+/// the same sender run one after another, so a sender opening several
+/// connections cannot take every slot. Senders are told apart by ID, so
+/// one behind several addresses still gets a single slot.
+struct TransferSlots {
+    slots: Semaphore,
+    max: usize,
+    senders: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+/// Held while a transfer runs.
+struct TransferSlot<'a> {
+    _sender: OwnedMutexGuard<()>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl TransferSlots {
+    fn new(max: usize) -> Self {
+        let max = max.max(1);
+        TransferSlots {
+            slots: Semaphore::new(max),
+            max,
+            senders: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn acquire(&self, sender: &str) -> TransferSlot<'_> {
+        let lock = {
+            let mut senders = self.senders.lock().unwrap();
+            // Forget senders nobody is transferring from anymore
+            senders.retain(|_, lock| Arc::strong_count(lock) > 1);
+            senders.entry(sender.to_lowercase()).or_default().clone()
+        };
+
+        let sender_guard = match lock.clone().try_lock_owned() {
+            Ok(guard) => guard,
+            Err(_) => {
+                crate::info!(
+                    "Waiting for the previous transfer from {} to finish...",
+                    sender
+                );
+                lock.lock_owned().await
+            }
+        };
+
+        let permit = match self.slots.try_acquire() {
+            Ok(permit) => permit,
+            Err(_) => {
+                crate::info!(
+                    "All {} transfer slots busy, {} has to wait...",
+                    self.max,
+                    sender
+                );
+                self.slots
+                    .acquire()
+                    .await
+                    .expect("transfer semaphore is never closed")
+            }
+        };
+
+        TransferSlot {
+            _sender: sender_guard,
+            _permit: permit,
+        }
+    }
+}
+
+/// Run the receiver until the process is stopped, or with `stdout` set
+/// until the one file has been passed on.
+///
+/// One QUIC endpoint stays up on the server port for the whole run and
+/// handles every connection in its own task. With a `source` we keep
+/// punching towards that peer; without one (`rxx receive --any`) the server
+/// tells us about each sender via `/listen`. Either way probes go out from
+/// the endpoint's own socket so the pinholes match.
+pub async fn run(
+    mut config: Config,
+    server_config: ServerConfig,
+    options: ReceiveOptions,
+    source: Option<String>,
+) -> Result<()> {
+    let mut local_addr = net::select_local(None)?;
+    local_addr.set_port(udp::SERVER_PORT);
+    let socket = std::net::UdpSocket::bind(local_addr).context("Failed to bind UDP socket")?;
+    socket.set_nonblocking(true)?;
+
+    let mux = MuxSocket::new(socket).context("Failed to set up shared UDP socket")?;
+    let endpoint = quic::start_server(server_config, mux.clone())?;
+    let (rebound, bound) = watch::channel(mux);
+    follow_address_changes(&config, endpoint.clone(), rebound);
+
+    // Lets `rxx who` on other machines show us as receiving, and publishes
+    // the key senders encrypt mailbox items with
+    let mailbox_key = match options.share {
+        Some(_) => None,
+        None => Some(mailbox::load_or_create_key(&mut config)?),
+    };
+    let heartbeat = net::spawn_heartbeat(&config, bound.clone());
+
+    // Collect files left for us while we were offline
+    if let (Some(mailbox_key), None) = (mailbox_key, &options.stdout) {
+        mailbox::spawn_poller(
+            &config,
+            mailbox_key,
+            &options.output_base,
+            options.user_folder,
+            options.preserve,
+        );
+    }
+
+    let expected = Arc::new(Expected::default());
+    let connections = Arc::new(Connections::new());
+    tokio::spawn(accept_loop(
+        endpoint,
+        config.clone(),
+        options.clone(),
+        expected.clone(),
+        connections.clone(),
+    ));
+
+    let stdout = options.stdout.clone();
+    let transfers = {
+        let config = config.clone();
+        let bound = bound.clone();
+        async {
+            match source {
+                Some(source) => {
+                    receive_from(source, config, bound, options, expected, connections).await
+                }
+                None => receive_from_any(config, bound, options, expected).await,
+            }
+        }
+    };
+    let finished = async {
+        match stdout {
+            Some(stdout) => stdout.finished().await,
+            None => std::future::pending().await,
+        }
+    };
+    let result = tokio::select! {
+        result = transfers => result,
+        result = finished => result,
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+
+    // Don't leave `rxx who` showing us as receiving until the heartbeat
+    // goes stale
+    heartbeat.abort();
+    let local_ipv6 = bound.borrow().local_ipv6()?;
+    let stopped = net::send_heartbeat(&config, &local_ipv6, false);
+    match tokio::time::timeout(Duration::from_secs(5), stopped).await {
+        Ok(Err(e)) => crate::debug!("DEBUG [PRESENCE]: Heartbeat failed: {}", e),
+        Err(_) => crate::debug!("DEBUG [PRESENCE]: Heartbeat timed out"),
+        Ok(Ok(())) => {}
+    }
+    result
+}
+
+/// Move the endpoint to our new address when it changes, so new senders
+/// reach us there, and only then tell the server about it.
+fn follow_address_changes(
+    config: &Config,
+    endpoint: Endpoint,
+    rebound: watch::Sender<Arc<MuxSocket>>,
+) {
+    let mut changes = match net::spawn_address_monitor(None) {
+        Ok(changes) => changes,
+        Err(e) => {
+            crate::info!("Address monitoring unavailable: {}", e);
+            return;
+        }
+    };
+    let config = config.clone();
+
+    tokio::spawn(async move {
+        while changes.changed().await.is_ok() {
+            let mut addr = *changes.borrow_and_update();
+            addr.set_port(udp::SERVER_PORT);
+            let mux = match rebind(&endpoint, addr) {
+                Ok(mux) => mux,
+                Err(e) => {
+                    crate::info!("Staying on the old address: {:#}", e);
+                    continue;
+                }
+            };
+            if rebound.send(mux).is_err() {
+                return;
+            }
+            crate::info!("Now receiving on {}", addr);
+
+            if let Err(e) = net::refresh_registration(&config, addr.ip()).await {
+                crate::info!("Failed to update server with new address: {}", e);
+            }
+        }
+    });
+}
+
+/// Bind a new shared socket to `addr` and move the endpoint onto it.
+/// Connections in flight may not survive the move.
+fn rebind(endpoint: &Endpoint, addr: std::net::SocketAddrV6) -> Result<Arc<MuxSocket>> {
+    let socket = std::net::UdpSocket::bind(addr)
+        .with_context(|| format!("Failed to bind UDP socket to {}", addr))?;
+    socket.set_nonblocking(true)?;
+    let mux = MuxSocket::new(socket).context("Failed to set up shared UDP socket")?;
+    endpoint
+        .rebind_abstract(mux.clone())
+        .context("Failed to move the QUIC endpoint")?;
+    Ok(mux)
+}
+
+async fn receive_from(
+    source: String,
+    config: Config,
+    bound: Bound,
+    options: ReceiveOptions,
+    expected: Arc<Expected>,
+    connections: Arc<Connections>,
+) -> Result<()> {
+    let mut events = connections.events.subscribe();
+    // The server only hears about senders that look us up by ID; others,
+    // and senders the server cannot tell us about, we punch towards
+    // ourselves
+    let signaled = matches!(
+        crate::peer::parse_peer(&source),
+        crate::peer::PeerAddress::Id(_)
+    );
+    let source_id = signaled.then(|| source.clone());
+    let mut rendezvous =
+        signaled.then(|| watch_for_sender(source.clone(), config.clone(), bound.clone()));
+
+    loop {
+        crate::info!("\nWaiting for next connection...");
+
+        let mux = bound.borrow().clone();
+        let punched: Arc<Mutex<Vec<IpAddr>>> = Arc::default();
+        let on_our_own = punch_towards(
+            &source,
+            source_id.as_deref(),
+            &config,
+            &mux,
+            &options,
+            &expected,
+            &punched,
+        );
+        let signal = async {
+            match &mut rendezvous {
+                Some(rendezvous) => rendezvous.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        let result = tokio::select! {
+            result = on_our_own => result,
+            Some(peer) = signal => {
+                // The sender is punching towards us at an agreed time
+                for addr in punched.lock().unwrap().drain(..) {
+                    expected.remove(addr);
+                }
+                expected.insert(IpAddr::V6(peer.addr), Some(&source));
+                let result = udp::punch_shared(&mux, &peer, &options.punch).await;
+                if result.is_err() {
+                    expected.remove(IpAddr::V6(peer.addr));
+                } else {
+                    crate::info!("Bidirectional UDP channel established!");
+                }
+                result
+            }
+        };
+
+        let peer_addr = match result {
+            Ok(addr) => addr,
+            Err(e) => {
+                for addr in punched.lock().unwrap().drain(..) {
+                    expected.remove(addr);
+                }
+                eprintln!("Error during UDP hole punching: {}", e);
+                continue;
+            }
+        };
+
+        crate::info!("Waiting for QUIC connection from {}...", peer_addr);
+        wait_for_transfer(
+            &connections,
+            &mut events,
+            peer_addr.ip(),
+            options.punch.attempt_timeout,
+        )
+        .await;
+    }
+}
+
+/// Resolve `source` and punch towards it, recording every address we try
+/// in `punched`.
+async fn punch_towards(
+    source: &str,
+    source_id: Option<&str>,
+    config: &Config,
+    mux: &MuxSocket,
+    options: &ReceiveOptions,
+    expected: &Arc<Expected>,
+    punched: &Arc<Mutex<Vec<IpAddr>>>,
+) -> Result<std::net::SocketAddr> {
+    let src = source.to_string();
+    let cfg = config.clone();
+    let exp = expected.clone();
+    let id = source_id.map(str::to_string);
+    let tried = punched.clone();
+    let resolver = move || {
+        let s = src.clone();
+        let c = cfg.clone();
+        let e = exp.clone();
+        let id = id.clone();
+        let tried = tried.clone();
+        Box::pin(async move {
+            let peer = net::resolve_peer(&s, &c).await?;
+            // Its handshake may arrive before our punching notices it
+            e.insert(IpAddr::V6(peer.addr), id.as_deref());
+            tried.lock().unwrap().push(IpAddr::V6(peer.addr));
+            Ok(peer)
+        })
+            as std::pin::Pin<Box<dyn std::future::Future<Output = Result<ResolvedPeer>> + Send>>
+    };
+    udp::punch_hole_shared(mux, resolver, &options.punch).await
+}
+
+/// Pass on each time the server says `source` wants to send to us. The long
+/// poll runs in its own task so that it is never cut off while the server
+/// hands over a rendezvous.
+fn watch_for_sender(source: String, config: Config, bound: Bound) -> mpsc::Receiver<ResolvedPeer> {
+    /// How long to wait before asking a server that failed again
+    const RETRY: Duration = Duration::from_secs(30);
+
+    let (senders, rendezvous) = mpsc::channel(1);
+    tokio::spawn(async move {
+        let mut reported = false;
+        loop {
+            match wait_for_sender(&source, &config, &bound).await {
+                Ok(peer) => {
+                    if senders.send(peer).await.is_err() {
+                        return;
+                    }
+                }
+                Err(e) => {
+                    if !reported {
+                        crate::info!(
+                            "Server cannot tell us when {} sends ({}), punching on our own",
+                            source,
+                            e
+                        );
+                        reported = true;
+                    }
+                    crate::debug!("DEBUG [RECV]: Waiting for senders failed: {}", e);
+                    tokio::time::sleep(RETRY).await;
+                }
+            }
+        }
+    });
+    rendezvous
+}
+
+/// Long-poll the server until `source` wants to send to us.
+async fn wait_for_sender(source: &str, config: &Config, bound: &Bound) -> Result<ResolvedPeer> {
+    loop {
+        let local_ipv6 = bound.borrow().local_ipv6()?;
+        for rendezvous in net::listen_for_senders(config, &local_ipv6).await? {
+            if rendezvous.peer_id.eq_ignore_ascii_case(source) {
+                return Ok(rendezvous.peer);
+            }
+            crate::info!(
+                "Ignoring {}, only receiving from {}",
+                rendezvous.peer_id,
+                source
+            );
+        }
+    }
+}
+
+/// Wait until the connection `peer` should open after punching has come and
+/// gone, so we do not probe a sender that is busy transferring.
+async fn wait_for_transfer(
+    connections: &Connections,
+    events: &mut broadcast::Receiver<ConnectionEvent>,
+    peer: IpAddr,
+    connect_timeout: Duration,
+) {
+    let started = next_event(connections, events, ConnectionEvent::Started(peer));
+    if tokio::time::timeout(connect_timeout, started)
+        .await
+        .is_err()
+    {
+        crate::info!("No QUIC connection from {}", peer);
+        return;
+    }
+    // Events are queued from here on, so a connection that is not active
+    // now has already finished
+    if connections.is_active(peer) {
+        next_event(connections, events, ConnectionEvent::Finished(peer)).await;
+    }
+}
+
+async fn next_event(
+    connections: &Connections,
+    events: &mut broadcast::Receiver<ConnectionEvent>,
+    wanted: ConnectionEvent,
+) {
+    loop {
+        match events.recv().await {
+            Ok(event) if event == wanted => return,
+            Ok(_) => {}
+            Err(broadcast::error::RecvError::Closed) => return,
+            // Some events were dropped, the one we want may be among them.
+            // A start may have come and gone, so stop waiting for it; an
+            // end has come once the address is no longer active.
+            Err(broadcast::error::RecvError::Lagged(_)) => match wanted {
+                ConnectionEvent::Started(_) => return,
+                ConnectionEvent::Finished(peer) if !connections.is_active(peer) => return,
+                ConnectionEvent::Finished(_) => {}
+            },
+        }
+    }
+}
+
+async fn receive_from_any(
+    config: Config,
+    bound: Bound,
+    options: ReceiveOptions,
+    expected: Arc<Expected>,
+) -> Result<()> {
+    let punching: Arc<Mutex<HashSet<String>>> = Arc::default();
+    crate::info!("Listening for senders as '{}'...", config.user_id);
+
+    loop {
+        let local_ipv6 = bound.borrow().local_ipv6();
+        let senders = match local_ipv6 {
+            Ok(local_ipv6) => net::listen_for_senders(&config, &local_ipv6).await,
+            Err(e) => Err(e.into()),
+        };
+        let senders = match senders {
+            Ok(senders) => senders,
+            Err(e) => {
+                eprintln!("Error waiting for senders: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        for rendezvous in senders {
+            let peer_id = rendezvous.peer_id.to_lowercase();
+            if !punching.lock().unwrap().insert(peer_id.clone()) {
+                crate::debug!("DEBUG [RECV]: Already punching towards {}", peer_id);
+                continue;
+            }
+
+            crate::info!(
+                "{} wants to connect, punching towards {}...",
+                rendezvous.peer_id,
+                rendezvous.peer.addr
+            );
+
+            let mux = bound.borrow().clone();
+            let punch = options.punch.clone();
+            let punching = punching.clone();
+            let expected = expected.clone();
+            tokio::spawn(async move {
+                // Accept the connection even if our probes are still in
+                // flight when the sender's QUIC handshake arrives
+                expected.insert(IpAddr::V6(rendezvous.peer.addr), Some(&peer_id));
+
+                // Sleeps until the start time agreed with the sender
+                match udp::punch_shared(&mux, &rendezvous.peer, &punch).await {
+                    Ok(addr) => crate::info!(
+                        "UDP channel to {} established ({})",
+                        rendezvous.peer_id,
+                        addr
+                    ),
+                    Err(e) => {
+                        expected.remove(IpAddr::V6(rendezvous.peer.addr));
+                        crate::info!("Hole punching towards {} failed: {}", rendezvous.peer_id, e)
+                    }
+                }
+                punching.lock().unwrap().remove(&peer_id);
+            });
+        }
+    }
+}
+
+async fn accept_loop(
+    endpoint: Endpoint,
+    config: Config,
+    options: ReceiveOptions,
+    expected: Arc<Expected>,
+    connections: Arc<Connections>,
+) {
+    let slots = Arc::new(TransferSlots::new(options.max_concurrent));
+
+    while let Some(incoming) = endpoint.accept().await {
+        let remote = incoming.remote_address();
+        let Some(peer_id) = expected.get(&remote.ip()) else {
+            crate::info!("Refusing unexpected QUIC connection from {}", remote);
+            incoming.refuse();
+            continue;
+        };
+
+        let config = config.clone();
+        let options = options.clone();
+        let slots = slots.clone();
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            connections.started(remote.ip());
+
+            match incoming.await {
+                Ok(connection) => {
+                    crate::info!(
+                        "QUIC connection accepted from {}",
+                        connection.remote_address()
+                    );
+
+                    let sender = peer_id.clone().unwrap_or_else(|| remote.ip().to_string());
+                    let _slot = slots.acquire(&sender).await;
+                    let result = match (&options.share, &options.stdout) {
+                        (Some(share), _) => {
+                            share::serve(&connection, share, &config, peer_id.as_deref()).await
+                        }
+                        (None, Some(stdout)) => {
+                            stdout.receive(&connection, peer_id.as_deref()).await
+                        }
+                        (None, None) => quic::receive_files(
+                            &connection,
+                            peer_id.as_deref(),
+                            &options.output_base,
+                            options.user_folder,
+                            options.preserve,
+                            &config,
+                        )
+                        .await
+                        .map(|_| ()),
+                    };
+                    match result {
+                        Ok(()) => crate::info!("Transfer with {} completed successfully", remote),
+                        Err(e) => eprintln!("Error during transfer with {}: {}", remote, e),
+                    }
+                }
+                Err(e) => eprintln!("Error accepting connection from {}: {}", remote, e),
+            }
+
+            connections.finished(remote.ip());
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_events_after_lag() {
+        let peer: IpAddr = "2001:db8::1".parse().unwrap();
+        let other: IpAddr = "2001:db8::2".parse().unwrap();
+        let short = Duration::from_millis(50);
+        let connections = Connections::new();
+
+        // The transfer finishes, then so many others that its events are
+        // dropped before we read them
+        let mut events = connections.events.subscribe();
+        connections.started(peer);
+        connections.finished(peer);
+        for _ in 0..100 {
+            connections.started(other);
+            connections.finished(other);
+        }
+        let finished = next_event(&connections, &mut events, ConnectionEvent::Finished(peer));
+        tokio::time::timeout(short, finished).await.unwrap();
+
+        // Still running after the lag: keep waiting for its end
+        let mut events = connections.events.subscribe();
+        connections.started(peer);
+        for _ in 0..100 {
+            connections.started(other);
+            connections.finished(other);
+        }
+        let finished = next_event(&connections, &mut events, ConnectionEvent::Finished(peer));
+        assert!(tokio::time::timeout(short, finished).await.is_err());
+        assert!(connections.is_active(peer));
+        connections.finished(peer);
+        assert!(!connections.is_active(peer));
+    }
+
+    #[tokio::test]
+    async fn test_transfer_slots() {
+        let (alice, bob, carol) = ("alice", "bob", "carol");
+        let short = Duration::from_millis(50);
+
+        let slots = TransferSlots::new(2);
+        let first = slots.acquire(alice).await;
+
+        // A second transfer from the same sender waits for the first, whatever
+        // the case of its ID
+        assert!(tokio::time::timeout(short, slots.acquire("ALICE"))
+            .await
+            .is_err());
+
+        // Other senders only wait for a free slot
+        let second = slots.acquire(bob).await;
+        assert!(tokio::time::timeout(short, slots.acquire(carol))
+            .await
+            .is_err());
+
+        drop(first);
+        let _third = tokio::time::timeout(short, slots.acquire(carol))
+            .await
+            .unwrap();
+        drop(second);
+        let _fourth = tokio::time::timeout(short, slots.acquire(alice))
+            .await
+            .unwrap();
+    }
+}
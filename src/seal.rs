@@ -0,0 +1,256 @@
+use anyhow::{bail, Context, Result};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// End-to-end encryption for mailbox items.
+///
+/// A sealed blob is `MAGIC`, a version byte and the sender's ephemeral
+/// X25519 public key, followed by records of a flag byte, a u32 length and
+/// a ChaCha20-Poly1305 ciphertext of up to `CHUNK_SIZE` plaintext bytes.
+/// Each record's nonce is its index plus the flag, so records cannot be
+/// reordered, dropped or appended after the one flagged as last.
+const MAGIC: &[u8; 4] = b"RXXM";
+const VERSION: u8 = 1;
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+const FLAG_LAST: u8 = 1;
+const KDF_INFO: &[u8] = b"rxx mailbox v1";
+
+pub fn generate_secret() -> StaticSecret {
+    StaticSecret::random_from_rng(rand::rngs::OsRng)
+}
+
+pub fn secret_from_hex(hex_key: &str) -> Result<StaticSecret> {
+    let bytes: [u8; 32] = hex::decode(hex_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .context("Invalid mailbox key")?;
+    Ok(StaticSecret::from(bytes))
+}
+
+pub fn public_from_hex(hex_key: &str) -> Result<PublicKey> {
+    let bytes: [u8; 32] = hex::decode(hex_key)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .context("Invalid mailbox public key")?;
+    Ok(PublicKey::from(bytes))
+}
+
+fn cipher(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> ChaCha20Poly1305 {
+    let mut salt = [0u8; 64];
+    salt[..32].copy_from_slice(ephemeral.as_bytes());
+    salt[32..].copy_from_slice(recipient.as_bytes());
+
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(KDF_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    ChaCha20Poly1305::new(Key::from_slice(&key))
+}
+
+fn nonce(index: u64, flag: u8) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = flag;
+    *Nonce::from_slice(&nonce)
+}
+
+/// Read `CHUNK_SIZE` bytes unless the input ends first.
+async fn read_chunk<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = reader.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    Ok(filled)
+}
+
+/// Encrypt everything `reader` yields for `recipient`. Returns the number of
+/// sealed bytes written.
+pub async fn seal<R, W>(reader: &mut R, writer: &mut W, recipient: &PublicKey) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let ephemeral = generate_secret();
+    let ephemeral_public = PublicKey::from(&ephemeral);
+    let shared = ephemeral.diffie_hellman(recipient);
+    let cipher = cipher(shared.as_bytes(), &ephemeral_public, recipient);
+
+    writer.write_all(MAGIC).await?;
+    writer.write_u8(VERSION).await?;
+    writer.write_all(ephemeral_public.as_bytes()).await?;
+    let mut written = (MAGIC.len() + 1 + 32) as u64;
+
+    // Read one chunk ahead so the last record can be flagged
+    let mut current = vec![0u8; CHUNK_SIZE];
+    let mut next = vec![0u8; CHUNK_SIZE];
+    let mut current_len = read_chunk(reader, &mut current).await?;
+    let mut index = 0u64;
+    loop {
+        let next_len = if current_len == CHUNK_SIZE {
+            read_chunk(reader, &mut next).await?
+        } else {
+            0
+        };
+        let flag = if next_len == 0 { FLAG_LAST } else { 0 };
+
+        let ciphertext = cipher
+            .encrypt(&nonce(index, flag), &current[..current_len])
+            .map_err(|_| anyhow::anyhow!("Encryption failed"))?;
+        writer.write_u8(flag).await?;
+        writer.write_u32(ciphertext.len() as u32).await?;
+        writer.write_all(&ciphertext).await?;
+        written += 5 + ciphertext.len() as u64;
+
+        if flag == FLAG_LAST {
+            break;
+        }
+        std::mem::swap(&mut current, &mut next);
+        current_len = next_len;
+        index += 1;
+    }
+
+    writer.flush().await?;
+    Ok(written)
+}
+
+/// Decrypt a blob sealed for `secret`, writing the plaintext to `writer`.
+pub async fn open<R, W>(reader: &mut R, writer: &mut W, secret: &StaticSecret) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut magic = [0u8; 4];
+    reader
+        .read_exact(&mut magic)
+        .await
+        .context("Sealed data is truncated")?;
+    if &magic != MAGIC {
+        bail!("Not a sealed mailbox item");
+    }
+    let version = reader.read_u8().await?;
+    if version != VERSION {
+        bail!("Unsupported mailbox item version {}", version);
+    }
+    let mut ephemeral = [0u8; 32];
+    reader.read_exact(&mut ephemeral).await?;
+    let ephemeral = PublicKey::from(ephemeral);
+
+    let shared = secret.diffie_hellman(&ephemeral);
+    let cipher = cipher(shared.as_bytes(), &ephemeral, &PublicKey::from(secret));
+
+    let mut index = 0u64;
+    loop {
+        let flag = reader.read_u8().await.context("Sealed data is truncated")?;
+        let len = reader.read_u32().await? as usize;
+        if len > CHUNK_SIZE + TAG_LEN {
+            bail!("Sealed record too large");
+        }
+        let mut ciphertext = vec![0u8; len];
+        reader
+            .read_exact(&mut ciphertext)
+            .await
+            .context("Sealed data is truncated")?;
+
+        let plaintext = cipher
+            .decrypt(&nonce(index, flag), ciphertext.as_slice())
+            .map_err(|_| anyhow::anyhow!("Mailbox item was tampered with or is not for us"))?;
+        writer.write_all(&plaintext).await?;
+
+        if flag == FLAG_LAST {
+            break;
+        }
+        index += 1;
+    }
+
+    let mut trailing = [0u8; 1];
+    if reader.read(&mut trailing).await? != 0 {
+        bail!("Unexpected data after the last sealed record");
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn roundtrip(plaintext: &[u8]) -> (Vec<u8>, StaticSecret) {
+        let secret = generate_secret();
+        let mut sealed = Vec::new();
+        let written = seal(&mut &plaintext[..], &mut sealed, &PublicKey::from(&secret))
+            .await
+            .unwrap();
+        assert_eq!(written, sealed.len() as u64);
+
+        let mut opened = Vec::new();
+        open(&mut sealed.as_slice(), &mut opened, &secret)
+            .await
+            .unwrap();
+        assert_eq!(opened, plaintext);
+        (sealed, secret)
+    }
+
+    #[tokio::test]
+    async fn test_seal_roundtrip() {
+        roundtrip(b"").await;
+        roundtrip(b"hello").await;
+        roundtrip(&vec![7u8; CHUNK_SIZE]).await;
+        roundtrip(&vec![9u8; 3 * CHUNK_SIZE + 17]).await;
+    }
+
+    #[tokio::test]
+    async fn test_open_rejects_tampering() {
+        let (sealed, secret) = roundtrip(&vec![1u8; 2 * CHUNK_SIZE + 5]).await;
+        let mut out = Vec::new();
+
+        // Wrong key
+        assert!(open(&mut sealed.as_slice(), &mut out, &generate_secret())
+            .await
+            .is_err());
+
+        // Flipped ciphertext bit
+        let mut flipped = sealed.clone();
+        let last = flipped.len() - 1;
+        flipped[last] ^= 1;
+        assert!(open(&mut flipped.as_slice(), &mut out, &secret)
+            .await
+            .is_err());
+
+        // Dropped last record
+        let first_record = MAGIC.len() + 1 + 32 + 5 + CHUNK_SIZE + TAG_LEN;
+        let truncated = &sealed[..first_record * 2 - (MAGIC.len() + 1 + 32)];
+        assert!(open(&mut &truncated[..], &mut out, &secret).await.is_err());
+
+        // Appended garbage
+        let mut extended = sealed.clone();
+        extended.push(0);
+        assert!(open(&mut extended.as_slice(), &mut out, &secret)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_key_hex() {
+        let secret = generate_secret();
+        let parsed = secret_from_hex(&hex::encode(secret.to_bytes())).unwrap();
+        assert_eq!(parsed.to_bytes(), secret.to_bytes());
+
+        let public = PublicKey::from(&secret);
+        assert_eq!(
+            public_from_hex(&hex::encode(public.as_bytes())).unwrap(),
+            public
+        );
+
+        assert!(secret_from_hex("abcd").is_err());
+        assert!(public_from_hex("not hex").is_err());
+    }
+}
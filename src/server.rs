@@ -1,14 +1,45 @@
 use crate::db::Database;
 use anyhow::Result;
 use axum::{
+    body::{Body, Bytes},
     extract::State,
-    http::StatusCode,
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
     routing::post,
     Json, Router,
 };
+use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Notify;
+
+/// How long a `/listen` request is held open when nothing is pending
+const LISTEN_POLL_SECS: u64 = 25;
+/// A receiver counts as listening for this long after its last poll
+const LISTENER_TTL_SECS: u64 = 60;
+/// Head start given to both peers of a rendezvous before they punch, so
+/// the receiver hears about the sender before either side sends probes
+const RENDEZVOUS_DELAY_MS: u64 = 1000;
+/// Rendezvous not picked up by the receiver within this long are dropped
+const RENDEZVOUS_EXPIRY_SECS: u64 = 10;
+/// A peer counts as online if its last heartbeat is at most this old
+const ONLINE_SECS: i64 = 90;
+/// How often expired mailbox items are cleaned up
+const MAILBOX_CLEANUP_SECS: u64 = 600;
+
+/// Storage for files sent to recipients that are offline
+#[derive(Debug, Clone)]
+pub struct MailboxSettings {
+    pub dir: PathBuf,
+    /// Bytes stored per recipient at most
+    pub quota: u64,
+    /// Items are deleted after this long whether fetched or not
+    pub ttl: Duration,
+}
 
 #[derive(Deserialize)]
 pub struct RegisterRequest {
@@ -26,16 +57,186 @@ pub struct UpdateRequest {
     pub id: String,
     pub ipv6: String,
     pub nonce: String,
-    pub peer_id: String,
+    /// Peer to resolve; omitted when a client only reports an address change
+    #[serde(default)]
+    pub peer_id: Option<String>,
+    /// Only resolve the peer, without telling a listening receiver about
+    /// us; clients use it to learn which local address reaches the peer
+    #[serde(default)]
+    pub lookup: bool,
 }
 
 #[derive(Serialize)]
 pub struct UpdateResponse {
     pub peer_ipv6: String,
+    pub session_id: String,
+    pub session_key: String,
+    /// Set when the peer is listening and will start punching in this many
+    /// milliseconds too
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start_in_ms: Option<u64>,
+}
+
+#[derive(Deserialize)]
+pub struct ListenRequest {
+    pub id: String,
+    pub ipv6: String,
+    pub nonce: String,
+}
+
+#[derive(Deserialize)]
+pub struct HeartbeatRequest {
+    pub id: String,
+    pub ipv6: String,
+    pub nonce: String,
+    /// Whether the client is running `rxx receive`
+    #[serde(default)]
+    pub listening: bool,
+    /// Key senders encrypt mailbox items with (hex X25519)
+    #[serde(default)]
+    pub public_key: Option<String>,
+    /// IDs allowed to see our presence; left unchanged when missing
+    #[serde(default)]
+    pub contacts: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct StatusRequest {
+    pub id: String,
+    pub nonce: String,
+    /// IDs to report on
+    pub peers: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct PeerStatus {
+    pub id: String,
+    pub registered: bool,
+    pub online: bool,
+    pub listening: bool,
+    /// The peer does not list the asker as a contact
+    pub hidden: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub struct StatusResponse {
+    pub peers: Vec<PeerStatus>,
+}
+
+#[derive(Deserialize)]
+pub struct MailboxRequest {
+    pub id: String,
+    pub nonce: String,
+    /// Item to fetch or delete
+    #[serde(default)]
+    pub item_id: Option<String>,
+    /// Recipient whose key to look up
+    #[serde(default)]
+    pub peer_id: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct MailboxEntry {
+    pub item_id: String,
+    pub sender: String,
+    pub size: u64,
+    pub created_at: i64,
+}
+
+/// A sender that wants to reach a listening receiver
+#[derive(Clone, Serialize)]
+pub struct RendezvousRequest {
+    pub peer_id: String,
+    pub peer_ipv6: String,
+    pub session_id: String,
+    pub session_key: String,
+    /// Milliseconds until both peers start punching, relative to when the
+    /// response is sent so clock differences do not matter
+    pub start_in_ms: u64,
+    #[serde(skip)]
+    start_at: Instant,
+}
+
+#[derive(Serialize)]
+pub struct ListenResponse {
+    pub requests: Vec<RendezvousRequest>,
+}
+
+/// A receiver waiting in `/listen` for senders to show up
+struct Listener {
+    pending: Vec<RendezvousRequest>,
+    notify: Arc<Notify>,
+    last_poll: Instant,
+}
+
+impl Listener {
+    /// Hand out pending requests with their remaining delay, dropping the
+    /// ones the sender has given up on by now.
+    fn take_pending(&mut self, now: Instant) -> Vec<RendezvousRequest> {
+        std::mem::take(&mut self.pending)
+            .into_iter()
+            .filter(|request| {
+                now.saturating_duration_since(request.start_at)
+                    < Duration::from_secs(RENDEZVOUS_EXPIRY_SECS)
+            })
+            .map(|mut request| {
+                request.start_in_ms =
+                    request.start_at.saturating_duration_since(now).as_millis() as u64;
+                request
+            })
+            .collect()
+    }
+}
+
+/// Forget receivers that stopped polling.
+fn expire_listeners(listeners: &mut HashMap<String, Listener>, now: Instant) {
+    listeners.retain(|_, listener| {
+        now.saturating_duration_since(listener.last_poll) <= Duration::from_secs(LISTENER_TTL_SECS)
+    });
 }
 
 struct AppState {
     db: Arc<Mutex<Database>>,
+    listeners: Mutex<HashMap<String, Listener>>,
+    mailbox: Option<MailboxSettings>,
+}
+
+impl AppState {
+    /// Queue a rendezvous for `receiver_id` if it is currently listening.
+    fn notify_listener(&self, receiver_id: &str, request: RendezvousRequest) -> bool {
+        let mut listeners = self.listeners.lock().unwrap();
+        let Some(listener) = listeners.get_mut(&receiver_id.to_lowercase()) else {
+            return false;
+        };
+        if listener.last_poll.elapsed() > Duration::from_secs(LISTENER_TTL_SECS) {
+            return false;
+        }
+
+        // A sender retrying replaces its earlier request
+        listener
+            .pending
+            .retain(|pending| !pending.peer_id.eq_ignore_ascii_case(&request.peer_id));
+        listener.pending.push(request);
+        listener.notify.notify_one();
+        true
+    }
+}
+
+/// Verify a client's nonce, turning a failure into the status to respond with.
+fn authenticate(db: &Database, id: &str, nonce: &str) -> Result<(), StatusCode> {
+    match db.verify(id, nonce) {
+        Ok(true) => Ok(()),
+        Ok(false) => {
+            eprintln!("Invalid nonce for {}", id);
+            Err(StatusCode::UNAUTHORIZED)
+        }
+        Err(e) => {
+            eprintln!("Authentication error: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
 }
 
 async fn register(
@@ -76,16 +277,55 @@ async fn update(State(state): State<Arc<AppState>>, Json(req): Json<UpdateReques
         }
     }
 
-    match db.get_ipv6(&req.peer_id) {
+    let Some(peer_id) = req.peer_id.as_deref() else {
+        println!("Updated {} -> {}", req.id, req.ipv6);
+        return Json(serde_json::json!({})).into_response();
+    };
+
+    match db.get_ipv6(peer_id) {
         Ok(Some(peer_ipv6)) => {
             println!(
                 "Updated {} -> {}, resolved {} -> {}",
-                req.id, req.ipv6, req.peer_id, peer_ipv6
+                req.id, req.ipv6, peer_id, peer_ipv6
             );
-            Json(UpdateResponse { peer_ipv6 }).into_response()
+            match db.get_or_create_session(&req.id, peer_id) {
+                Ok((session_id, session_key)) => {
+                    // Let a listening receiver know where we are, and have
+                    // both sides start punching at the same moment
+                    let delay = Duration::from_millis(RENDEZVOUS_DELAY_MS);
+                    let rendezvous = RendezvousRequest {
+                        peer_id: req.id.to_lowercase(),
+                        peer_ipv6: req.ipv6.clone(),
+                        session_id: session_id.clone(),
+                        session_key: session_key.clone(),
+                        start_in_ms: RENDEZVOUS_DELAY_MS,
+                        start_at: Instant::now() + delay,
+                    };
+                    let start_in_ms = if req.lookup {
+                        None
+                    } else if state.notify_listener(peer_id, rendezvous) {
+                        println!("Notified listening {} about {}", peer_id, req.id);
+                        Some(RENDEZVOUS_DELAY_MS)
+                    } else {
+                        None
+                    };
+
+                    Json(UpdateResponse {
+                        peer_ipv6,
+                        session_id,
+                        session_key,
+                        start_in_ms,
+                    })
+                    .into_response()
+                }
+                Err(e) => {
+                    eprintln!("Session error: {}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            }
         }
         Ok(None) => {
-            println!("Peer not found: {}", req.peer_id);
+            println!("Peer not found: {}", peer_id);
             StatusCode::NOT_FOUND.into_response()
         }
         Err(e) => {
@@ -95,15 +335,479 @@ async fn update(State(state): State<Arc<AppState>>, Json(req): Json<UpdateReques
     }
 }
 
-pub async fn run_server(db_path: &str, port: u16) -> Result<()> {
+/// Long-poll for senders that want to reach this receiver.
+///
+/// Returns as soon as a rendezvous is pending, or with an empty list after
+/// `LISTEN_POLL_SECS` so the receiver polls again. Each poll also refreshes
+/// the receiver's address.
+async fn listen(State(state): State<Arc<AppState>>, Json(req): Json<ListenRequest>) -> Response {
+    {
+        let db = state.db.lock().unwrap();
+        // Polling counts as a heartbeat of a receiving client
+        let result = db.update(&req.id, &req.ipv6, &req.nonce).and_then(|valid| {
+            if valid {
+                db.heartbeat(&req.id, true)?;
+            }
+            Ok(valid)
+        });
+        match result {
+            Ok(true) => {}
+            Ok(false) => {
+                eprintln!("Invalid nonce for {}", req.id);
+                return StatusCode::UNAUTHORIZED.into_response();
+            }
+            Err(e) => {
+                eprintln!("Listen error: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    }
+
+    let id = req.id.to_lowercase();
+    let take_pending = || {
+        let mut listeners = state.listeners.lock().unwrap();
+        expire_listeners(&mut listeners, Instant::now());
+        let listener = listeners.entry(id.clone()).or_insert_with(|| Listener {
+            pending: Vec::new(),
+            notify: Arc::new(Notify::new()),
+            last_poll: Instant::now(),
+        });
+        listener.last_poll = Instant::now();
+        (
+            listener.take_pending(Instant::now()),
+            listener.notify.clone(),
+        )
+    };
+
+    let (requests, notify) = take_pending();
+    if !requests.is_empty() {
+        return Json(ListenResponse { requests }).into_response();
+    }
+
+    let _ = tokio::time::timeout(Duration::from_secs(LISTEN_POLL_SECS), notify.notified()).await;
+    let (requests, _) = take_pending();
+    Json(ListenResponse { requests }).into_response()
+}
+
+/// Record that a client is online, and whether it is receiving.
+async fn heartbeat(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<HeartbeatRequest>,
+) -> Response {
+    let db = state.db.lock().unwrap();
+    let result = db.update(&req.id, &req.ipv6, &req.nonce).and_then(|valid| {
+        if valid {
+            db.heartbeat(&req.id, req.listening)?;
+            if let Some(key) = &req.public_key {
+                if crate::seal::public_from_hex(key).is_ok() {
+                    db.set_public_key(&req.id, key)?;
+                }
+            }
+            if let Some(contacts) = &req.contacts {
+                db.set_contacts(&req.id, contacts)?;
+            }
+        }
+        Ok(valid)
+    });
+
+    match result {
+        Ok(true) => {
+            crate::debug!("Heartbeat from {} (listening: {})", req.id, req.listening);
+            Json(serde_json::json!({})).into_response()
+        }
+        Ok(false) => {
+            eprintln!("Invalid nonce for {}", req.id);
+            StatusCode::UNAUTHORIZED.into_response()
+        }
+        Err(e) => {
+            eprintln!("Heartbeat error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Report which of the given peers are online. Only registered clients may
+/// ask, and a peer's presence is only shown to the contacts it lists.
+async fn status(State(state): State<Arc<AppState>>, Json(req): Json<StatusRequest>) -> Response {
+    let db = state.db.lock().unwrap();
+    if let Err(status) = authenticate(&db, &req.id, &req.nonce) {
+        return status.into_response();
+    }
+
+    let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(now) => now.as_secs() as i64,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let peers = req
+        .peers
+        .iter()
+        .map(|id| peer_status(&db, &req.id, id, now))
+        .collect::<Result<Vec<_>>>();
+    match peers {
+        Ok(peers) => Json(StatusResponse { peers }).into_response(),
+        Err(e) => {
+            eprintln!("Status error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// What `asker` may learn about whether `id` is around at unix time `now`.
+fn peer_status(db: &Database, asker: &str, id: &str, now: i64) -> Result<PeerStatus> {
+    let mut status = PeerStatus {
+        id: id.to_lowercase(),
+        registered: false,
+        online: false,
+        listening: false,
+        hidden: false,
+        last_seen_secs: None,
+    };
+    let Some(presence) = db.presence(id)? else {
+        return Ok(status);
+    };
+    status.registered = true;
+    if !id.eq_ignore_ascii_case(asker) && !db.has_contact(id, asker)? {
+        status.hidden = true;
+        return Ok(status);
+    }
+
+    let age = presence.last_seen.map(|seen| (now - seen).max(0));
+    status.online = age.is_some_and(|age| age <= ONLINE_SECS);
+    status.listening = status.online && presence.listening;
+    status.last_seen_secs = age.map(|age| age as u64);
+    Ok(status)
+}
+
+/// Look up the key to encrypt mailbox items for `peer_id` with.
+async fn mailbox_key(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<MailboxRequest>,
+) -> Response {
+    if state.mailbox.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let db = state.db.lock().unwrap();
+    if let Err(status) = authenticate(&db, &req.id, &req.nonce) {
+        return status.into_response();
+    }
+    let Some(peer_id) = req.peer_id.as_deref() else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    match db.get_public_key(peer_id) {
+        Ok(Some(public_key)) => {
+            Json(serde_json::json!({ "public_key": public_key })).into_response()
+        }
+        Ok(None) => {
+            println!("No mailbox key for {}", peer_id);
+            StatusCode::NOT_FOUND.into_response()
+        }
+        Err(e) => {
+            eprintln!("Mailbox key error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Store an encrypted item for a recipient.
+///
+/// The body is the sealed blob; the sender authenticates with the
+/// `x-rxx-id` and `x-rxx-nonce` headers and names the recipient in
+/// `x-rxx-recipient`. Uploads that would exceed the recipient's quota are
+/// rejected with 413; the quota is checked again when the item is added, as
+/// other uploads may have finished meanwhile.
+async fn mailbox_upload(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Response {
+    let Some(mailbox) = &state.mailbox else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    let (id, nonce, recipient) = (
+        header("x-rxx-id"),
+        header("x-rxx-nonce"),
+        header("x-rxx-recipient"),
+    );
+
+    let remaining = {
+        let db = state.db.lock().unwrap();
+        if let Err(status) = authenticate(&db, &id, &nonce) {
+            return status.into_response();
+        }
+        match db.presence(&recipient) {
+            Ok(Some(_)) => {}
+            Ok(None) => return StatusCode::NOT_FOUND.into_response(),
+            Err(e) => {
+                eprintln!("Mailbox error: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+        match db.mailbox_usage(&recipient) {
+            Ok(usage) => mailbox.quota.saturating_sub(usage),
+            Err(e) => {
+                eprintln!("Mailbox error: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    };
+
+    let item_id = hex::encode(rand::random::<[u8; 16]>());
+    let path = mailbox.dir.join(&item_id);
+    let partial = path.with_extension("part");
+
+    let size = match store_upload(body, &partial, remaining).await {
+        Ok(Some(size)) => size,
+        Ok(None) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            println!(
+                "Mailbox of {} is full, rejected item from {}",
+                recipient, id
+            );
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+        Err(e) => {
+            let _ = tokio::fs::remove_file(&partial).await;
+            eprintln!("Mailbox upload error: {}", e);
+            return StatusCode::BAD_REQUEST.into_response();
+        }
+    };
+
+    if let Err(e) = tokio::fs::rename(&partial, &path).await {
+        eprintln!("Mailbox upload error: {}", e);
+        let _ = tokio::fs::remove_file(&partial).await;
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    let db = state.db.lock().unwrap();
+    match db.mailbox_add(&item_id, &recipient, &id, size, mailbox.quota) {
+        Ok(true) => {}
+        Ok(false) => {
+            let _ = std::fs::remove_file(&path);
+            println!(
+                "Mailbox of {} is full, rejected item from {}",
+                recipient, id
+            );
+            return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+        }
+        Err(e) => {
+            eprintln!("Mailbox error: {}", e);
+            let _ = std::fs::remove_file(&path);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    println!(
+        "Stored mailbox item {} ({} bytes) from {} for {}",
+        item_id, size, id, recipient
+    );
+    Json(serde_json::json!({ "item_id": item_id })).into_response()
+}
+
+/// Write an upload to `path`. Returns `None` if it is larger than `limit`.
+async fn store_upload(body: Body, path: &Path, limit: u64) -> Result<Option<u64>> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut stream = body.into_data_stream();
+    let mut size = 0u64;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        if size > limit {
+            return Ok(None);
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+    Ok(Some(size))
+}
+
+async fn mailbox_list(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<MailboxRequest>,
+) -> Response {
+    if state.mailbox.is_none() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let db = state.db.lock().unwrap();
+    if let Err(status) = authenticate(&db, &req.id, &req.nonce) {
+        return status.into_response();
+    }
+
+    match db.mailbox_list(&req.id) {
+        Ok(items) => {
+            let items: Vec<MailboxEntry> = items
+                .into_iter()
+                .map(|item| MailboxEntry {
+                    item_id: item.item_id,
+                    sender: item.sender,
+                    size: item.size,
+                    created_at: item.created_at,
+                })
+                .collect();
+            Json(serde_json::json!({ "items": items })).into_response()
+        }
+        Err(e) => {
+            eprintln!("Mailbox error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Stream a stored item back to its recipient.
+async fn mailbox_fetch(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<MailboxRequest>,
+) -> Response {
+    let Some(mailbox) = &state.mailbox else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let item = {
+        let db = state.db.lock().unwrap();
+        if let Err(status) = authenticate(&db, &req.id, &req.nonce) {
+            return status.into_response();
+        }
+        // Only items addressed to the caller can be fetched
+        match db.mailbox_list(&req.id) {
+            Ok(items) => items
+                .into_iter()
+                .find(|item| Some(&item.item_id) == req.item_id.as_ref()),
+            Err(e) => {
+                eprintln!("Mailbox error: {}", e);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    };
+    let Some(item) = item else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let file = match tokio::fs::File::open(mailbox.dir.join(&item.item_id)).await {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Mailbox fetch error: {}", e);
+            return StatusCode::NOT_FOUND.into_response();
+        }
+    };
+
+    let stream = futures_util::stream::unfold(file, |mut file| async move {
+        let mut buf = vec![0u8; 64 * 1024];
+        match file.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), file))
+            }
+            Err(e) => Some((Err(e), file)),
+        }
+    });
+    (
+        [(header::CONTENT_LENGTH, item.size.to_string())],
+        Body::from_stream(stream),
+    )
+        .into_response()
+}
+
+/// Delete an item once its recipient has stored it.
+async fn mailbox_delete(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<MailboxRequest>,
+) -> Response {
+    let Some(mailbox) = &state.mailbox else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let db = state.db.lock().unwrap();
+    if let Err(status) = authenticate(&db, &req.id, &req.nonce) {
+        return status.into_response();
+    }
+    let Some(item_id) = req.item_id.as_deref() else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+
+    match db.mailbox_remove(&req.id, item_id) {
+        Ok(true) => {
+            if let Err(e) = std::fs::remove_file(mailbox.dir.join(item_id)) {
+                eprintln!("Failed to delete mailbox item {}: {}", item_id, e);
+            }
+            Json(serde_json::json!({})).into_response()
+        }
+        Ok(false) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => {
+            eprintln!("Mailbox error: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Delete mailbox items that outlived the TTL.
+fn expire_mailbox(state: &AppState, mailbox: &MailboxSettings) {
+    let expired = match state
+        .db
+        .lock()
+        .unwrap()
+        .mailbox_expire(mailbox.ttl.as_secs() as i64)
+    {
+        Ok(expired) => expired,
+        Err(e) => {
+            eprintln!("Mailbox cleanup error: {}", e);
+            return;
+        }
+    };
+    for item_id in expired {
+        println!("Mailbox item {} expired", item_id);
+        if let Err(e) = std::fs::remove_file(mailbox.dir.join(&item_id)) {
+            eprintln!("Failed to delete mailbox item {}: {}", item_id, e);
+        }
+    }
+}
+
+pub async fn run_server(db_path: &str, port: u16, mailbox: Option<MailboxSettings>) -> Result<()> {
     let db = Database::open(db_path)?;
+
+    if let Some(mailbox) = &mailbox {
+        std::fs::create_dir_all(&mailbox.dir)?;
+        println!(
+            "Mailbox enabled in {:?} (quota {} bytes per recipient, items kept {} days)",
+            mailbox.dir,
+            mailbox.quota,
+            mailbox.ttl.as_secs() / 86400
+        );
+    }
+
     let state = Arc::new(AppState {
         db: Arc::new(Mutex::new(db)),
+        listeners: Mutex::new(HashMap::new()),
+        mailbox: mailbox.clone(),
     });
 
+    if let Some(mailbox) = mailbox {
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(MAILBOX_CLEANUP_SECS));
+            loop {
+                ticker.tick().await;
+                expire_mailbox(&state, &mailbox);
+            }
+        });
+    }
+
     let app = Router::new()
         .route("/register", post(register))
         .route("/update", post(update))
+        .route("/listen", post(listen))
+        .route("/heartbeat", post(heartbeat))
+        .route("/status", post(status))
+        .route("/mailbox/key", post(mailbox_key))
+        .route("/mailbox/upload", post(mailbox_upload))
+        .route("/mailbox/list", post(mailbox_list))
+        .route("/mailbox/fetch", post(mailbox_fetch))
+        .route("/mailbox/delete", post(mailbox_delete))
         .with_state(state);
 
     let addr = format!("[::]:{}", port);
@@ -113,3 +817,106 @@ pub async fn run_server(db_path: &str, port: u16) -> Result<()> {
     axum::serve(listener, app).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(peer_id: &str, start_at: Instant) -> RendezvousRequest {
+        RendezvousRequest {
+            peer_id: peer_id.to_string(),
+            peer_ipv6: "2001:db8::1".to_string(),
+            session_id: String::new(),
+            session_key: String::new(),
+            start_in_ms: RENDEZVOUS_DELAY_MS,
+            start_at,
+        }
+    }
+
+    #[test]
+    fn test_take_pending() {
+        let now = Instant::now();
+        let mut listener = Listener {
+            pending: vec![
+                request("soon", now + Duration::from_millis(400)),
+                request("late", now - Duration::from_secs(2)),
+                request(
+                    "stale",
+                    now - Duration::from_secs(RENDEZVOUS_EXPIRY_SECS + 1),
+                ),
+            ],
+            notify: Arc::new(Notify::new()),
+            last_poll: now,
+        };
+
+        let taken = listener.take_pending(now);
+        assert!(listener.pending.is_empty());
+        assert_eq!(taken.len(), 2);
+        assert_eq!(taken[0].peer_id, "soon");
+        assert_eq!(taken[0].start_in_ms, 400);
+        assert_eq!(taken[1].peer_id, "late");
+        assert_eq!(taken[1].start_in_ms, 0);
+    }
+
+    #[test]
+    fn test_expire_listeners() {
+        let now = Instant::now();
+        let listener = |last_poll| Listener {
+            pending: Vec::new(),
+            notify: Arc::new(Notify::new()),
+            last_poll,
+        };
+        let mut listeners = HashMap::from([
+            ("bob".to_string(), listener(now)),
+            (
+                "gone".to_string(),
+                listener(now - Duration::from_secs(LISTENER_TTL_SECS + 1)),
+            ),
+        ]);
+        expire_listeners(&mut listeners, now);
+        assert_eq!(listeners.keys().collect::<Vec<_>>(), ["bob"]);
+    }
+
+    #[test]
+    fn test_status_only_for_contacts() {
+        let db = Database::open(":memory:").unwrap();
+        db.register("bob", "2001:db8::1").unwrap();
+        db.heartbeat("bob", true).unwrap();
+        db.set_contacts("bob", &["alice".to_string()]).unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+
+        let status = peer_status(&db, "Alice", "Bob", now).unwrap();
+        assert!(status.listening && !status.hidden);
+
+        let status = peer_status(&db, "mallory", "bob", now).unwrap();
+        assert!(status.registered && status.hidden);
+        assert!(!status.online && status.last_seen_secs.is_none());
+
+        assert!(!peer_status(&db, "bob", "bob", now).unwrap().hidden);
+        assert!(
+            !peer_status(&db, "mallory", "carol", now)
+                .unwrap()
+                .registered
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store_upload_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("upload.part");
+        let size = store_upload(Body::from("0123456789"), &path, 10)
+            .await
+            .unwrap();
+        assert_eq!(size, Some(10));
+        assert_eq!(std::fs::read(&path).unwrap(), b"0123456789");
+        assert_eq!(
+            store_upload(Body::from("0123456789"), &path, 9)
+                .await
+                .unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,709 @@
+use anyhow::{bail, Context, Result};
+use quinn::{Connection, RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::compress::Compress;
+use crate::config::Config;
+use crate::history::{Direction, Transfer};
+use crate::meta::Preserve;
+use crate::quic;
+
+/// Pulling files from a peer running `rxx share`.
+///
+/// A request stream starts with an opcode and a length-prefixed path
+/// relative to the shared root. The exporter answers with a status byte;
+/// on success the file follows in the same format a push uses, otherwise a
+/// length-prefixed error message.
+pub const OP_GET: u8 = 1;
+/// List a directory; the path is followed by a recursive flag byte and the
+/// answer is a JSON array of `Entry`
+pub const OP_LIST: u8 = 2;
+/// Store a file in a directory of a writable share; the file follows the
+/// path and the status is sent once it is stored
+pub const OP_PUT: u8 = 3;
+/// Delete a file from a writable share
+pub const OP_DELETE: u8 = 4;
+const STATUS_OK: u8 = 0;
+const STATUS_ERROR: u8 = 1;
+const MAX_PATH_LEN: u32 = 4096;
+/// Largest listing a client accepts
+const MAX_LISTING_LEN: u64 = 64 * 1024 * 1024;
+/// Files of rxx itself (e.g. sync state) start with this and are not listed
+pub const INTERNAL_PREFIX: &str = ".rxx-";
+
+/// A file or directory in a listing
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Path relative to the listed directory, `/`-separated
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u64,
+    /// Modification time in seconds since the Unix epoch
+    pub mtime: i64,
+    /// SHA256 of the content, for files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+}
+
+impl Entry {
+    /// One line of `rxx ls` output.
+    pub fn describe(&self, now: SystemTime) -> String {
+        let modified = UNIX_EPOCH + Duration::from_secs(self.mtime.max(0) as u64);
+        let age = crate::net::format_age(now.duration_since(modified).unwrap_or_default());
+        if self.is_dir {
+            format!("{:>12}  {:>4}  {:12}  {}/", "-", age, "", self.name)
+        } else {
+            let hash = self.sha256.as_deref().unwrap_or_default();
+            format!(
+                "{:>12}  {:>4}  {:12}  {}",
+                self.size,
+                age,
+                &hash[..hash.len().min(12)],
+                self.name
+            )
+        }
+    }
+}
+
+/// Hash of a file, valid while its size and mtime are unchanged
+type CachedHash = (u64, SystemTime, String);
+
+/// A directory exported by `rxx share`.
+#[derive(Debug)]
+pub struct Share {
+    root: PathBuf,
+    /// Peers that may upload and delete files (`rxx share --writable
+    /// --allow <id>`), lowercase; none for a read-only share
+    writers: Vec<String>,
+    hashes: Mutex<HashMap<PathBuf, CachedHash>>,
+}
+
+impl Share {
+    pub fn new(root: &Path, writers: &[String]) -> Result<Self> {
+        let root = root
+            .canonicalize()
+            .with_context(|| format!("Failed to open shared directory {:?}", root))?;
+        if !root.is_dir() {
+            bail!("{:?} is not a directory", root);
+        }
+        Ok(Share {
+            root,
+            writers: writers.iter().map(|id| id.to_lowercase()).collect(),
+            hashes: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn is_writable(&self) -> bool {
+        !self.writers.is_empty()
+    }
+
+    /// Whether `peer`, the ID we expected the connection from, may change
+    /// the share. Peers we only know by address never may.
+    fn check_writer(&self, peer: Option<&str>) -> Result<()> {
+        if !self.is_writable() {
+            bail!("Share is read-only");
+        }
+        match peer {
+            Some(peer) if self.writers.contains(&peer.to_lowercase()) => Ok(()),
+            Some(peer) => bail!("{} may not change this share", peer),
+            None => bail!("Only peers known by ID may change this share"),
+        }
+    }
+
+    /// Map a requested path to a file inside the share.
+    ///
+    /// A symlink stays a symlink: what it points to must be a file in the
+    /// share, but the path returned is the link's own, so the file is sent
+    /// under the requested name and deleting it removes only the link.
+    pub fn resolve(&self, requested: &str) -> Result<PathBuf> {
+        if !self.locate(requested)?.is_file() {
+            bail!("Not a file: {}", requested);
+        }
+        let path = self.join(requested)?;
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            bail!("Not a file: {}", requested);
+        };
+        let parent = parent
+            .canonicalize()
+            .with_context(|| format!("No such file: {}", requested))?;
+        if !parent.starts_with(&self.root) {
+            bail!("Path {:?} leaves the shared directory", requested);
+        }
+        Ok(parent.join(name))
+    }
+
+    /// Create a directory of the share for incoming files and return it.
+    pub fn prepare_dir(&self, requested: &str) -> Result<PathBuf> {
+        let path = self.join(requested)?;
+        // Check what exists before creating anything, so a symlink in the
+        // share cannot get directories created outside it
+        let mut existing = path.as_path();
+        while !existing.exists() {
+            existing = existing
+                .parent()
+                .with_context(|| format!("Failed to create directory {}", requested))?;
+        }
+        if !existing.canonicalize()?.starts_with(&self.root) {
+            bail!("Path {:?} leaves the shared directory", requested);
+        }
+        std::fs::create_dir_all(&path)
+            .with_context(|| format!("Failed to create directory {}", requested))?;
+        self.locate(requested)
+    }
+
+    /// Map a requested path into the share.
+    ///
+    /// `..` is rejected outright, and the result is checked again after
+    /// resolving symlinks, so neither can reach outside the root.
+    fn locate(&self, requested: &str) -> Result<PathBuf> {
+        let path = self
+            .join(requested)?
+            .canonicalize()
+            .with_context(|| format!("No such file: {}", requested))?;
+        if !path.starts_with(&self.root) {
+            bail!("Path {:?} leaves the shared directory", requested);
+        }
+        Ok(path)
+    }
+
+    /// Append a requested path to the root without touching the filesystem.
+    fn join(&self, requested: &str) -> Result<PathBuf> {
+        let mut path = self.root.clone();
+        for component in Path::new(requested).components() {
+            match component {
+                Component::Normal(part) => path.push(part),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => {
+                    bail!("Path {:?} leaves the shared directory", requested)
+                }
+            }
+        }
+        Ok(path)
+    }
+
+    /// List a directory of the share, sorted by name.
+    ///
+    /// Symlinks are followed as long as they stay inside the share; anything
+    /// pointing outside is left out, as are names that are not UTF-8.
+    pub fn list(&self, requested: &str, recursive: bool) -> Result<Vec<Entry>> {
+        let dir = self.locate(requested)?;
+        if !dir.is_dir() {
+            bail!("Not a directory: {}", requested);
+        }
+        let mut entries = Vec::new();
+        let mut visited = vec![dir.clone()];
+        self.list_into(&dir, "", recursive, &mut entries, &mut visited)?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn list_into(
+        &self,
+        dir: &Path,
+        prefix: &str,
+        recursive: bool,
+        entries: &mut Vec<Entry>,
+        visited: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if name.starts_with(INTERNAL_PREFIX) {
+                continue;
+            }
+            let Ok(path) = entry.path().canonicalize() else {
+                continue;
+            };
+            if !path.starts_with(&self.root) {
+                continue;
+            }
+
+            let metadata = std::fs::metadata(&path)?;
+            let name = format!("{}{}", prefix, name);
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |age| age.as_secs() as i64);
+
+            if metadata.is_dir() {
+                entries.push(Entry {
+                    name: name.clone(),
+                    is_dir: true,
+                    size: 0,
+                    mtime,
+                    sha256: None,
+                });
+                // Symlinks can lead back to a directory being listed
+                if recursive && !visited.contains(&path) {
+                    visited.push(path.clone());
+                    self.list_into(&path, &format!("{}/", name), true, entries, visited)?;
+                    visited.pop();
+                }
+            } else if metadata.is_file() {
+                entries.push(Entry {
+                    name,
+                    is_dir: false,
+                    size: metadata.len(),
+                    mtime,
+                    sha256: Some(self.hash(&path, &metadata)?),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// SHA256 of a file, cached until its size or mtime change.
+    fn hash(&self, path: &Path, metadata: &std::fs::Metadata) -> Result<String> {
+        let modified = metadata.modified()?;
+        if let Some((size, time, hash)) = self.hashes.lock().unwrap().get(path) {
+            if *size == metadata.len() && *time == modified {
+                return Ok(hash.clone());
+            }
+        }
+
+        let mut file = std::fs::File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; 64 * 1024];
+        loop {
+            let n = file.read(&mut buffer)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buffer[..n]);
+        }
+        let hash = hex::encode(hasher.finalize());
+
+        self.hashes
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), (metadata.len(), modified, hash.clone()));
+        Ok(hash)
+    }
+}
+
+async fn write_string<W: AsyncWrite + Unpin>(writer: &mut W, value: &str) -> Result<()> {
+    writer.write_u32(value.len() as u32).await?;
+    writer.write_all(value.as_bytes()).await?;
+    Ok(())
+}
+
+async fn read_string<R: AsyncRead + Unpin>(reader: &mut R) -> Result<String> {
+    let len = reader.read_u32().await?;
+    if len > MAX_PATH_LEN {
+        bail!("String too long ({} bytes)", len);
+    }
+    let mut bytes = vec![0u8; len as usize];
+    reader.read_exact(&mut bytes).await?;
+    String::from_utf8(bytes).context("Invalid UTF-8")
+}
+
+/// Serve the requests on one connection from a peer until it closes.
+/// `peer` is the ID we punched towards for this connection, if any.
+pub async fn serve(
+    connection: &Connection,
+    share: &Share,
+    config: &Config,
+    peer: Option<&str>,
+) -> Result<()> {
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(streams) => streams,
+            Err(quinn::ConnectionError::ApplicationClosed(_)) => return Ok(()),
+            Err(e) => return Err(e).context("Failed to accept request stream"),
+        };
+        // A failed request does not end the others
+        let remote = connection.remote_address();
+        if let Err(e) = serve_request(remote, peer, share, config, send, recv).await {
+            eprintln!("Request from {} failed: {:#}", remote, e);
+        }
+    }
+}
+
+async fn serve_request(
+    remote: SocketAddr,
+    peer: Option<&str>,
+    share: &Share,
+    config: &Config,
+    mut send: SendStream,
+    mut recv: RecvStream,
+) -> Result<()> {
+    let op = recv.read_u8().await.context("Failed to read request")?;
+    let requested = match op {
+        OP_GET | OP_LIST | OP_PUT | OP_DELETE => read_string(&mut recv).await?,
+        other => {
+            let error = anyhow::anyhow!("Unknown request {}", other);
+            refuse(&mut send, "", error).await?;
+            return finish(send).await;
+        }
+    };
+
+    match op {
+        OP_GET => {
+            crate::info!("{} requested {}", remote, requested);
+            match share.resolve(&requested) {
+                Ok(path) => {
+                    send.write_u8(STATUS_OK).await?;
+                    let mut entry = Transfer::start(Direction::Sent, Some(remote.to_string()));
+                    entry.expected_peer(peer);
+                    entry.local_file(&path);
+                    let mut throttled = crate::qos::Throttle::new(&mut send);
+                    let result = quic::write_file(
+                        &mut throttled,
+                        &path,
+                        &config.user_id,
+                        None,
+                        Compress::None,
+                    )
+                    .await;
+                    if let Ok((total, hash)) = result {
+                        entry.content(total, hash);
+                    }
+                    entry.finish(&result).await;
+                    result?;
+                }
+                Err(e) => refuse(&mut send, &requested, e).await?,
+            }
+        }
+        OP_LIST => {
+            let recursive = recv.read_u8().await? != 0;
+            crate::info!("{} listed {}", remote, requested);
+            // Hashing may read a lot of data
+            match tokio::task::block_in_place(|| share.list(&requested, recursive)) {
+                Ok(entries) => {
+                    send.write_u8(STATUS_OK).await?;
+                    send.write_all(&serde_json::to_vec(&entries)?).await?;
+                }
+                Err(e) => refuse(&mut send, &requested, e).await?,
+            }
+        }
+        OP_PUT => {
+            crate::info!("{} uploads to {}", remote, requested);
+            let dir = share
+                .check_writer(peer)
+                .and_then(|_| share.prepare_dir(&requested));
+            match dir {
+                Ok(dir) => {
+                    let mut entry = Transfer::start(Direction::Received, Some(remote.to_string()));
+                    entry.expected_peer(peer);
+                    let result = async {
+                        let header = quic::read_header(&mut recv).await?;
+                        header.check_sender(peer)?;
+                        let keep = Preserve::from_config(config);
+                        quic::read_content(&mut recv, None, header, &dir, false, keep, config).await
+                    }
+                    .await;
+                    if let Ok(received) = &result {
+                        entry.received(received);
+                    }
+                    entry.finish(&result).await;
+                    match result {
+                        Ok(_) => send.write_u8(STATUS_OK).await?,
+                        Err(e) => refuse(&mut send, &requested, e).await?,
+                    }
+                }
+                Err(e) => refuse(&mut send, &requested, e).await?,
+            }
+        }
+        _ => {
+            crate::info!("{} deletes {}", remote, requested);
+            let path = share
+                .check_writer(peer)
+                .and_then(|_| share.resolve(&requested));
+            match path.and_then(|path| Ok(std::fs::remove_file(path)?)) {
+                Ok(()) => send.write_u8(STATUS_OK).await?,
+                Err(e) => refuse(&mut send, &requested, e).await?,
+            }
+        }
+    }
+
+    finish(send).await
+}
+
+async fn refuse(send: &mut SendStream, requested: &str, error: anyhow::Error) -> Result<()> {
+    crate::info!("Refusing request for {}: {}", requested, error);
+    send.write_u8(STATUS_ERROR).await?;
+    write_string(send, &error.to_string()).await
+}
+
+async fn finish(mut send: SendStream) -> Result<()> {
+    send.finish().context("Failed to finish stream")?;
+    // Wait until the peer has everything before moving on
+    let _ = send.stopped().await;
+    Ok(())
+}
+
+async fn read_status(recv: &mut RecvStream) -> Result<()> {
+    match recv.read_u8().await.context("Peer closed the request")? {
+        STATUS_OK => Ok(()),
+        _ => bail!("Peer refused: {}", read_string(recv).await?),
+    }
+}
+
+/// Ask the share of `peer` for `path` and store it in `output_dir`.
+pub async fn get(
+    connection: &Connection,
+    path: &str,
+    output_dir: &Path,
+    peer: &str,
+    config: &crate::config::Config,
+) -> Result<()> {
+    let (mut send, mut recv) = connection
+        .open_bi()
+        .await
+        .context("Failed to open request stream")?;
+
+    send.write_u8(OP_GET).await?;
+    write_string(&mut send, path).await?;
+    send.finish().context("Failed to finish request")?;
+
+    read_status(&mut recv).await?;
+    let mut entry = Transfer::start(
+        Direction::Received,
+        Some(connection.remote_address().to_string()),
+    );
+    entry.expected_peer(Some(peer));
+    let keep = Preserve::from_config(config);
+    let result = quic::read_file(&mut recv, None, output_dir, false, keep, config).await;
+    if let Ok(received) = &result {
+        entry.received(received);
+    }
+    entry.finish(&result).await;
+    result.map(|_| ())
+}
+
+/// Upload `file` into the directory `remote_dir` of `peer`'s writable share.
+pub async fn put(
+    connection: &Connection,
+    file: &Path,
+    remote_dir: &str,
+    user_id: &str,
+    peer: &str,
+) -> Result<()> {
+    let (mut send, mut recv) = connection
+        .open_bi()
+        .await
+        .context("Failed to open request stream")?;
+
+    send.write_u8(OP_PUT).await?;
+    write_string(&mut send, remote_dir).await?;
+    // A refusal stops the upload; report the reason rather than the
+    // write error it causes
+    let mut entry = Transfer::start(
+        Direction::Sent,
+        Some(connection.remote_address().to_string()),
+    );
+    entry.peer = Some(peer.to_string());
+    entry.local_file(file);
+    let mut throttled = crate::qos::Throttle::new(&mut send);
+    let written = quic::write_file(&mut throttled, file, user_id, None, Compress::None).await;
+    let _ = send.finish();
+
+    let result = match read_status(&mut recv).await {
+        Ok(()) => written,
+        Err(e) => written.and(Err(e)),
+    };
+    if let Ok((total, hash)) = result {
+        entry.content(total, hash);
+    }
+    entry.finish(&result).await;
+    result.map(|_| ())
+}
+
+/// Delete a file from a writable share.
+pub async fn delete(connection: &Connection, path: &str) -> Result<()> {
+    let (mut send, mut recv) = connection
+        .open_bi()
+        .await
+        .context("Failed to open request stream")?;
+
+    send.write_u8(OP_DELETE).await?;
+    write_string(&mut send, path).await?;
+    send.finish().context("Failed to finish request")?;
+
+    read_status(&mut recv).await
+}
+
+/// Ask the exporter what is in the directory `path`.
+pub async fn list(connection: &Connection, path: &str, recursive: bool) -> Result<Vec<Entry>> {
+    let (mut send, mut recv) = connection
+        .open_bi()
+        .await
+        .context("Failed to open request stream")?;
+
+    send.write_u8(OP_LIST).await?;
+    write_string(&mut send, path).await?;
+    send.write_u8(recursive as u8).await?;
+    send.finish().context("Failed to finish request")?;
+
+    read_status(&mut recv).await?;
+    let mut listing = Vec::new();
+    (&mut recv)
+        .take(MAX_LISTING_LEN)
+        .read_to_end(&mut listing)
+        .await
+        .context("Failed to read listing")?;
+    serde_json::from_slice(&listing).context("Invalid listing")
+}
+
+/// Split `alice:/docs/report.pdf` (or `[2001:db8::1]:/docs/report.pdf`)
+/// into the peer and the path.
+pub fn parse_remote(remote: &str) -> Result<(String, String)> {
+    let (peer, path) = if let Some(rest) = remote.strip_prefix('[') {
+        rest.split_once("]:").context("Expected [address]:path")?
+    } else {
+        remote.split_once(':').context("Expected peer:path")?
+    };
+    if peer.is_empty() || path.is_empty() {
+        bail!("Expected peer:path");
+    }
+    Ok((peer.to_string(), path.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_confines_to_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let root = base.join("shared");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("docs/report.pdf"), b"report").unwrap();
+        std::fs::write(base.join("secret"), b"secret").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(base.join("secret"), root.join("link")).unwrap();
+
+        let share = Share::new(&root, &[]).unwrap();
+        let report = share.root().join("docs/report.pdf");
+        assert_eq!(share.resolve("docs/report.pdf").unwrap(), report);
+        assert_eq!(share.resolve("/docs/report.pdf").unwrap(), report);
+        assert!(share.resolve("./docs/../docs/report.pdf").is_err());
+
+        assert!(share.resolve("../secret").is_err());
+        assert!(share.resolve("/../secret").is_err());
+        assert!(share.resolve("docs").is_err());
+        assert!(share.resolve("missing").is_err());
+        #[cfg(unix)]
+        {
+            assert!(share.resolve("link").is_err());
+            // Links inside the share keep their own name
+            std::os::unix::fs::symlink("docs/report.pdf", root.join("alias")).unwrap();
+            let alias = share.resolve("alias").unwrap();
+            assert_eq!(alias, share.root().join("alias"));
+            assert!(alias.is_symlink());
+        }
+    }
+
+    #[test]
+    fn test_list() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let root = base.join("shared");
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        std::fs::write(root.join("a.txt"), b"hello").unwrap();
+        std::fs::write(root.join("docs/b.txt"), b"").unwrap();
+        std::fs::write(root.join(".rxx-sync.json"), b"{}").unwrap();
+        std::fs::write(base.join("secret"), b"secret").unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(base.join("secret"), root.join("outside")).unwrap();
+            std::os::unix::fs::symlink(&root, root.join("docs/loop")).unwrap();
+        }
+
+        let share = Share::new(&root, &[]).unwrap();
+        let top: Vec<String> = share
+            .list("/", false)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(top, ["a.txt", "docs"]);
+
+        let all = share.list("", true).unwrap();
+        let names: Vec<&str> = all.iter().map(|entry| entry.name.as_str()).collect();
+        #[cfg(unix)]
+        assert_eq!(names, ["a.txt", "docs", "docs/b.txt", "docs/loop"]);
+        assert_eq!(all[0].size, 5);
+        assert_eq!(
+            all[0].sha256.as_deref(),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+        assert!(all[1].is_dir && all[1].sha256.is_none());
+
+        assert_eq!(share.list("docs", false).unwrap().len(), 2);
+        #[cfg(unix)]
+        {
+            // Siblings linking to each other
+            std::fs::create_dir(root.join("x")).unwrap();
+            std::fs::create_dir(root.join("y")).unwrap();
+            std::os::unix::fs::symlink("../y", root.join("x/l")).unwrap();
+            std::os::unix::fs::symlink("../x", root.join("y/l")).unwrap();
+            let names: Vec<String> = share
+                .list("", true)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.name)
+                .filter(|name| name.starts_with('x') || name.starts_with('y'))
+                .collect();
+            assert_eq!(names, ["x", "x/l", "x/l/l", "y", "y/l", "y/l/l"]);
+        }
+        assert!(share.list("a.txt", false).is_err());
+        assert!(share.list("..", false).is_err());
+
+        let new_dir = share.prepare_dir("new/sub").unwrap();
+        assert_eq!(new_dir, share.root().join("new/sub"));
+        assert!(new_dir.is_dir());
+        assert!(share.prepare_dir("../escape").is_err());
+        #[cfg(unix)]
+        {
+            std::fs::create_dir(base.join("elsewhere")).unwrap();
+            std::os::unix::fs::symlink(base.join("elsewhere"), root.join("out")).unwrap();
+            assert!(share.prepare_dir("out/new").is_err());
+            assert!(!base.join("elsewhere/new").exists());
+        }
+    }
+
+    #[test]
+    fn test_check_writer() {
+        let root = std::env::temp_dir();
+        let read_only = Share::new(&root, &[]).unwrap();
+        assert!(read_only.check_writer(Some("alice")).is_err());
+
+        let share = Share::new(&root, &["Alice".to_string()]).unwrap();
+        assert!(share.check_writer(Some("alice")).is_ok());
+        assert!(share.check_writer(Some("ALICE")).is_ok());
+        assert!(share.check_writer(Some("mallory")).is_err());
+        assert!(share.check_writer(None).is_err());
+    }
+
+    #[test]
+    fn test_parse_remote() {
+        assert_eq!(
+            parse_remote("alice:/docs/report.pdf").unwrap(),
+            ("alice".to_string(), "/docs/report.pdf".to_string())
+        );
+        assert_eq!(
+            parse_remote("[2001:db8::1]:report.pdf").unwrap(),
+            ("2001:db8::1".to_string(), "report.pdf".to_string())
+        );
+        assert!(parse_remote("alice").is_err());
+        assert!(parse_remote(":/x").is_err());
+        assert!(parse_remote("[2001:db8::1]").is_err());
+    }
+}
@@ -0,0 +1,299 @@
+use anyhow::{bail, Context, Result};
+use indicatif::ProgressBar;
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+
+/// Sparse files are sent as their data extents and the holes between them,
+/// so neither side reads or writes the zeros. The content is a series of
+/// frames: `DATA` (u32 length and the bytes), `HOLE` (u64 length) and
+/// `END`. The SHA256 covers the frames rather than the zeros, so checking
+/// it takes no longer than receiving them.
+const FRAME_END: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_HOLE: u8 = 2;
+const MAX_DATA: usize = 64 * 1024;
+/// Zeros a sink that has to write them may get for free, and for each byte
+/// of data after that, so a tiny transfer cannot produce endless output
+const FREE_ZEROS: u64 = 1 << 30;
+const ZEROS_PER_BYTE: u64 = 1 << 16;
+
+/// Where received content is written.
+pub trait Sink: AsyncWrite + Unpin {
+    /// Whether `skip` writes the zeros out.
+    const WRITES_ZEROS: bool = true;
+
+    /// Skip `len` zero bytes.
+    fn skip(&mut self, len: u64) -> impl std::future::Future<Output = std::io::Result<()>>;
+}
+
+/// Files get a hole instead of the zeros.
+impl Sink for File {
+    const WRITES_ZEROS: bool = false;
+
+    async fn skip(&mut self, len: u64) -> std::io::Result<()> {
+        let end = self.seek(SeekFrom::Current(len as i64)).await?;
+        // Received files are written front to back, so this only extends
+        self.set_len(end).await
+    }
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> Sink for Box<W> {
+    async fn skip(&mut self, len: u64) -> std::io::Result<()> {
+        write_zeros(self, len).await
+    }
+}
+
+impl Sink for Vec<u8> {
+    async fn skip(&mut self, len: u64) -> std::io::Result<()> {
+        write_zeros(self, len).await
+    }
+}
+
+async fn write_zeros<W: AsyncWrite + Unpin + ?Sized>(out: &mut W, len: u64) -> std::io::Result<()> {
+    let zeros = [0u8; 64 * 1024];
+    let mut left = len;
+    while left > 0 {
+        let n = left.min(zeros.len() as u64) as usize;
+        out.write_all(&zeros[..n]).await?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+fn hash_hole(hasher: &mut Sha256, len: u64) {
+    hasher.update([FRAME_HOLE]);
+    hasher.update(len.to_be_bytes());
+}
+
+fn hash_data(hasher: &mut Sha256, data: &[u8]) {
+    hasher.update([FRAME_DATA]);
+    hasher.update((data.len() as u32).to_be_bytes());
+    hasher.update(data);
+}
+
+/// The (offset, length) ranges of a file that hold data, if it has holes.
+#[cfg(target_os = "linux")]
+pub fn data_extents(path: &Path) -> Option<Vec<(u64, u64)>> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::File::open(path).ok()?;
+    let metadata = file.metadata().ok()?;
+    let len = metadata.len();
+    if metadata.blocks() * 512 >= len {
+        return None;
+    }
+
+    let fd = file.as_raw_fd();
+    let mut extents = Vec::new();
+    let mut pos = 0u64;
+    while pos < len {
+        // SAFETY: plain seeks on a descriptor we own for the whole loop
+        let start = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            if std::io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO) {
+                // Only a hole is left
+                break;
+            }
+            return None;
+        }
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return None;
+        }
+        let (start, end) = (start as u64, (end as u64).min(len));
+        if end > start {
+            extents.push((start, end - start));
+        }
+        pos = end;
+    }
+    Some(extents)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn data_extents(_path: &Path) -> Option<Vec<(u64, u64)>> {
+    None
+}
+
+/// Send the `len` bytes of `file` as data and hole frames, reading only the
+/// `extents` that hold data. Returns the SHA256 of the frames.
+pub async fn send_sparse<W: AsyncWrite + Unpin>(
+    file: &mut File,
+    send: &mut W,
+    extents: &[(u64, u64)],
+    len: u64,
+    pb: &ProgressBar,
+) -> Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; MAX_DATA];
+    let mut pos = 0u64;
+
+    for &(start, extent_len) in extents.iter().chain([(len, 0)].iter()) {
+        if start > pos {
+            send.write_u8(FRAME_HOLE).await?;
+            send.write_u64(start - pos).await?;
+            hash_hole(&mut hasher, start - pos);
+            pb.inc(start - pos);
+            pos = start;
+        }
+
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut left = extent_len;
+        while left > 0 {
+            let want = left.min(buffer.len() as u64) as usize;
+            let n = file
+                .read(&mut buffer[..want])
+                .await
+                .context("Failed to read from file")?;
+            if n == 0 {
+                bail!("File shrank while sending");
+            }
+            send.write_u8(FRAME_DATA).await?;
+            send.write_u32(n as u32).await?;
+            send.write_all(&buffer[..n])
+                .await
+                .context("Failed to send file chunk")?;
+            hash_data(&mut hasher, &buffer[..n]);
+            left -= n as u64;
+            pos += n as u64;
+            pb.inc(n as u64);
+        }
+    }
+    send.write_u8(FRAME_END).await?;
+    Ok(hasher.finalize().into())
+}
+
+/// Write what `send_sparse` sent to `out`, at most `len` bytes. Returns
+/// the number of bytes and the SHA256 of the frames.
+pub async fn receive_sparse<R, W>(
+    recv: &mut R,
+    out: &mut W,
+    len: u64,
+    pb: &ProgressBar,
+) -> Result<(u64, [u8; 32])>
+where
+    R: AsyncRead + Unpin,
+    W: Sink + ?Sized,
+{
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; MAX_DATA];
+    let mut total = 0u64;
+    let mut zeros_allowed = FREE_ZEROS;
+
+    loop {
+        let frame = recv.read_u8().await.context("Failed to read frame")?;
+        let frame_len = match frame {
+            FRAME_END => break,
+            FRAME_DATA => recv.read_u32().await? as u64,
+            FRAME_HOLE => recv.read_u64().await?,
+            _ => bail!("Unknown sparse frame {}", frame),
+        };
+        if frame_len > len - total {
+            bail!("Sender sent more than the announced {} bytes", len);
+        }
+
+        if frame == FRAME_HOLE {
+            if W::WRITES_ZEROS {
+                if frame_len > zeros_allowed {
+                    bail!("Sender sent too large holes for the data it sent");
+                }
+                zeros_allowed -= frame_len;
+            }
+            out.skip(frame_len)
+                .await
+                .context("Failed to write to file")?;
+            hash_hole(&mut hasher, frame_len);
+        } else {
+            if frame_len > MAX_DATA as u64 {
+                bail!("Data frame too large ({} bytes)", frame_len);
+            }
+            let data = &mut buffer[..frame_len as usize];
+            recv.read_exact(data)
+                .await
+                .context("Failed to read from stream")?;
+            out.write_all(data)
+                .await
+                .context("Failed to write to file")?;
+            hash_data(&mut hasher, data);
+            zeros_allowed = zeros_allowed.saturating_add(frame_len * ZEROS_PER_BYTE);
+        }
+        total += frame_len;
+        pb.inc(frame_len);
+    }
+    Ok((total, hasher.finalize().into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sparse_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sparse");
+        // Data, a hole, data, and a hole at the end
+        let len = 8 * 1024 * 1024;
+        {
+            use std::io::{Seek, Write};
+            let mut file = std::fs::File::create(&path).unwrap();
+            file.set_len(len).unwrap();
+            file.write_all(b"head").unwrap();
+            file.seek(SeekFrom::Start(4 * 1024 * 1024)).unwrap();
+            file.write_all(&[7u8; 100_000]).unwrap();
+        }
+        let expected = Sha256::digest(std::fs::read(&path).unwrap());
+
+        // Whether the filesystem keeps the holes is up to it
+        let extents = data_extents(&path).unwrap_or_else(|| vec![(0, len)]);
+        assert!(extents.iter().all(|&(start, n)| start + n <= len));
+
+        let mut file = File::open(&path).await.unwrap();
+        let mut wire = Vec::new();
+        let pb = ProgressBar::hidden();
+        let hash = send_sparse(&mut file, &mut wire, &extents, len, &pb)
+            .await
+            .unwrap();
+
+        let mut out = Vec::new();
+        let (total, received) = receive_sparse(&mut wire.as_slice(), &mut out, len, &pb)
+            .await
+            .unwrap();
+        assert_eq!(total, len);
+        assert_eq!(received, hash);
+        assert_eq!(Sha256::digest(&out), expected);
+
+        // Nothing past the announced size is accepted
+        assert!(
+            receive_sparse(&mut wire.as_slice(), &mut Vec::new(), len - 1, &pb)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_large_holes() {
+        let len = 4u64 << 30;
+        let mut wire = vec![FRAME_HOLE];
+        wire.extend_from_slice(&len.to_be_bytes());
+        wire.push(FRAME_END);
+        let pb = ProgressBar::hidden();
+
+        // Only a file takes the hole without writing it out
+        assert!(
+            receive_sparse(&mut wire.as_slice(), &mut Vec::new(), len, &pb)
+                .await
+                .is_err()
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("holes");
+        let mut file = File::create(&path).await.unwrap();
+        let (total, _) = receive_sparse(&mut wire.as_slice(), &mut file, len, &pb)
+            .await
+            .unwrap();
+        assert_eq!(total, len);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+}
@@ -0,0 +1,286 @@
+use anyhow::{bail, Context, Result};
+use quinn::Connection;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use crate::config::Config;
+use crate::share::{self, Entry, Share};
+
+/// Hashes both sides agreed on after the last sync, per peer
+const STATE_FILE: &str = ".rxx-sync.json";
+
+/// Path relative to the synced directory -> SHA256
+type Manifest = BTreeMap<String, String>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Push,
+    Pull,
+    DeleteLocal,
+    DeleteRemote,
+    /// Changed differently on both sides since the last sync
+    Conflict,
+}
+
+impl Action {
+    fn label(&self) -> &'static str {
+        match self {
+            Action::Push => "push",
+            Action::Pull => "pull",
+            Action::DeleteLocal => "delete local",
+            Action::DeleteRemote => "delete remote",
+            Action::Conflict => "CONFLICT",
+        }
+    }
+}
+
+/// Decide what to do with every path, given the hashes on both sides and
+/// the ones they agreed on after the last sync (`base`).
+///
+/// A side whose hash differs from the base changed the file (a missing
+/// hash being a deletion or not yet existing). If only one side changed,
+/// its version wins; if both did, a modification wins over a deletion and
+/// two different modifications are a conflict.
+pub fn plan(local: &Manifest, remote: &Manifest, base: &Manifest) -> Vec<(String, Action)> {
+    let paths: BTreeSet<&String> = local
+        .keys()
+        .chain(remote.keys())
+        .chain(base.keys())
+        .collect();
+
+    let mut actions = Vec::new();
+    for path in paths {
+        let (ours, theirs, agreed) = (local.get(path), remote.get(path), base.get(path));
+        let action = if ours == theirs {
+            continue;
+        } else if theirs == agreed {
+            match ours {
+                Some(_) => Action::Push,
+                None => Action::DeleteRemote,
+            }
+        } else if ours == agreed {
+            match theirs {
+                Some(_) => Action::Pull,
+                None => Action::DeleteLocal,
+            }
+        } else {
+            match (ours, theirs) {
+                (None, _) => Action::Pull,
+                (_, None) => Action::Push,
+                _ => Action::Conflict,
+            }
+        };
+        actions.push((path.clone(), action));
+    }
+    actions
+}
+
+fn manifest(entries: Vec<Entry>) -> Manifest {
+    entries
+        .into_iter()
+        .filter_map(|entry| Some((entry.name, entry.sha256?)))
+        .collect()
+}
+
+fn load_state(dir: &Path) -> Result<BTreeMap<String, Manifest>> {
+    let path = dir.join(STATE_FILE);
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content =
+        std::fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+    serde_json::from_str(&content).with_context(|| format!("Failed to parse {:?}", path))
+}
+
+fn save_state(dir: &Path, state: &BTreeMap<String, Manifest>) -> Result<()> {
+    let path = dir.join(STATE_FILE);
+    std::fs::write(&path, serde_json::to_string_pretty(state)?)
+        .with_context(|| format!("Failed to write {:?}", path))
+}
+
+/// Directory part of a relative path, "" for top-level files.
+fn parent_of(path: &str) -> &str {
+    path.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// Bring `dir` and the writable share of `peer` in line.
+///
+/// With `dry_run` only the plan is printed. Fails if anything is left out
+/// of sync, i.e. on conflicts or failed transfers.
+pub async fn run(
+    connection: &Connection,
+    dir: &Path,
+    peer: &str,
+    dry_run: bool,
+    config: &Config,
+) -> Result<()> {
+    let local_share = Share::new(dir, &[])?;
+    let local = manifest(tokio::task::block_in_place(|| local_share.list("", true))?);
+    let remote = manifest(share::list(connection, "", true).await?);
+
+    let mut state = load_state(dir)?;
+    let base = state.get(peer).cloned().unwrap_or_default();
+    let actions = plan(&local, &remote, &base);
+
+    if actions.is_empty() {
+        crate::info!("Already in sync with {}", peer);
+    }
+
+    // Paths both sides agree on now, updated as actions succeed
+    let mut synced: Manifest = local
+        .iter()
+        .filter(|(path, hash)| remote.get(*path) == Some(hash))
+        .map(|(path, hash)| (path.clone(), hash.clone()))
+        .collect();
+    let (mut done, mut conflicts, mut failures) = (0, 0, 0);
+
+    for (path, action) in &actions {
+        if *action == Action::Conflict {
+            crate::info!(
+                "{:>13}  {} (changed on both sides, resolve by hand)",
+                action.label(),
+                path
+            );
+        } else {
+            crate::info!("{:>13}  {}", action.label(), path);
+        }
+        if dry_run {
+            continue;
+        }
+
+        let result = match action {
+            Action::Push => match local_share.resolve(path) {
+                Ok(file) => share::put(connection, &file, parent_of(path), &config.user_id, peer)
+                    .await
+                    .map(|_| local.get(path).cloned()),
+                Err(e) => Err(e),
+            },
+            Action::Pull => match local_share.prepare_dir(parent_of(path)) {
+                Ok(output_dir) => share::get(connection, path, &output_dir, peer, config)
+                    .await
+                    .map(|_| remote.get(path).cloned()),
+                Err(e) => Err(e),
+            },
+            Action::DeleteLocal => local_share
+                .resolve(path)
+                .and_then(|file| Ok(std::fs::remove_file(file)?))
+                .map(|_| None),
+            Action::DeleteRemote => share::delete(connection, path).await.map(|_| None),
+            Action::Conflict => {
+                conflicts += 1;
+                // Keep the old base so it stays a conflict until resolved
+                Ok(base.get(path).cloned())
+            }
+        };
+
+        match result {
+            Ok(hash) => {
+                if *action != Action::Conflict {
+                    done += 1;
+                }
+                if let Some(hash) = hash {
+                    synced.insert(path.clone(), hash);
+                }
+            }
+            Err(e) => {
+                eprintln!("Failed to {} {}: {:#}", action.label(), path, e);
+                failures += 1;
+                if let Some(hash) = base.get(path) {
+                    synced.insert(path.clone(), hash.clone());
+                }
+            }
+        }
+    }
+
+    if dry_run {
+        crate::info!("Dry run: {} change(s) planned, nothing done", actions.len());
+        return Ok(());
+    }
+
+    state.insert(peer.to_string(), synced);
+    save_state(dir, &state)?;
+
+    if !actions.is_empty() {
+        crate::info!(
+            "Sync with {}: {} change(s), {} conflict(s), {} failure(s)",
+            peer,
+            done,
+            conflicts,
+            failures
+        );
+    }
+    if conflicts + failures > 0 {
+        bail!("{} is not fully in sync with {}", dir.display(), peer);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(entries: &[(&str, &str)]) -> Manifest {
+        entries
+            .iter()
+            .map(|(path, hash)| (path.to_string(), hash.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_plan_first_sync() {
+        let local = manifest(&[("a", "1"), ("same", "s"), ("both", "x")]);
+        let remote = manifest(&[("b", "2"), ("same", "s"), ("both", "y")]);
+        let actions = plan(&local, &remote, &Manifest::new());
+        assert_eq!(
+            actions,
+            [
+                ("a".to_string(), Action::Push),
+                ("b".to_string(), Action::Pull),
+                ("both".to_string(), Action::Conflict),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_plan_with_base() {
+        let base = manifest(&[
+            ("edited-here", "1"),
+            ("edited-there", "1"),
+            ("deleted-here", "1"),
+            ("deleted-there", "1"),
+            ("deleted-here-edited-there", "1"),
+            ("edited-both", "1"),
+            ("deleted-both", "1"),
+        ]);
+        let local = manifest(&[
+            ("edited-here", "2"),
+            ("edited-there", "1"),
+            ("deleted-there", "1"),
+            ("edited-both", "2"),
+        ]);
+        let remote = manifest(&[
+            ("edited-here", "1"),
+            ("edited-there", "2"),
+            ("deleted-here", "1"),
+            ("deleted-here-edited-there", "2"),
+            ("edited-both", "3"),
+        ]);
+
+        let actions: BTreeMap<String, Action> = plan(&local, &remote, &base).into_iter().collect();
+        assert_eq!(actions["edited-here"], Action::Push);
+        assert_eq!(actions["edited-there"], Action::Pull);
+        assert_eq!(actions["deleted-here"], Action::DeleteRemote);
+        assert_eq!(actions["deleted-there"], Action::DeleteLocal);
+        assert_eq!(actions["deleted-here-edited-there"], Action::Pull);
+        assert_eq!(actions["edited-both"], Action::Conflict);
+        assert!(!actions.contains_key("deleted-both"));
+        assert_eq!(actions.len(), 6);
+    }
+
+    #[test]
+    fn test_parent_of() {
+        assert_eq!(parent_of("a.txt"), "");
+        assert_eq!(parent_of("docs/a.txt"), "docs");
+        assert_eq!(parent_of("docs/sub/a.txt"), "docs/sub");
+    }
+}
@@ -0,0 +1,687 @@
+use anyhow::{bail, Context, Result};
+use indicatif::ProgressBar;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::meta::Metadata;
+use crate::quic;
+
+/// A directory goes out as a manifest of everything in it, followed by the
+/// content of each file in manifest order. Every file is checked against its
+/// own SHA256; the manifest ends with one over itself.
+///
+/// The manifest is a u32 entry count and the entries: a kind byte, a u32
+/// length and the path relative to the directory (`/`-separated), then
+/// for `DIR` and `FILE` the u32 length and encoded metadata, for `FILE`
+/// also the u64 size before it, for `SYMLINK` a u32 length and the target,
+/// and for `HARDLINK` the u32 index of the entry it links to.
+const KIND_DIR: u8 = 1;
+const KIND_FILE: u8 = 2;
+const KIND_SYMLINK: u8 = 3;
+const KIND_HARDLINK: u8 = 4;
+/// Entries a sender may announce
+const MAX_ENTRIES: u32 = 1 << 20;
+const MAX_PATH_LEN: u32 = 4096;
+/// Size of the whole manifest, as the fields' own limits add up to far more
+const MAX_MANIFEST: u64 = 256 << 20;
+
+/// What to do with symbolic links in a directory being sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Symlinks {
+    /// Send what the link points to
+    Follow,
+    /// Send the link itself
+    Preserve,
+    /// Leave links out
+    Skip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    Dir {
+        path: String,
+        metadata: Metadata,
+    },
+    File {
+        path: String,
+        size: u64,
+        metadata: Metadata,
+        /// Where to read it on the sending side
+        source: PathBuf,
+    },
+    Symlink {
+        path: String,
+        target: String,
+    },
+    /// Another name of the file at this index
+    Hardlink {
+        path: String,
+        index: u32,
+    },
+}
+
+impl Entry {
+    fn path(&self) -> &str {
+        match self {
+            Entry::Dir { path, .. }
+            | Entry::File { path, .. }
+            | Entry::Symlink { path, .. }
+            | Entry::Hardlink { path, .. } => path,
+        }
+    }
+}
+
+/// List what is sent of the directory `root`, parents before children.
+pub fn scan(root: &Path, symlinks: Symlinks) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    let mut inodes = HashMap::new();
+    let mut visited = Vec::new();
+    if let Some(id) = file_id(&std::fs::metadata(root)?) {
+        visited.push(id);
+    }
+    scan_dir(root, "", symlinks, &mut entries, &mut inodes, &mut visited)?;
+    Ok(entries)
+}
+
+fn scan_dir(
+    dir: &Path,
+    prefix: &str,
+    symlinks: Symlinks,
+    entries: &mut Vec<Entry>,
+    inodes: &mut HashMap<(u64, u64), u32>,
+    visited: &mut Vec<(u64, u64)>,
+) -> Result<()> {
+    let mut names: Vec<_> = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory {:?}", dir))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name())
+        .collect();
+    names.sort();
+
+    for name in names {
+        let source = dir.join(&name);
+        let Some(name) = name.to_str() else {
+            crate::info!("Skipping {:?}: name is not valid UTF-8", source);
+            continue;
+        };
+        let path = format!("{}{}", prefix, name);
+        let mut metadata = std::fs::symlink_metadata(&source)?;
+
+        if metadata.file_type().is_symlink() {
+            match symlinks {
+                Symlinks::Skip => continue,
+                Symlinks::Preserve => {
+                    let target = std::fs::read_link(&source)?;
+                    let Some(target) = target.to_str() else {
+                        crate::info!("Skipping {:?}: link target is not valid UTF-8", source);
+                        continue;
+                    };
+                    entries.push(Entry::Symlink {
+                        path,
+                        target: target.to_string(),
+                    });
+                    continue;
+                }
+                Symlinks::Follow => match std::fs::metadata(&source) {
+                    Ok(target) => metadata = target,
+                    Err(e) => {
+                        crate::info!("Skipping broken link {:?}: {}", source, e);
+                        continue;
+                    }
+                },
+            }
+        }
+
+        if metadata.is_dir() {
+            let id = file_id(&metadata);
+            if id.is_some_and(|id| visited.contains(&id)) {
+                crate::info!("Skipping {:?}: link back to a directory being sent", source);
+                continue;
+            }
+            entries.push(Entry::Dir {
+                path: path.clone(),
+                metadata: Metadata::read(&source)?,
+            });
+            visited.extend(id);
+            scan_dir(
+                &source,
+                &format!("{}/", path),
+                symlinks,
+                entries,
+                inodes,
+                visited,
+            )?;
+            if id.is_some() {
+                visited.pop();
+            }
+        } else if metadata.is_file() {
+            if let Some(id) = file_id(&metadata).filter(|_| link_count(&metadata) > 1) {
+                if let Some(&index) = inodes.get(&id) {
+                    entries.push(Entry::Hardlink { path, index });
+                    continue;
+                }
+                inodes.insert(id, entries.len() as u32);
+            }
+            entries.push(Entry::File {
+                path,
+                size: metadata.len(),
+                metadata: Metadata::read(&source)?,
+                source,
+            });
+        } else {
+            crate::info!("Skipping {:?}: not a regular file or directory", source);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn file_id(metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_metadata: &std::fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(unix)]
+fn link_count(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.nlink()
+}
+
+#[cfg(not(unix))]
+fn link_count(_metadata: &std::fs::Metadata) -> u64 {
+    1
+}
+
+fn encode_manifest(entries: &[Entry]) -> Vec<u8> {
+    fn put_bytes(data: &mut Vec<u8>, bytes: &[u8]) {
+        data.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+        data.extend_from_slice(bytes);
+    }
+
+    let mut data = Vec::new();
+    data.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+    for entry in entries {
+        let kind = match entry {
+            Entry::Dir { .. } => KIND_DIR,
+            Entry::File { .. } => KIND_FILE,
+            Entry::Symlink { .. } => KIND_SYMLINK,
+            Entry::Hardlink { .. } => KIND_HARDLINK,
+        };
+        data.push(kind);
+        put_bytes(&mut data, entry.path().as_bytes());
+        match entry {
+            Entry::Dir { metadata, .. } => put_bytes(&mut data, &metadata.encode()),
+            Entry::File { size, metadata, .. } => {
+                data.extend_from_slice(&size.to_be_bytes());
+                put_bytes(&mut data, &metadata.encode());
+            }
+            Entry::Symlink { target, .. } => put_bytes(&mut data, target.as_bytes()),
+            Entry::Hardlink { index, .. } => data.extend_from_slice(&index.to_be_bytes()),
+        }
+    }
+    data
+}
+
+async fn read_bytes<R: AsyncRead + Unpin>(recv: &mut R, limit: u32) -> Result<Vec<u8>> {
+    let len = recv.read_u32().await?;
+    if len > limit {
+        bail!("Manifest field too long ({} bytes)", len);
+    }
+    let mut bytes = vec![0u8; len as usize];
+    recv.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
+
+/// Read a manifest, returning it along with the hash of its bytes.
+async fn read_manifest<R: AsyncRead + Unpin>(recv: &mut R) -> Result<(Vec<Entry>, Sha256)> {
+    // Hash what we read so the sender's hash can be checked
+    let mut recv = HashingReader {
+        inner: recv,
+        hasher: Sha256::new(),
+        read: 0,
+    };
+    let count = recv.read_u32().await?;
+    if count > MAX_ENTRIES {
+        bail!("Too many entries in directory ({})", count);
+    }
+
+    let mut entries = Vec::new();
+    for index in 0..count {
+        let kind = recv.read_u8().await?;
+        let path = String::from_utf8(read_bytes(&mut recv, MAX_PATH_LEN).await?)
+            .context("Invalid UTF-8 in path")?;
+        check_path(&path)?;
+        let entry = match kind {
+            KIND_DIR => Entry::Dir {
+                path,
+                metadata: Metadata::decode(
+                    &read_bytes(&mut recv, crate::meta::MAX_ENCODED).await?,
+                )?,
+            },
+            KIND_FILE => Entry::File {
+                path,
+                size: recv.read_u64().await?,
+                metadata: Metadata::decode(
+                    &read_bytes(&mut recv, crate::meta::MAX_ENCODED).await?,
+                )?,
+                source: PathBuf::new(),
+            },
+            KIND_SYMLINK => Entry::Symlink {
+                path,
+                target: String::from_utf8(read_bytes(&mut recv, MAX_PATH_LEN).await?)
+                    .context("Invalid UTF-8 in link target")?,
+            },
+            KIND_HARDLINK => {
+                let target = recv.read_u32().await?;
+                if target >= index
+                    || !matches!(entries.get(target as usize), Some(Entry::File { .. }))
+                {
+                    bail!("Hard link {:?} to an unknown file", path);
+                }
+                Entry::Hardlink {
+                    path,
+                    index: target,
+                }
+            }
+            _ => bail!("Unknown directory entry kind {}", kind),
+        };
+        if recv.read > MAX_MANIFEST {
+            bail!("Manifest larger than {} bytes", MAX_MANIFEST);
+        }
+        entries.push(entry);
+    }
+    Ok((entries, recv.hasher))
+}
+
+/// Hashes and counts everything read through it.
+struct HashingReader<'a, R> {
+    inner: &'a mut R,
+    hasher: Sha256,
+    read: u64,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<'_, R> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let this = &mut *self;
+        let poll = std::pin::Pin::new(&mut *this.inner).poll_read(cx, buf);
+        this.hasher.update(&buf.filled()[before..]);
+        this.read += (buf.filled().len() - before) as u64;
+        poll
+    }
+}
+
+/// A path from a manifest must stay inside the directory: relative, with
+/// plain names only.
+fn check_path(path: &str) -> Result<()> {
+    for name in path.split('/') {
+        quic::check_file_name(name).with_context(|| format!("Refusing unsafe path {:?}", path))?;
+    }
+    Ok(())
+}
+
+/// Whether a link at `path` pointing to `target` could lead out of the
+/// directory. Only relative targets are allowed, going up at the start
+/// and at most as far as the directory itself. Names after that may be
+/// links too, but each of them passed the same check.
+fn escapes(path: &str, target: &str) -> bool {
+    let mut depth = path.split('/').count() - 1;
+    let mut components = Path::new(target).components().peekable();
+    if components.peek().is_none() {
+        return true;
+    }
+    let mut descended = false;
+    for component in components {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if !descended && depth > 0 => depth -= 1,
+            Component::Normal(_) => descended = true,
+            _ => return true,
+        }
+    }
+    false
+}
+
+/// Make sure nothing between `root` and the entry at `path` is a symlink,
+/// so writing the entry cannot land outside `root`.
+fn check_parents(root: &Path, path: &str) -> Result<()> {
+    let mut dir = root.to_path_buf();
+    for name in path.split('/').take(path.split('/').count() - 1) {
+        dir.push(name);
+        match std::fs::symlink_metadata(&dir) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                bail!("Refusing to write {:?} through the symlink {:?}", path, dir)
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Clear the way for a new entry at `path`, without following a link
+/// that is there.
+fn remove_link(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.is_dir() => {
+            std::fs::remove_file(path).with_context(|| format!("Failed to replace {:?}", path))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Send a directory as scanned into `entries`. Returns the number of
+/// content bytes and the SHA256 of the manifest.
+pub async fn send_tree<W: AsyncWrite + Unpin>(
+    send: &mut W,
+    entries: &[Entry],
+    pb: &ProgressBar,
+) -> Result<(u64, [u8; 32])> {
+    let manifest = encode_manifest(entries);
+    let manifest_hash: [u8; 32] = Sha256::digest(&manifest).into();
+    send.write_all(&manifest)
+        .await
+        .context("Failed to send manifest")?;
+    send.write_all(&manifest_hash)
+        .await
+        .context("Failed to send manifest")?;
+
+    let mut total = 0;
+    for entry in entries {
+        let Entry::File { source, size, .. } = entry else {
+            continue;
+        };
+        crate::debug!("DEBUG [SEND]: Sending {:?}", source);
+        let mut file = File::open(source)
+            .await
+            .with_context(|| format!("Failed to open file: {:?}", source))?;
+        let extents = crate::sparse::data_extents(source);
+        let flags = if extents.is_some() {
+            quic::FLAG_SPARSE
+        } else {
+            0
+        };
+        send.write_u8(flags).await?;
+        let (sent, _) = quic::write_content(send, &mut file, *size, extents.as_deref(), pb)
+            .await
+            .with_context(|| format!("Failed to send {:?}", source))?;
+        total += sent;
+    }
+    Ok((total, manifest_hash))
+}
+
+/// Receive a directory sent by `send_tree` into `root`. With `preserve`
+/// the sender's metadata is applied (extended attributes with `xattrs`
+/// too). Returns the number of content bytes and the SHA256 of the
+/// manifest.
+pub async fn receive_tree<R: AsyncRead + Unpin>(
+    recv: &mut R,
+    root: &Path,
+    preserve: bool,
+    xattrs: bool,
+) -> Result<(u64, [u8; 32])> {
+    let (entries, hasher) = read_manifest(recv)
+        .await
+        .context("Failed to read manifest")?;
+    let mut received_hash = [0u8; 32];
+    recv.read_exact(&mut received_hash)
+        .await
+        .context("Failed to read manifest hash")?;
+    let manifest_hash: [u8; 32] = hasher.finalize().into();
+    if manifest_hash != received_hash {
+        bail!("Manifest integrity check failed: hash mismatch");
+    }
+
+    let size = entries
+        .iter()
+        .try_fold(0u64, |total, entry| match entry {
+            Entry::File { size, .. } => total.checked_add(*size),
+            _ => Some(total),
+        })
+        .context("Directory sizes in the manifest add up to more than 16 EiB")?;
+
+    if std::fs::symlink_metadata(root).is_ok_and(|m| m.file_type().is_symlink()) {
+        bail!("Refusing to write into the symlink {:?}", root);
+    }
+    tokio::fs::create_dir_all(root)
+        .await
+        .with_context(|| format!("Failed to create {:?}", root))?;
+
+    crate::info!(
+        "Receiving {} entries ({} bytes) into {:?}...",
+        entries.len(),
+        size,
+        root
+    );
+    let pb = quic::progress_bar(size, "Receiving");
+
+    let mut total = 0;
+    for entry in &entries {
+        let local = root.join(entry.path());
+        match entry {
+            Entry::Dir { path, .. } => {
+                check_parents(root, path)?;
+                remove_link(&local)?;
+                tokio::fs::create_dir_all(&local)
+                    .await
+                    .with_context(|| format!("Failed to create {:?}", local))?;
+            }
+            Entry::File {
+                path,
+                size,
+                metadata,
+                ..
+            } => {
+                check_parents(root, path)?;
+                remove_link(&local)?;
+                if let Some(parent) = local.parent() {
+                    tokio::fs::create_dir_all(parent).await?;
+                }
+                let flags = recv.read_u8().await.context("Failed to read file flags")?;
+                if flags & !quic::FLAG_SPARSE != 0 {
+                    bail!("Unsupported file flags {:#x}", flags);
+                }
+                let mut file = File::create(&local)
+                    .await
+                    .with_context(|| format!("Failed to create output file: {:?}", local))?;
+                let result = async {
+                    let received = quic::read_body(recv, &mut file, *size, flags, &pb).await?;
+                    file.flush().await.context("Failed to flush file")?;
+                    Ok::<_, anyhow::Error>(received)
+                }
+                .await;
+                drop(file);
+                let (received, _) = match result {
+                    Ok(received) => received,
+                    Err(e) => {
+                        let _ = tokio::fs::remove_file(&local).await;
+                        return Err(e.context(format!("Failed to receive {:?}", path)));
+                    }
+                };
+                total += received;
+                if preserve {
+                    if let Err(e) = metadata.apply(&local, xattrs) {
+                        crate::info!("Could not preserve metadata: {:#}", e);
+                    }
+                }
+            }
+            // Once all content is in place
+            Entry::Symlink { .. } | Entry::Hardlink { .. } => {}
+        }
+    }
+    pb.finish_with_message("Received");
+
+    for entry in &entries {
+        let local = root.join(entry.path());
+        match entry {
+            Entry::Hardlink { path, index } => {
+                check_parents(root, path)?;
+                remove_link(&local)?;
+                let original = root.join(entries[*index as usize].path());
+                std::fs::hard_link(&original, &local)
+                    .with_context(|| format!("Failed to link {:?} to {:?}", local, original))?;
+            }
+            Entry::Symlink { path, target } => {
+                if escapes(path, target) {
+                    crate::info!(
+                        "Skipping link {:?} -> {:?}: it points outside the directory",
+                        path,
+                        target
+                    );
+                    continue;
+                }
+                check_parents(root, path)?;
+                remove_link(&local)?;
+                make_symlink(target, &local)?;
+            }
+            _ => {}
+        }
+    }
+
+    // Deepest first, creating entries changes a directory's times
+    if preserve {
+        for entry in entries.iter().rev() {
+            if let Entry::Dir { path, metadata } = entry {
+                if let Err(e) = metadata.apply(&root.join(path), xattrs) {
+                    crate::info!("Could not preserve metadata: {:#}", e);
+                }
+            }
+        }
+    }
+
+    crate::info!(
+        "Directory received successfully: {} entries, {} bytes",
+        entries.len(),
+        total
+    );
+    Ok((total, manifest_hash))
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &str, link: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, link)
+        .with_context(|| format!("Failed to create link {:?}", link))
+}
+
+#[cfg(not(unix))]
+fn make_symlink(target: &str, link: &Path) -> Result<()> {
+    crate::info!(
+        "Skipping link {:?} -> {:?}: not supported here",
+        link,
+        target
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escapes() {
+        assert!(!escapes("link", "file"));
+        assert!(!escapes("link", "./sub/file"));
+        assert!(!escapes("a/b/link", "../../file"));
+        assert!(escapes("a/link", "../b/../c"));
+        assert!(escapes("link", "../file"));
+        assert!(escapes("a/b/link", "../../../file"));
+        assert!(escapes("link", "/etc/passwd"));
+        assert!(escapes("link", ""));
+        // `sub` might itself be a link to the directory
+        assert!(escapes("a/link", "sub/../.."));
+    }
+
+    #[test]
+    fn test_check_path() {
+        assert!(check_path("a/b/c.txt").is_ok());
+        assert!(check_path("a/../b").is_err());
+        assert!(check_path("/etc/passwd").is_err());
+        assert!(check_path("a//b").is_err());
+        assert!(check_path("").is_err());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_tree_roundtrip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let src = base.join("src");
+        std::fs::create_dir_all(src.join("docs/empty")).unwrap();
+        std::fs::write(src.join("docs/a.txt"), b"alpha").unwrap();
+        std::fs::write(src.join("b.bin"), vec![7u8; 100_000]).unwrap();
+        std::fs::hard_link(src.join("b.bin"), src.join("docs/b-again.bin")).unwrap();
+        std::os::unix::fs::symlink("docs/a.txt", src.join("inside")).unwrap();
+        std::os::unix::fs::symlink("../outside", src.join("escape")).unwrap();
+        std::os::unix::fs::symlink(".", src.join("docs/loop")).unwrap();
+
+        let skipped = scan(&src, Symlinks::Skip).unwrap();
+        assert!(!skipped
+            .iter()
+            .any(|entry| matches!(entry, Entry::Symlink { .. })));
+        // Following does not recurse into the loop
+        let followed = scan(&src, Symlinks::Follow).unwrap();
+        assert!(followed.iter().any(|entry| entry.path() == "inside"));
+        assert!(!followed.iter().any(|entry| entry.path() == "docs/loop"));
+
+        let entries = scan(&src, Symlinks::Preserve).unwrap();
+        assert!(entries.iter().any(|entry| matches!(
+            entry,
+            Entry::Hardlink { path, .. } if path == "docs/b-again.bin"
+        )));
+
+        let mut wire = Vec::new();
+        let pb = ProgressBar::hidden();
+        let (sent, hash) = send_tree(&mut wire, &entries, &pb).await.unwrap();
+        assert_eq!(sent, 100_005);
+
+        let dest = base.join("dest");
+        let (received, received_hash) = receive_tree(&mut wire.as_slice(), &dest, false, false)
+            .await
+            .unwrap();
+        assert_eq!((received, received_hash), (sent, hash));
+        assert_eq!(std::fs::read(dest.join("docs/a.txt")).unwrap(), b"alpha");
+        assert!(dest.join("docs/empty").is_dir());
+        assert_eq!(
+            file_id(&std::fs::metadata(dest.join("b.bin")).unwrap()),
+            file_id(&std::fs::metadata(dest.join("docs/b-again.bin")).unwrap())
+        );
+        assert_eq!(
+            std::fs::read_link(dest.join("inside")).unwrap(),
+            Path::new("docs/a.txt")
+        );
+        assert!(std::fs::symlink_metadata(dest.join("escape")).is_err());
+        assert!(std::fs::symlink_metadata(dest.join("docs/loop")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_manifest_size_overflow() {
+        let metadata = Metadata::read(&std::env::temp_dir()).unwrap();
+        let file = |path: &str| Entry::File {
+            path: path.to_string(),
+            size: u64::MAX / 2 + 1,
+            metadata: metadata.clone(),
+            source: PathBuf::new(),
+        };
+        let mut wire = encode_manifest(&[file("a"), file("b")]);
+        let hash: [u8; 32] = Sha256::digest(&wire).into();
+        wire.extend_from_slice(&hash);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("dest");
+        assert!(receive_tree(&mut wire.as_slice(), &dest, false, false)
+            .await
+            .is_err());
+        assert!(!dest.exists());
+    }
+}
@@ -1,121 +1,463 @@
 use anyhow::{Context, Result};
 use std::future::Future;
-use std::net::{Ipv6Addr, SocketAddr};
+use std::net::SocketAddr;
 use std::pin::Pin;
 use tokio::net::UdpSocket;
 use tokio::time::{interval, timeout, Duration};
 
+use crate::mux::MuxSocket;
+use crate::net::ResolvedPeer;
+use crate::probe::{ProbeKind, ProbeSession};
+
 pub const CLIENT_PORT: u16 = 3457;
 pub const SERVER_PORT: u16 = 3458;
-const PROBE_PACKET: &[u8] = b"RXX_PROBE";
-const PROBE_ACK: &[u8] = b"RXX_PROBE_ACK";
 const TIMEOUT_SECS: u64 = 10;
 const MAX_RETRIES: u32 = 3;
+const PROBE_INTERVAL_MS: u64 = 1000;
+const BACKOFF_INITIAL_SECS: u64 = 1;
+const BACKOFF_MAX_SECS: u64 = 30;
+
+/// How long and how often to try punching before giving up.
+#[derive(Debug, Clone)]
+pub struct PunchOptions {
+    pub attempt_timeout: Duration,
+    /// `None` keeps trying until the peer shows up
+    pub max_retries: Option<u32>,
+    pub probe_interval: Duration,
+    pub backoff_initial: Duration,
+    pub backoff_max: Duration,
+}
+
+impl Default for PunchOptions {
+    fn default() -> Self {
+        PunchOptions {
+            attempt_timeout: Duration::from_secs(TIMEOUT_SECS),
+            max_retries: Some(MAX_RETRIES),
+            probe_interval: Duration::from_millis(PROBE_INTERVAL_MS),
+            backoff_initial: Duration::from_secs(BACKOFF_INITIAL_SECS),
+            backoff_max: Duration::from_secs(BACKOFF_MAX_SECS),
+        }
+    }
+}
+
+impl PunchOptions {
+    pub fn from_config(config: &crate::config::Config) -> Self {
+        let mut options = PunchOptions::default();
+        if let Some(punch) = &config.punch {
+            if let Some(secs) = punch.timeout_secs {
+                options.attempt_timeout = Duration::from_secs(secs.get());
+            }
+            if let Some(retries) = punch.max_retries {
+                options.max_retries = (retries > 0).then_some(retries);
+            }
+            if let Some(ms) = punch.probe_interval_ms {
+                options.probe_interval = Duration::from_millis(ms.max(1));
+            }
+            if let Some(secs) = punch.backoff_initial_secs {
+                options.backoff_initial = Duration::from_secs(secs);
+            }
+            if let Some(secs) = punch.backoff_max_secs {
+                options.backoff_max = Duration::from_secs(secs);
+            }
+        }
+        options
+    }
+
+    /// Delay before the attempt following `attempt` failed ones.
+    ///
+    /// Exponential in the number of failures and capped at `backoff_max`;
+    /// `jitter` in `[0, 1]` scales the result down by up to half so peers
+    /// that failed together do not retry in lockstep.
+    pub fn backoff_delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(16);
+        let delay = self
+            .backoff_initial
+            .saturating_mul(1 << exponent)
+            .min(self.backoff_max);
+        delay.mul_f64(1.0 - 0.5 * jitter.clamp(0.0, 1.0))
+    }
+
+    fn describe_retries(&self) -> String {
+        match self.max_retries {
+            Some(n) => n.to_string(),
+            None => "∞".to_string(),
+        }
+    }
+}
 
-pub async fn punch_hole<F>(mut resolver: F, is_server: bool) -> Result<SocketAddr>
+/// Result of a successful hole punch: the peer's address and the socket the
+/// pinhole was opened from.
+///
+/// The socket is handed over to quinn as-is so the QUIC endpoint keeps
+/// using the exact local address and port the firewall mapping was made for.
+pub struct PunchedChannel {
+    pub peer_addr: SocketAddr,
+    pub socket: std::net::UdpSocket,
+}
+
+pub async fn punch_hole<F>(resolver: F, options: &PunchOptions) -> Result<PunchedChannel>
 where
-    F: FnMut() -> Pin<Box<dyn Future<Output = Result<Ipv6Addr>> + Send>>,
+    F: FnMut() -> Pin<Box<dyn Future<Output = Result<ResolvedPeer>> + Send>>,
 {
-    for attempt in 1..=MAX_RETRIES {
-        let peer_addr = resolver().await?;
-        println!(
-            "UDP hole punching attempt {}/{} to {}...",
-            attempt, MAX_RETRIES, peer_addr
-        );
+    let probe_interval = options.probe_interval;
+    let (peer_addr, socket) = punch_with_retries(resolver, options, |peer| async move {
+        try_punch_hole(&peer, probe_interval).await
+    })
+    .await?;
+    let socket = socket.into_std().context("Failed to convert UDP socket")?;
+    Ok(PunchedChannel { peer_addr, socket })
+}
 
-        match timeout(
-            Duration::from_secs(TIMEOUT_SECS),
-            try_punch_hole(peer_addr, is_server),
-        )
-        .await
-        {
-            Ok(Ok(peer_socket)) => {
-                println!("Bidirectional UDP channel established!");
-                return Ok(peer_socket);
+/// Punch towards a sender from the receiver's shared socket, retrying like
+/// `punch_hole` does.
+pub async fn punch_hole_shared<F>(
+    mux: &MuxSocket,
+    resolver: F,
+    options: &PunchOptions,
+) -> Result<SocketAddr>
+where
+    F: FnMut() -> Pin<Box<dyn Future<Output = Result<ResolvedPeer>> + Send>>,
+{
+    let probe_interval = options.probe_interval;
+    punch_with_retries(resolver, options, |peer| async move {
+        try_punch_shared(mux, &peer, probe_interval).await
+    })
+    .await
+}
+
+/// Run punching attempts with backoff until one succeeds or the retries
+/// are used up. The peer is resolved again before every attempt.
+async fn punch_with_retries<F, P, Fut, T>(
+    mut resolver: F,
+    options: &PunchOptions,
+    mut try_punch: P,
+) -> Result<T>
+where
+    F: FnMut() -> Pin<Box<dyn Future<Output = Result<ResolvedPeer>> + Send>>,
+    P: FnMut(ResolvedPeer) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0u32;
+    loop {
+        attempt += 1;
+
+        // Only the first few attempts are reported in full; after that a
+        // waiting receiver just checks in now and then
+        let verbose = attempt <= MAX_RETRIES || attempt.is_multiple_of(10);
+
+        // A registry that cannot be reached counts as a failed attempt
+        let peer = match resolver().await {
+            Ok(peer) => peer,
+            Err(e) if options.max_retries.is_some_and(|max| attempt >= max) => return Err(e),
+            Err(e) => {
+                if verbose {
+                    crate::info!("Attempt {} could not find the peer: {:#}", attempt, e);
+                }
+                tokio::time::sleep(options.backoff_delay(attempt, rand::random::<f64>())).await;
+                continue;
+            }
+        };
+        if verbose {
+            crate::info!(
+                "UDP hole punching attempt {}/{} to {}...",
+                attempt,
+                options.describe_retries(),
+                peer.addr
+            );
+        } else {
+            crate::debug!("DEBUG [PUNCH]: Attempt {} to {}...", attempt, peer.addr);
+        }
+
+        if attempt == 1 && !peer.session.is_authenticated() {
+            crate::info!("WARNING: probes are not authenticated in direct IPv6 mode");
+        }
+
+        wait_for_start(&peer, verbose).await;
+
+        match timeout(options.attempt_timeout, try_punch(peer)).await {
+            Ok(Ok(punched)) => {
+                crate::info!("Bidirectional UDP channel established!");
+                return Ok(punched);
             }
             Ok(Err(e)) => {
-                println!("Attempt {} failed: {}", attempt, e);
+                crate::info!("Attempt {} failed: {}", attempt, e);
             }
             Err(_) => {
-                println!(
-                    "Attempt {} timed out after {} seconds",
-                    attempt, TIMEOUT_SECS
-                );
+                if verbose {
+                    crate::info!(
+                        "Attempt {} timed out after {} seconds",
+                        attempt,
+                        options.attempt_timeout.as_secs()
+                    );
+                }
             }
         }
 
-        if attempt < MAX_RETRIES {
-            println!("Retrying...");
+        if options.max_retries.is_some_and(|max| attempt >= max) {
+            break;
         }
+
+        let delay = options.backoff_delay(attempt, rand::random::<f64>());
+        if verbose {
+            crate::info!("Retrying in {:.1} seconds...", delay.as_secs_f64());
+        }
+        tokio::time::sleep(delay).await;
     }
 
-    anyhow::bail!(
-        "Failed to establish UDP channel after {} attempts",
-        MAX_RETRIES
+    anyhow::bail!("Failed to establish UDP channel after {} attempts", attempt)
+}
+
+/// Punch towards a sender from the receiver's shared socket.
+///
+/// Unlike `punch_hole` this makes a single attempt: the socket stays with
+/// the QUIC endpoint, and a sender that retries triggers a new rendezvous.
+pub async fn punch_shared(
+    mux: &MuxSocket,
+    peer: &ResolvedPeer,
+    options: &PunchOptions,
+) -> Result<SocketAddr> {
+    wait_for_start(peer, true).await;
+    timeout(
+        options.attempt_timeout,
+        try_punch_shared(mux, peer, options.probe_interval),
     )
+    .await
+    .map_err(|_| {
+        anyhow::anyhow!(
+            "Timed out after {} seconds",
+            options.attempt_timeout.as_secs()
+        )
+    })?
+}
+
+/// Sleep until the start time the server gave both peers, if any.
+async fn wait_for_start(peer: &ResolvedPeer, verbose: bool) {
+    let Some(delay) = peer.start_in else {
+        return;
+    };
+    if verbose {
+        crate::info!(
+            "Peer is listening, punching together in {} ms...",
+            delay.as_millis()
+        );
+    }
+    tokio::time::sleep(delay).await;
 }
 
-async fn try_punch_hole(peer_addr: Ipv6Addr, is_server: bool) -> Result<SocketAddr> {
-    // Server binds to 3458, client binds to 3457
-    let local_port = if is_server { SERVER_PORT } else { CLIENT_PORT };
-    let peer_port = if is_server { CLIENT_PORT } else { SERVER_PORT };
+async fn try_punch_shared(
+    mux: &MuxSocket,
+    peer: &ResolvedPeer,
+    probe_interval: Duration,
+) -> Result<SocketAddr> {
+    let session = &peer.session;
+    let mut probes = mux.register(session.session_id);
+    let peer_socket = SocketAddr::from(peer.socket_addr(CLIENT_PORT));
+    let probe_packet = session.encode(ProbeKind::Probe, &crate::probe::new_nonce());
 
-    // Get the stable local IPv6 address
-    let local_ipv6 = crate::net::get_local_ipv6()?;
+    let mut probe_interval = interval(probe_interval);
+    loop {
+        tokio::select! {
+            _ = probe_interval.tick() => {
+                mux.send_to(&probe_packet, peer_socket)
+                    .await
+                    .context("Failed to send probe packet")?;
+                crate::debug!("DEBUG [PUNCH]: Sent probe packet to {}", peer_socket);
+            }
+
+            received = probes.recv() => {
+                let Some((data, from)) = received else {
+                    anyhow::bail!("Probe session was replaced");
+                };
+                let Some(probe) = session.decode(&data) else {
+                    crate::info!("Ignoring unrecognized packet ({} bytes) from {}", data.len(), from);
+                    continue;
+                };
+                // The sender only trusts an ACK to its own probe, so keep
+                // going until we have answered one
+                if probe.kind == ProbeKind::Ack {
+                    crate::debug!("DEBUG [PUNCH]: Probe ACK from {}", from);
+                    continue;
+                }
+                mux.send_to(&session.encode(ProbeKind::Ack, &probe.nonce), from)
+                    .await
+                    .context("Failed to send probe ACK")?;
+                return Ok(from);
+            }
+        }
+    }
+}
+
+async fn try_punch_hole(
+    peer: &ResolvedPeer,
+    probe_interval: Duration,
+) -> Result<(SocketAddr, UdpSocket)> {
+    // We bind to 3457 and punch towards the receiver's shared socket on 3458
+    let peer_socket = peer.socket_addr(SERVER_PORT);
+
+    // Get the local IPv6 address matching the peer (link-local peers need a
+    // link-local address on the same interface)
+    let mut local_addr = crate::net::select_local(Some(&peer_socket))?;
+    local_addr.set_port(CLIENT_PORT);
 
     // Bind to specific IPv6 address
-    let socket = UdpSocket::bind(format!("[{}]:{}", local_ipv6, local_port))
+    let socket = UdpSocket::bind(local_addr)
         .await
         .context("Failed to bind UDP socket")?;
 
-    println!("UDP socket bound to [{}]:{}", local_ipv6, local_port);
+    crate::debug!("DEBUG [PUNCH]: UDP socket bound to {}", local_addr);
 
-    let peer_socket = SocketAddr::from((peer_addr, peer_port));
+    let peer_socket = SocketAddr::from(peer_socket);
+    let session: &ProbeSession = &peer.session;
+    let nonce = crate::probe::new_nonce();
+    let probe_packet = session.encode(ProbeKind::Probe, &nonce);
 
     // Start sending probe packets
-    let mut probe_interval = interval(Duration::from_secs(1));
+    let mut probe_interval = interval(probe_interval);
     let mut buf = [0u8; 1024];
-    let mut sent_probe = false;
-    let mut received_probe = false;
 
     loop {
         tokio::select! {
             _ = probe_interval.tick() => {
                 // Send probe packet
-                socket.send_to(PROBE_PACKET, peer_socket)
+                socket.send_to(&probe_packet, peer_socket)
                     .await
                     .context("Failed to send probe packet")?;
-                println!("Sent probe packet to {}", peer_socket);
-                sent_probe = true;
+                crate::debug!("DEBUG [PUNCH]: Sent probe packet to {}", peer_socket);
             }
 
             result = socket.recv_from(&mut buf) => {
                 let (len, from) = result.context("Failed to receive packet")?;
-                let data = &buf[..len];
-
-                if data == PROBE_PACKET {
-                    println!("Received probe packet from {}", from);
-                    received_probe = true;
-
-                    // Send ACK back
-                    socket.send_to(PROBE_ACK, from)
-                        .await
-                        .context("Failed to send probe ACK")?;
-                    println!("Sent probe ACK to {}", from);
-
-                    // If we've received a probe and sent ACK, bidirectional is established
-                    // (we can receive from peer, and peer will receive our ACK)
-                    return Ok(from);
-                } else if data == PROBE_ACK {
-                    println!("Received probe ACK from {}", from);
-                    received_probe = true;
-
-                    // Check if bidirectional
-                    if sent_probe && received_probe {
-                        return Ok(from);
+
+                let Some(probe) = session.decode(&buf[..len]) else {
+                    crate::info!("Ignoring unrecognized packet ({} bytes) from {}", len, from);
+                    continue;
+                };
+
+                match probe.kind {
+                    ProbeKind::Probe => {
+                        crate::info!("Received probe packet from {} ({})", from, probe.sender_id);
+
+                        // Send ACK back
+                        socket.send_to(&session.encode(ProbeKind::Ack, &probe.nonce), from)
+                            .await
+                            .context("Failed to send probe ACK")?;
+                        crate::info!("Sent probe ACK to {}", from);
+
+                        // A probe can be replayed from anywhere, so only the
+                        // ACK to one of ours tells where the peer is
+                    }
+                    ProbeKind::Ack if probe.nonce != nonce => {
+                        crate::info!("Ignoring stale probe ACK from {}", from);
+                    }
+                    ProbeKind::Ack => {
+                        // An ACK carrying our nonce can only be a reply to a
+                        // probe we sent, so the channel works in both
+                        // directions
+                        crate::info!("Received probe ACK from {} ({})", from, probe.sender_id);
+                        return Ok((from, socket));
                     }
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_and_caps() {
+        let options = PunchOptions {
+            backoff_initial: Duration::from_secs(1),
+            backoff_max: Duration::from_secs(30),
+            ..PunchOptions::default()
+        };
+
+        assert_eq!(options.backoff_delay(1, 0.0), Duration::from_secs(1));
+        assert_eq!(options.backoff_delay(2, 0.0), Duration::from_secs(2));
+        assert_eq!(options.backoff_delay(4, 0.0), Duration::from_secs(8));
+        assert_eq!(options.backoff_delay(10, 0.0), Duration::from_secs(30));
+        assert_eq!(
+            options.backoff_delay(u32::MAX, 0.0),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn test_backoff_jitter() {
+        let options = PunchOptions::default();
+
+        assert_eq!(options.backoff_delay(3, 1.0), Duration::from_secs(2));
+        let jittered = options.backoff_delay(3, 0.5);
+        assert!(jittered > Duration::from_secs(2) && jittered < Duration::from_secs(4));
+    }
+
+    #[test]
+    fn test_options_from_config() {
+        let mut config = crate::config::Config {
+            user_id: "alice".to_string(),
+            server_url: "http://localhost:3457".to_string(),
+            ..Default::default()
+        };
+        let options = PunchOptions::from_config(&config);
+        assert_eq!(options.max_retries, Some(MAX_RETRIES));
+
+        config.punch = Some(crate::config::PunchConfig {
+            timeout_secs: std::num::NonZeroU64::new(60),
+            max_retries: Some(0),
+            ..Default::default()
+        });
+        let options = PunchOptions::from_config(&config);
+        assert_eq!(options.attempt_timeout, Duration::from_secs(60));
+        assert_eq!(options.max_retries, None);
+
+        // A zero timeout would fail every attempt at once
+        assert!(toml::from_str::<crate::config::PunchConfig>("timeout_secs = 0").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resolver_errors_are_retried() {
+        let mut options = PunchOptions {
+            backoff_initial: Duration::from_millis(1),
+            backoff_max: Duration::from_millis(1),
+            max_retries: None,
+            ..PunchOptions::default()
+        };
+        let failing_resolver = |failures: u32| {
+            let mut calls = 0;
+            move || -> Pin<Box<dyn Future<Output = Result<ResolvedPeer>> + Send>> {
+                calls += 1;
+                let found = calls > failures;
+                Box::pin(async move {
+                    anyhow::ensure!(found, "server unreachable");
+                    Ok(ResolvedPeer {
+                        addr: std::net::Ipv6Addr::LOCALHOST,
+                        scope_id: 0,
+                        session: ProbeSession::unauthenticated("alice"),
+                        start_in: None,
+                    })
+                })
+            }
+        };
+
+        // Waiting for the peer outlasts a registry that is down for a while
+        let addr = punch_with_retries(failing_resolver(5), &options, |peer| async move {
+            Ok(peer.addr)
+        })
+        .await
+        .unwrap();
+        assert_eq!(addr, std::net::Ipv6Addr::LOCALHOST);
+
+        // With a limit the last resolver error is returned
+        options.max_retries = Some(3);
+        let result = punch_with_retries(failing_resolver(5), &options, |peer| async move {
+            Ok(peer.addr)
+        })
+        .await;
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("server unreachable"));
+    }
+}
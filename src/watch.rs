@@ -0,0 +1,225 @@
+use anyhow::{Context, Result};
+use notify::event::{AccessKind, AccessMode, EventKind, ModifyKind, RenameMode};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher as _};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Where sent files are moved, inside the watched directory
+pub const SENT_DIR: &str = ".sent";
+/// Delay before the first retry of a failed file, doubled on every failure
+const RETRY_DELAY: Duration = Duration::from_secs(15);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(600);
+/// Give up on a connection the peer has not answered on for this long
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Files in a directory that are ready to be sent.
+///
+/// Yields what is already there, then every file that is closed after
+/// writing or moved into the directory. Hidden files (including `.sent`)
+/// and subdirectories are ignored, so writers can use a temporary name
+/// starting with `.` and rename it when done.
+pub struct Watcher {
+    dir: PathBuf,
+    events: mpsc::UnboundedReceiver<PathBuf>,
+    /// Keeps the notifications coming
+    _watcher: RecommendedWatcher,
+    /// Files found but not handed out yet, oldest first
+    ready: Vec<PathBuf>,
+    /// Failed files by the time they may be tried again
+    retries: BTreeMap<Instant, Vec<PathBuf>>,
+    failures: HashMap<PathBuf, u32>,
+}
+
+impl Watcher {
+    pub fn new(dir: &Path) -> Result<Self> {
+        let dir = dir
+            .canonicalize()
+            .with_context(|| format!("Failed to open watched directory {:?}", dir))?;
+        std::fs::create_dir_all(dir.join(SENT_DIR))
+            .with_context(|| format!("Failed to create {:?}", dir.join(SENT_DIR)))?;
+
+        let (tx, events) = mpsc::unbounded_channel();
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                let event = match event {
+                    Ok(event) => event,
+                    Err(e) => {
+                        eprintln!("Error watching directory: {}", e);
+                        return;
+                    }
+                };
+                let path = match event.kind {
+                    EventKind::Access(AccessKind::Close(AccessMode::Write))
+                    | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => event.paths.first(),
+                    _ => None,
+                };
+                if let Some(path) = path {
+                    let _ = tx.send(path.clone());
+                }
+            })
+            .context("Failed to set up directory watch")?;
+        watcher
+            .watch(&dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("Failed to watch {:?}", dir))?;
+
+        // Watch first so nothing written meanwhile is missed
+        let mut existing: Vec<(std::time::SystemTime, PathBuf)> = std::fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| is_candidate(path))
+            .map(|path| {
+                let modified = path.metadata().and_then(|m| m.modified());
+                (modified.unwrap_or(std::time::UNIX_EPOCH), path)
+            })
+            .collect();
+        existing.sort();
+
+        Ok(Watcher {
+            dir,
+            events,
+            _watcher: watcher,
+            ready: existing.into_iter().map(|(_, path)| path).collect(),
+            retries: BTreeMap::new(),
+            failures: HashMap::new(),
+        })
+    }
+
+    /// Wait for the next file to send.
+    pub async fn next(&mut self) -> Option<PathBuf> {
+        loop {
+            let now = Instant::now();
+            while let Some(entry) = self.retries.first_entry() {
+                if *entry.key() > now {
+                    break;
+                }
+                self.ready.extend(entry.remove());
+            }
+
+            // The same file may be reported more than once
+            while !self.ready.is_empty() {
+                let path = self.ready.remove(0);
+                if is_candidate(&path) {
+                    return Some(path);
+                }
+            }
+
+            let retry_at = self.retries.keys().next().copied();
+            tokio::select! {
+                path = self.events.recv() => {
+                    let path = path?;
+                    if path.parent() == Some(self.dir.as_path()) && !self.ready.contains(&path) {
+                        self.ready.push(path);
+                    }
+                }
+                _ = sleep_until(retry_at) => {}
+            }
+        }
+    }
+
+    /// Move a file that was sent out of the way, into `.sent`.
+    pub fn mark_sent(&mut self, path: &Path) -> Result<()> {
+        self.failures.remove(path);
+        let name = path.file_name().context("Invalid file name")?;
+        let sent_dir = self.dir.join(SENT_DIR);
+
+        // Do not overwrite an earlier file of the same name
+        let mut target = sent_dir.join(name);
+        let mut n = 1;
+        while target.exists() {
+            target = sent_dir.join(format!("{}.{}", name.to_string_lossy(), n));
+            n += 1;
+        }
+        std::fs::rename(path, &target)
+            .with_context(|| format!("Failed to move {:?} to {:?}", path, target))
+    }
+
+    /// Hand a file out again before anything else.
+    pub fn retry_now(&mut self, path: PathBuf) {
+        self.ready.insert(0, path);
+    }
+
+    /// Try a file again later, waiting longer after every failure.
+    /// Returns the delay.
+    pub fn retry(&mut self, path: PathBuf) -> Duration {
+        let failures = self.failures.entry(path.clone()).or_insert(0);
+        let delay = retry_delay(*failures);
+        *failures += 1;
+        self.retries
+            .entry(Instant::now() + delay)
+            .or_default()
+            .push(path);
+        delay
+    }
+}
+
+/// How long to wait after `failures` failed attempts in a row.
+pub fn retry_delay(failures: u32) -> Duration {
+    RETRY_DELAY
+        .saturating_mul(1 << failures.min(16))
+        .min(MAX_RETRY_DELAY)
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// A regular, non-hidden file (symlinks are followed).
+fn is_candidate(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_none_or(|name| name.starts_with('.'));
+    !hidden && path.is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_delay() {
+        assert_eq!(retry_delay(0), RETRY_DELAY);
+        assert_eq!(retry_delay(1), RETRY_DELAY * 2);
+        assert_eq!(retry_delay(6), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(100), MAX_RETRY_DELAY);
+    }
+
+    async fn next(watcher: &mut Watcher) -> PathBuf {
+        tokio::time::timeout(Duration::from_secs(5), watcher.next())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_watcher() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("old.txt"), b"old").unwrap();
+        std::fs::write(dir.join(".partial"), b"hidden").unwrap();
+
+        let mut watcher = Watcher::new(dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        // Existing files first, then new and renamed ones
+        assert_eq!(next(&mut watcher).await, dir.join("old.txt"));
+        watcher.mark_sent(&dir.join("old.txt")).unwrap();
+        assert!(dir.join(SENT_DIR).join("old.txt").exists());
+
+        std::fs::write(dir.join("new.txt"), b"new").unwrap();
+        assert_eq!(next(&mut watcher).await, dir.join("new.txt"));
+        std::fs::rename(dir.join(".partial"), dir.join("done.txt")).unwrap();
+        assert_eq!(next(&mut watcher).await, dir.join("done.txt"));
+
+        // A second file of the same name does not replace the first
+        std::fs::write(dir.join("old.txt"), b"again").unwrap();
+        assert_eq!(next(&mut watcher).await, dir.join("old.txt"));
+        watcher.mark_sent(&dir.join("old.txt")).unwrap();
+        assert!(dir.join(SENT_DIR).join("old.txt.1").exists());
+    }
+}